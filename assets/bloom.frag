@@ -0,0 +1,42 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+// params: brightness threshold, intensity, radius in pixels
+
+layout(location = 0) in vec2 varying_uv;
+
+layout(location = 0) out vec4 target;
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+layout(set = 0, binding = 1) uniform PostParams {
+    vec2 resolution;
+    float time;
+    vec4 params[2];
+} post;
+
+vec3 bright(vec2 uv, float threshold) {
+    vec3 colour = texture(source, uv).rgb;
+    float luma = dot(colour, vec3(0.2126, 0.7152, 0.0722));
+    return colour * step(threshold, luma);
+}
+
+void main() {
+    float threshold = post.params[0].x;
+    float intensity = post.params[0].y;
+    float radius = max(post.params[0].z, 1.0);
+
+    // sample the bright parts on a 5x5 grid, weighted towards the centre
+    vec2 texel = radius / post.resolution;
+    vec3 glow = vec3(0.0);
+    float total = 0.0;
+    for (int x = -2; x <= 2; x++) {
+        for (int y = -2; y <= 2; y++) {
+            float weight = exp(-float(x * x + y * y) * 0.5);
+            glow += bright(varying_uv + vec2(x, y) * texel, threshold) * weight;
+            total += weight;
+        }
+    }
+
+    vec3 colour = texture(source, varying_uv).rgb;
+    target = vec4(colour + glow / total * intensity, 1.0);
+}
@@ -0,0 +1,36 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+// params: curvature, scanline strength, vignette strength
+
+layout(location = 0) in vec2 varying_uv;
+
+layout(location = 0) out vec4 target;
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+layout(set = 0, binding = 1) uniform PostParams {
+    vec2 resolution;
+    float time;
+    vec4 params[2];
+} post;
+
+void main() {
+    float curvature = post.params[0].x;
+    float scanlines = post.params[0].y;
+    float vignette = post.params[0].z;
+
+    // bulge the screen outwards from its centre
+    vec2 centred = varying_uv * 2.0 - 1.0;
+    centred *= 1.0 + curvature * dot(centred, centred) * 0.25;
+    vec2 uv = centred * 0.5 + 0.5;
+    if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0)))) {
+        target = vec4(0.0, 0.0, 0.0, 1.0);
+        return;
+    }
+
+    vec3 colour = texture(source, uv).rgb;
+    float scanline = 0.5 + 0.5 * sin(uv.y * post.resolution.y * 3.14159265);
+    colour *= mix(1.0, scanline, scanlines);
+    colour *= 1.0 - vignette * dot(centred, centred) * 0.5;
+    target = vec4(colour, 1.0);
+}
@@ -0,0 +1,31 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+// params: gamma, saturation, contrast, brightness, then a red, green and
+// blue tint added to a multiplier of 1; a gamma of 0 is treated as 1
+
+layout(location = 0) in vec2 varying_uv;
+
+layout(location = 0) out vec4 target;
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+layout(set = 0, binding = 1) uniform PostParams {
+    vec2 resolution;
+    float time;
+    vec4 params[2];
+} post;
+
+void main() {
+    float gamma = post.params[0].x > 0.0 ? post.params[0].x : 1.0;
+    float saturation = post.params[0].y;
+    float contrast = post.params[0].z;
+    float brightness = post.params[0].w;
+    vec3 tint = 1.0 + post.params[1].rgb;
+
+    vec3 colour = texture(source, varying_uv).rgb;
+    float luma = dot(colour, vec3(0.2126, 0.7152, 0.0722));
+    colour = mix(vec3(luma), colour, saturation);
+    colour = (colour - 0.5) * contrast + 0.5 + brightness;
+    colour = pow(clamp(colour * tint, 0.0, 1.0), vec3(1.0 / gamma));
+    target = vec4(colour, 1.0);
+}
@@ -1,10 +1,19 @@
 #version 450
 #extension GL_ARB_separate_shader_objects : enable
 
-layout(location = 0) in vec4 varying_color;
+layout(location = 0) in vec2 varying_uv;
+layout(location = 1) in vec4 varying_fg;
+layout(location = 2) in vec4 varying_bg;
 
 layout(location = 0) out vec4 target;
 
+layout(set = 1, binding = 0) uniform sampler2D tileset;
+
 void main() {
-    target = vec4(varying_color.rgb, 1.0);
+    vec4 texel = texture(tileset, varying_uv);
+    // composite the tinted tile over the cell background
+    float fg_alpha = texel.a * varying_fg.a;
+    float alpha = fg_alpha + varying_bg.a * (1.0 - fg_alpha);
+    vec3 colour = varying_fg.rgb * texel.rgb * fg_alpha + varying_bg.rgb * varying_bg.a * (1.0 - fg_alpha);
+    target = vec4(colour / max(alpha, 0.0001), alpha);
 }
@@ -2,15 +2,21 @@
 #extension GL_ARB_separate_shader_objects : enable
 
 layout(location = 0) in vec3 position;
-layout(location = 1) in vec4 color;
+layout(location = 1) in vec2 uv;
+layout(location = 2) in vec4 fg;
+layout(location = 3) in vec4 bg;
 
-layout(location = 0) out vec4 varying_color;
+layout(location = 0) out vec2 varying_uv;
+layout(location = 1) out vec4 varying_fg;
+layout(location = 2) out vec4 varying_bg;
 
-layout(binding = 0) uniform UniformBlock {
+layout(set = 0, binding = 0) uniform UniformBlock {
     mat4 projection;
 } uniform_block;
 
 void main() {
-    varying_color = color;
+    varying_uv = uv;
+    varying_fg = fg;
+    varying_bg = bg;
     gl_Position = uniform_block.projection * vec4(position, 1.0);
 }
\ No newline at end of file
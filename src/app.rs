@@ -0,0 +1,1080 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::backend::{select, BackendVisitor, WindowBackend};
+use crate::bindings::ActionMap;
+use crate::config::{Config, FullscreenMode, LoopMode, ScalingPolicy, TimingConfig, WindowConfig};
+use crate::context::{Context, InstanceWrapper, SurfaceId};
+use crate::gamepad::Gamepads;
+use crate::hal_prelude::*;
+use crate::input::{Blackboard, InputEvent, RootEventHandler};
+use crate::layer::{LayerResized, LayerStack};
+use crate::postprocess::PostProcessChain;
+use crate::recording::{InputRecorder, InputReplay, RecordingError};
+use crate::renderer::{AtlasInfo, MeshConsoleRenderer};
+use crate::swapchain::{FramebufferState, SwapchainState};
+use crate::texture::TextureError;
+use crate::viewport::ViewportTransform;
+use crate::window::{physical_size, PlatformWindow, Window, WindowCommand, WindowState};
+use crate::winit::{ControlFlow, Event};
+
+use log::{debug, info, warn};
+
+/// Longest frame time in seconds fed to the update accumulator, so that a
+/// stall such as dragging the window does not queue up a burst of updates.
+const MAX_FRAME_TIME: f32 = 0.25;
+
+/// Decides how many fixed-step updates each frame runs. Frame times are
+/// added to an accumulator and an update of `step` seconds is due for each
+/// whole step in it. Only the frame times given are used, so replaying the
+/// same frame times runs the same updates.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedStep {
+    step: f32,
+    max_updates: u32,
+    accumulator: f32,
+}
+
+impl FixedStep {
+    pub fn new(timing: &TimingConfig) -> Self {
+        FixedStep {
+            step: 1.0 / timing.updates_per_second.max(1.0),
+            max_updates: timing.max_updates_per_frame,
+            accumulator: 0.0,
+        }
+    }
+
+    /// Get the length of an update in seconds.
+    pub fn step(&self) -> f32 {
+        self.step
+    }
+
+    /// Add a frame lasting `dt` seconds and return the number of updates now
+    /// due. Frames count as at most `MAX_FRAME_TIME` long, and time left over
+    /// after `max_updates_per_frame` updates is dropped.
+    pub fn advance(&mut self, dt: f32) -> u32 {
+        self.accumulator += dt.min(MAX_FRAME_TIME);
+        let mut updates = 0;
+        while self.accumulator >= self.step {
+            if updates == self.max_updates {
+                debug!("dropping {}s of updates to catch up", self.accumulator);
+                self.accumulator = 0.0;
+                break;
+            }
+            self.accumulator -= self.step;
+            updates += 1;
+        }
+        updates
+    }
+}
+
+/// Callbacks for a game driven by a `Runner`. Every method has an empty
+/// default, so implement only those needed.
+pub trait App {
+    /// Called once before the first frame, to fill the layers and push
+    /// input handlers.
+    fn init(&mut self, _ctx: &mut AppContext) {}
+
+    /// Called with each event that no input handler consumed, before the
+    /// frame's updates.
+    fn on_event(&mut self, _ctx: &mut AppContext, _event: &InputEvent) {}
+
+    /// Advance the game by `dt` seconds. Called zero or more times a frame
+    /// with the same `dt`, as set by `TimingConfig::updates_per_second`.
+    /// Handlers in the input chain are advanced by `dt` before each call and
+    /// reset after it, so presses and repeats are seen by exactly one update.
+    fn update(&mut self, _ctx: &mut AppContext, _dt: f32) {}
+
+    /// Draw into the layers' consoles. Called once a frame, after the
+    /// updates.
+    fn render(&mut self, _ctx: &mut AppContext) {}
+
+    /// Called once after the last frame, before the GPU resources are
+    /// destroyed.
+    fn shutdown(&mut self, _ctx: &mut AppContext) {}
+}
+
+/// Handle to a window opened with `AppContext::open_window`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WindowHandle(usize);
+
+/// The layers and input of a window opened with `AppContext::open_window`.
+pub struct WindowView {
+    /// The layers drawn in the window each frame.
+    pub layers: LayerStack,
+    /// The window's own chain of input handlers. Its events are neither
+    /// passed to `App::on_event` nor recorded.
+    pub input: RootEventHandler,
+    /// Placement of the content in the window as of the current frame.
+    pub transform: ViewportTransform,
+    /// (width, height) in pixels of the area covered by the layers.
+    pub content_size: (f32, f32),
+    /// Layers resized to fit the window this frame.
+    pub resized_layers: Vec<LayerResized>,
+}
+
+/// State shared with an `App`'s callbacks.
+pub struct AppContext {
+    /// The layers drawn each frame.
+    pub layers: LayerStack,
+    /// The chain of input handlers, which sees events before the `App`.
+    pub input: RootEventHandler,
+    /// Actions bound in the config, in the input chain at priority 0. The
+    /// runner toggles fullscreen on "toggle_fullscreen".
+    pub actions: Rc<RefCell<ActionMap>>,
+    /// The atlases loaded by the renderer.
+    pub atlases: AtlasInfo,
+    /// Placement of the content in the window as of the current frame.
+    pub transform: ViewportTransform,
+    /// (width, height) in pixels of the area covered by the layers.
+    pub content_size: (f32, f32),
+    /// Layers resized to fit the window this frame.
+    pub resized_layers: Vec<LayerResized>,
+    /// Additional windows which have opened and not yet closed.
+    pub windows: HashMap<WindowHandle, WindowView>,
+    /// Ratio of physical to logical pixels of the window, kept up to date
+    /// as the window moves between displays.
+    pub hidpi_factor: f64,
+    should_quit: bool,
+    redraw_requested: bool,
+    window: WindowState,
+    window_commands: Vec<WindowCommand>,
+    windows_to_open: Vec<(WindowHandle, WindowConfig)>,
+    windows_to_close: Vec<WindowHandle>,
+    next_window: usize,
+}
+
+impl AppContext {
+    /// Create the context for a runner, with the config's bindings in the
+    /// input chain and the window's fullscreen mode queued to be applied.
+    fn new(config: &Config, layers: LayerStack, atlases: AtlasInfo, hidpi_factor: f64) -> Self {
+        let actions = Rc::new(RefCell::new(ActionMap::from_config(&config.bindings)));
+        let mut input = RootEventHandler::default();
+        input.push_handler(0, Rc::clone(&actions));
+
+        AppContext {
+            layers,
+            input,
+            actions,
+            atlases,
+            transform: ViewportTransform::compute(
+                config.window.scaling,
+                (1.0, 1.0),
+                (config.window.width, config.window.height),
+            ),
+            content_size: (1.0, 1.0),
+            resized_layers: Vec::new(),
+            windows: HashMap::new(),
+            hidpi_factor,
+            should_quit: false,
+            redraw_requested: false,
+            window: WindowState::new(&config.window),
+            window_commands: vec![WindowCommand::SetFullscreen(config.window.fullscreen)],
+            windows_to_open: Vec::new(),
+            windows_to_close: Vec::new(),
+            next_window: 0,
+        }
+    }
+
+    /// Stop the runner at the end of the current frame.
+    pub fn quit(&mut self) {
+        self.should_quit = true;
+    }
+
+    /// Check if the runner will stop at the end of the current frame.
+    pub fn is_quitting(&self) -> bool {
+        self.should_quit
+    }
+
+    /// Change the window at the start of the next frame.
+    pub fn window_command(&mut self, command: WindowCommand) {
+        self.window_commands.push(command);
+    }
+
+    /// Open another window at the start of the next frame, such as a
+    /// detached debug console. It appears in `windows` once open.
+    ///
+    /// The window shares the main window's device, tilesets and font, so it
+    /// draws with the same atlases. The GL backend cannot share its device
+    /// between windows, so there the window never opens.
+    pub fn open_window(&mut self, config: WindowConfig) -> WindowHandle {
+        let handle = WindowHandle(self.next_window);
+        self.next_window += 1;
+        self.windows_to_open.push((handle, config));
+        handle
+    }
+
+    /// Close a window opened with `open_window` at the start of the next
+    /// frame. Windows closed by the user are closed without this.
+    pub fn close_window(&mut self, handle: WindowHandle) {
+        self.windows_to_close.push(handle);
+    }
+
+    /// Get the window's current fullscreen mode.
+    pub fn fullscreen(&self) -> FullscreenMode {
+        self.window.fullscreen()
+    }
+
+    /// Draw this frame even if no layer has changed, and keep an
+    /// `OnDemand` loop awake for another frame. Call every frame while
+    /// animating something the renderer cannot see, such as a shader effect
+    /// or a post-processing pass that reads the time.
+    pub fn request_redraw(&mut self) {
+        self.redraw_requested = true;
+    }
+}
+
+/// Opens a window and runs an `App` in it until it quits or the window is
+/// closed.
+pub struct Runner<'a> {
+    config: &'a Config,
+    recording: Option<String>,
+    replay: Option<String>,
+}
+
+impl<'a> Runner<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Runner {
+            config,
+            recording: None,
+            replay: None,
+        }
+    }
+
+    /// Record all input to a file, for replay with `with_replay`.
+    pub fn with_recording(mut self, path: &str) -> Self {
+        self.recording = Some(path.to_owned());
+        self
+    }
+
+    /// Feed input from a recording instead of the window until it runs out,
+    /// with each frame's recorded time driving the updates. To replay
+    /// without a window, use `run_headless` instead.
+    pub fn with_replay(mut self, path: &str) -> Self {
+        self.replay = Some(path.to_owned());
+        self
+    }
+
+    /// Run `app` until it quits or the window is closed, on the first backend
+    /// listed in `GraphicsConfig::backends` that a context can be created
+    /// on.
+    pub fn run<A: App>(self, app: &mut A) {
+        let order = self.config.graphics.backends.clone();
+        if select(&order, RunVisitor { runner: self, app }).is_err() {
+            panic!("No usable graphics backend in {:?}!", order);
+        }
+    }
+
+    /// Replay a recording into `app` without opening a window or device, such
+    /// as to reproduce a bug report or to drive the UI from a test. Each
+    /// frame's events and recorded time are handled as when the recording
+    /// was made, up to drawing: nothing is drawn, `atlases` is empty, and
+    /// window commands and new windows are ignored. Runs until the recording
+    /// ends or the app quits.
+    pub fn run_headless<A: App, R: BufRead>(self, mut replay: InputReplay<R>, app: &mut A) -> Result<(), RecordingError> {
+        let config = self.config;
+        let mut ctx = AppContext::new(config, LayerStack::default(), AtlasInfo::default(), 1.0);
+        app.init(&mut ctx);
+
+        let mut blackboard = Blackboard::default();
+        let mut fixed = FixedStep::new(&config.timing);
+        loop {
+            begin_frame(&mut ctx);
+            let dt = match replay.next_frame(&mut ctx.input)? {
+                Some(record) => record.dt,
+                None => break,
+            };
+            ctx.layers.update_cameras(dt);
+            let updates = fixed.advance(dt);
+            dispatch_input(app, &mut ctx, &mut blackboard);
+            ctx.window_commands.clear();
+            ctx.windows_to_open.clear();
+            ctx.windows_to_close.clear();
+            if ctx.should_quit {
+                break;
+            }
+
+            run_updates(app, &mut ctx, updates, fixed.step());
+            app.render(&mut ctx);
+            blackboard.reset();
+        }
+        app.shutdown(&mut ctx);
+        Ok(())
+    }
+
+    fn run_with<A: App, B: WindowBackend>(
+        self,
+        instance: InstanceWrapper<B>,
+        mut window: Window,
+        mut context: Context<B>,
+        app: &mut A,
+    ) {
+        let config = self.config;
+
+        let mut command_pool = context.create_command_pool();
+
+        let layers = LayerStack::default();
+        let mut post = unsafe { PostProcessChain::new(&context, &config) };
+        let mut renderer = unsafe {
+            MeshConsoleRenderer::<B>::new(&mut context, &mut command_pool, &config, &layers, post.is_some())
+        };
+
+        let hidpi_factor = window.platform_window(&context).get_hidpi_factor();
+        let mut ctx = AppContext::new(config, layers, renderer.atlas_info().clone(), hidpi_factor);
+        let mut secondaries = Vec::new();
+        app.init(&mut ctx);
+
+        let mut blackboard = Blackboard::default();
+        let on_demand = config.timing.mode == LoopMode::OnDemand;
+        let mut gamepads = Gamepads::default();
+        if on_demand {
+            gamepads.set_waker(window.events_loop.create_proxy());
+        }
+        #[cfg(target_os = "linux")]
+        gamepads.scan();
+
+        let mut recorder = self.recording.map(|path| {
+            info!("recording input to {}", path);
+            InputRecorder::create(&path).expect("Unable to create input recording!")
+        });
+        let mut replay = self.replay.map(|path| {
+            info!("replaying input from {}", path);
+            InputReplay::open(&path).expect("Unable to open input recording!")
+        });
+
+        let frame_semaphore = context.device.create_semaphore().unwrap();
+        let present_semaphore = context.device.create_semaphore().unwrap();
+        // signalled once the last frame's commands are done with the renderer's buffers
+        let frame_fence = context.device.create_fence(true).unwrap();
+
+        info!("starting main loop");
+        let (width, height) = physical_size(window.platform_window(&context));
+        let mut swapchain = SwapchainState::new(&mut context, Extent2D { width, height });
+        let mut framebuffer_state = FramebufferState::new_empty(renderer.attachments());
+        if let Err(err) = rebuild_framebuffers(&context, &renderer, &mut framebuffer_state, post.as_mut(), &mut swapchain) {
+            warn!("Unable to build the framebuffers, retrying next frame: {}", err);
+        }
+
+        let mut fixed = FixedStep::new(&config.timing);
+        let min_frame_time = config
+            .timing
+            .frame_cap
+            .map(|cap| Duration::from_nanos((1e9 / cap.max(1.0)) as u64));
+        let mut last_frame = Instant::now();
+        let mut waiting = false;
+        'main: loop {
+            begin_frame(&mut ctx);
+            let mut woke = false;
+            if waiting {
+                let (input, views) = (&mut ctx.input, &mut ctx.windows);
+                window.events_loop.run_forever(|event| {
+                    route_event(event, input, &secondaries, views);
+                    ControlFlow::Break
+                });
+                last_frame = Instant::now();
+                woke = true;
+            }
+
+            let now = Instant::now();
+            let dt = now.duration_since(last_frame);
+            last_frame = now;
+            let mut dt = dt.as_secs() as f32 + dt.subsec_nanos() as f32 * 1e-9;
+            if woke {
+                // time spent asleep is skipped, but the input gets an update
+                dt = dt.max(fixed.step());
+            }
+
+            if let Some(ref mut replaying) = replay {
+                // live input is dropped so that the handlers see only the recording
+                window.events_loop.poll_events(|_| ());
+                match replaying.next_frame(&mut ctx.input).expect("Unable to read input recording!") {
+                    Some(record) => dt = record.dt,
+                    None => {
+                        info!("replay finished, switching to live input");
+                        replay = None;
+                    }
+                }
+            }
+            if replay.is_none() {
+                let (input, views) = (&mut ctx.input, &mut ctx.windows);
+                window.events_loop.poll_events(|event| route_event(event, input, &secondaries, views));
+                gamepads.poll(&mut ctx.input);
+            }
+            if let Some(ref mut recorder) = recorder {
+                recorder.record(&ctx.input, dt).expect("Unable to record input!");
+            }
+            ctx.layers.update_cameras(dt);
+            for view in ctx.windows.values_mut() {
+                view.layers.update_cameras(dt);
+            }
+            let updates = fixed.advance(dt);
+            dispatch_input(app, &mut ctx, &mut blackboard);
+
+            for command in std::mem::replace(&mut ctx.window_commands, Vec::new()) {
+                let os_window = window.platform_window(&context);
+                blackboard.should_rebuild_swapchain |= ctx.window.apply(os_window, command);
+            }
+
+            for handle in std::mem::replace(&mut ctx.windows_to_close, Vec::new()) {
+                if let Some(index) = secondaries.iter().position(|secondary: &Secondary<_>| secondary.handle == handle) {
+                    unsafe { secondaries.remove(index).destroy(&mut context) };
+                }
+                ctx.windows.remove(&handle);
+            }
+            for (handle, window_config) in std::mem::replace(&mut ctx.windows_to_open, Vec::new()) {
+                let view = WindowView {
+                    layers: LayerStack::default(),
+                    input: RootEventHandler::default(),
+                    transform: ctx.transform,
+                    content_size: (1.0, 1.0),
+                    resized_layers: Vec::new(),
+                };
+                if let Some(secondary) = open_secondary(handle, &window_config, &instance, &window, &mut context, config, &renderer) {
+                    secondaries.push(secondary);
+                    ctx.windows.insert(handle, view);
+                }
+            }
+            for secondary in &mut secondaries {
+                let view = ctx.windows.get_mut(&secondary.handle).unwrap();
+                if secondary.prepare(&mut context, view, config) {
+                    ctx.windows_to_close.push(secondary.handle);
+                }
+            }
+
+            if (ctx.should_quit || blackboard.should_rebuild_swapchain) && framebuffer_state.is_some() {
+                context.device.wait_idle().unwrap();
+                unsafe { command_pool.reset() };
+
+                framebuffer_state.destroy(&context.device);
+                if let Some(ref mut post) = post {
+                    post.destroy_framebuffers(&context.device);
+                }
+
+                swapchain.destroy(&context.device);
+            }
+
+            if ctx.should_quit {
+                info!("got quit signal, breaking from 'main loop");
+                break 'main;
+            }
+
+            let rebuild_swapchain = blackboard.should_rebuild_swapchain || framebuffer_state.is_none();
+            if rebuild_swapchain {
+                info!("rebuilding swapchain ({} | {})", blackboard.should_rebuild_swapchain, framebuffer_state.is_none());
+                let os_window = window.platform_window(&context);
+                ctx.hidpi_factor = os_window.get_hidpi_factor();
+                let (width, height) = physical_size(os_window);
+                swapchain.rebuild(&mut context, Extent2D { width, height });
+
+                if let Err(err) = rebuild_framebuffers(&context, &renderer, &mut framebuffer_state, post.as_mut(), &mut swapchain) {
+                    warn!("Unable to rebuild the framebuffers, skipping the frame: {}", err);
+                }
+
+                if config.window.scaling == ScalingPolicy::Resize {
+                    let size = (
+                        swapchain.extent.width as f32 / cell_scale(config, &ctx),
+                        swapchain.extent.height as f32 / cell_scale(config, &ctx),
+                    );
+                    let resized = ctx.layers.fit_to_window(size, renderer.cell_size());
+                    ctx.resized_layers.extend(resized);
+                }
+            }
+
+            // recomputed every frame, so that both resizes and changes to the
+            // layers are picked up
+            ctx.content_size = renderer.content_size(&ctx.layers);
+            ctx.transform = ViewportTransform::compute_scaled(
+                config.window.scaling,
+                ctx.content_size,
+                (swapchain.extent.width, swapchain.extent.height),
+                cell_scale(config, &ctx),
+            );
+
+            run_updates(app, &mut ctx, updates, fixed.step());
+            app.render(&mut ctx);
+
+            for secondary in &mut secondaries {
+                let view = ctx.windows.get_mut(&secondary.handle).unwrap();
+                secondary.draw(&mut context, view, !on_demand || ctx.redraw_requested);
+            }
+
+            // the vertex buffer and every uniform, the post-processing ones
+            // included, are written in place from here on
+            unsafe { context.device.wait_for_fence(&frame_fence, !0).unwrap() };
+            let changed = renderer.update(&mut context, &mut command_pool, &mut ctx.layers);
+            // a replay has no window events to wake the loop
+            waiting = on_demand
+                && replay.is_none()
+                && !ctx.redraw_requested
+                && ctx.window_commands.is_empty()
+                && ctx.windows_to_open.is_empty()
+                && ctx.windows_to_close.is_empty()
+                && !ctx.layers.cameras_moving()
+                && !ctx.windows.values().any(|view| view.layers.cameras_moving());
+            let redraw = changed || rebuild_swapchain || blackboard.should_redraw || ctx.redraw_requested;
+            blackboard.reset();
+            if framebuffer_state.is_none() {
+                // the rebuild failed and is retried next frame
+                waiting = false;
+                continue 'main;
+            }
+            if on_demand && !redraw {
+                continue 'main;
+            }
+
+            if let Some(ref mut post) = post {
+                post.update(&context.device);
+            }
+            let swapchain_itself = swapchain.swapchain.as_mut().unwrap();
+
+            let frame_index: SwapImageIndex = unsafe {
+                command_pool.reset();
+                match swapchain_itself.acquire_image(!0, FrameSync::Semaphore(&frame_semaphore)) {
+                    Ok(i) => i,
+                    Err(_) => {
+                        warn!("Rebuilding the swapchain because acquire_image errored");
+                        blackboard.should_rebuild_swapchain = true;
+                        waiting = false;
+                        continue 'main;
+                    }
+                }
+            };
+
+            let mut cmd_buffer = command_pool.acquire_command_buffer::<gfx_hal::command::OneShot>();
+            unsafe {
+                render_frame(&renderer, post.as_ref(), &mut cmd_buffer, &framebuffer_state, frame_index, &ctx.transform);
+            }
+
+            let submission = Submission {
+                wait_semaphores: Some((&frame_semaphore, PipelineStage::BOTTOM_OF_PIPE)),
+                signal_semaphores: std::iter::once(&present_semaphore),
+                command_buffers: Some(&cmd_buffer),
+            };
+
+            let result = unsafe {
+                context.device.reset_fence(&frame_fence).unwrap();
+                context.queue_group.queues[0].submit(submission, Some(&frame_fence));
+                swapchain_itself.present(&mut context.queue_group.queues[0], frame_index, vec![&present_semaphore])
+            };
+
+            if result.is_err() {
+                warn!("Rebuilding the swapchain because present errored");
+                blackboard.should_rebuild_swapchain = true;
+                waiting = false;
+            }
+
+            if let Some(min_frame_time) = min_frame_time {
+                let elapsed = now.elapsed();
+                if elapsed < min_frame_time {
+                    thread::sleep(min_frame_time - elapsed);
+                }
+            }
+        }
+
+        app.shutdown(&mut ctx);
+        gamepads.stop();
+
+        for secondary in secondaries {
+            unsafe { secondary.destroy(&mut context) };
+        }
+
+        if let Some(ref mut recorder) = recorder {
+            recorder.flush().expect("Unable to record input!");
+        }
+
+        let device = &context.device;
+
+        unsafe {
+            renderer.destroy(&context);
+            if let Some(post) = post {
+                post.destroy(&context);
+            }
+
+            device.destroy_command_pool(command_pool.into_raw());
+            device.destroy_semaphore(frame_semaphore);
+            device.destroy_semaphore(present_semaphore);
+            device.destroy_fence(frame_fence);
+        }
+    }
+}
+
+/// A window opened with `AppContext::open_window`, with its own surface,
+/// swapchain and renderer on the main window's device. The renderer shares
+/// the main renderer's atlases.
+struct Secondary<B: gfx_hal::Backend> {
+    handle: WindowHandle,
+    window: PlatformWindow,
+    surface: SurfaceId,
+    scaling: ScalingPolicy,
+    swapchain: SwapchainState<B>,
+    framebuffer_state: FramebufferState<B>,
+    renderer: MeshConsoleRenderer<B>,
+    post: Option<PostProcessChain<B>>,
+    command_pool: CommandPool<B, Graphics>,
+    frame_semaphore: B::Semaphore,
+    present_semaphore: B::Semaphore,
+    frame_fence: B::Fence,
+    blackboard: Blackboard,
+}
+
+impl<B: gfx_hal::Backend> Secondary<B> {
+    fn new(
+        handle: WindowHandle,
+        window: PlatformWindow,
+        surface: SurfaceId,
+        scaling: ScalingPolicy,
+        context: &mut Context<B>,
+        config: &Config,
+        main: &MeshConsoleRenderer<B>,
+    ) -> Self {
+        let command_pool = context.create_command_pool();
+        let mut post = unsafe { PostProcessChain::new(context, config) };
+        let renderer = unsafe { MeshConsoleRenderer::sharing_atlases(context, config, &LayerStack::default(), post.is_some(), main) };
+        let (width, height) = physical_size(&window);
+        let mut swapchain = SwapchainState::for_surface(context, surface, Extent2D { width, height });
+        let mut framebuffer_state = FramebufferState::new_empty(renderer.attachments());
+        if let Err(err) = rebuild_framebuffers(context, &renderer, &mut framebuffer_state, post.as_mut(), &mut swapchain) {
+            warn!("Unable to build a window's framebuffers, retrying next frame: {}", err);
+        }
+        Secondary {
+            handle,
+            window,
+            surface,
+            scaling,
+            swapchain,
+            framebuffer_state,
+            renderer,
+            post,
+            command_pool,
+            frame_semaphore: context.device.create_semaphore().unwrap(),
+            present_semaphore: context.device.create_semaphore().unwrap(),
+            frame_fence: context.device.create_fence(true).unwrap(),
+            blackboard: Blackboard::default(),
+        }
+    }
+
+    /// Dispatch the window's events and rebuild its swapchain if it was
+    /// resized. Returns true if the window was asked to close.
+    fn prepare(&mut self, context: &mut Context<B>, view: &mut WindowView, config: &Config) -> bool {
+        self.blackboard.observe(view.input.events());
+        view.input.dispatch();
+        if self.blackboard.should_quit {
+            return true;
+        }
+
+        if self.blackboard.should_rebuild_swapchain || self.framebuffer_state.is_none() {
+            context.device.wait_idle().unwrap();
+            unsafe { self.command_pool.reset() };
+            self.framebuffer_state.destroy(&context.device);
+            if let Some(ref mut post) = self.post {
+                post.destroy_framebuffers(&context.device);
+            }
+
+            let (width, height) = physical_size(&self.window);
+            self.swapchain.rebuild(context, Extent2D { width, height });
+            let rebuilt = rebuild_framebuffers(context, &self.renderer, &mut self.framebuffer_state, self.post.as_mut(), &mut self.swapchain);
+            if let Err(err) = rebuilt {
+                warn!("Unable to rebuild a window's framebuffers, skipping the frame: {}", err);
+            }
+
+            if self.scaling == ScalingPolicy::Resize {
+                let scale = self.cell_scale(config);
+                let size = (self.swapchain.extent.width as f32 / scale, self.swapchain.extent.height as f32 / scale);
+                let resized = view.layers.fit_to_window(size, self.renderer.cell_size());
+                view.resized_layers.extend(resized);
+            }
+        }
+
+        view.content_size = self.renderer.content_size(&view.layers);
+        view.transform = ViewportTransform::compute_scaled(
+            self.scaling,
+            view.content_size,
+            (self.swapchain.extent.width, self.swapchain.extent.height),
+            self.cell_scale(config),
+        );
+        false
+    }
+
+    /// Draw the window's layers if they have changed, the swapchain was
+    /// rebuilt or `force` is set.
+    fn draw(&mut self, context: &mut Context<B>, view: &mut WindowView, force: bool) {
+        unsafe { context.device.wait_for_fence(&self.frame_fence, !0).unwrap() };
+        let changed = self.renderer.update(context, &mut self.command_pool, &mut view.layers);
+        let rebuilt = self.blackboard.should_rebuild_swapchain;
+        let redraw = force || changed || rebuilt || self.blackboard.should_redraw;
+        self.blackboard.reset();
+        // a failed rebuild is retried by the next `prepare`
+        if !redraw || self.framebuffer_state.is_none() {
+            return;
+        }
+
+        if let Some(ref mut post) = self.post {
+            post.update(&context.device);
+        }
+        let swapchain = self.swapchain.swapchain.as_mut().unwrap();
+        let frame_index: SwapImageIndex = unsafe {
+            self.command_pool.reset();
+            match swapchain.acquire_image(!0, FrameSync::Semaphore(&self.frame_semaphore)) {
+                Ok(i) => i,
+                Err(_) => {
+                    warn!("Rebuilding a window's swapchain because acquire_image errored");
+                    self.blackboard.should_rebuild_swapchain = true;
+                    return;
+                }
+            }
+        };
+
+        let mut cmd_buffer = self.command_pool.acquire_command_buffer::<gfx_hal::command::OneShot>();
+        unsafe {
+            render_frame(&self.renderer, self.post.as_ref(), &mut cmd_buffer, &self.framebuffer_state, frame_index, &view.transform);
+        }
+
+        let submission = Submission {
+            wait_semaphores: Some((&self.frame_semaphore, PipelineStage::BOTTOM_OF_PIPE)),
+            signal_semaphores: std::iter::once(&self.present_semaphore),
+            command_buffers: Some(&cmd_buffer),
+        };
+        let result = unsafe {
+            context.device.reset_fence(&self.frame_fence).unwrap();
+            context.queue_group.queues[0].submit(submission, Some(&self.frame_fence));
+            swapchain.present(&mut context.queue_group.queues[0], frame_index, vec![&self.present_semaphore])
+        };
+        if result.is_err() {
+            warn!("Rebuilding a window's swapchain because present errored");
+            self.blackboard.should_rebuild_swapchain = true;
+        }
+    }
+
+    fn cell_scale(&self, config: &Config) -> f32 {
+        if config.window.scale_cells_by_dpi {
+            self.window.get_hidpi_factor() as f32
+        } else {
+            1.0
+        }
+    }
+
+    /// Destroy the window's GPU resources and remove its surface from the
+    /// context. The window closes when dropped.
+    unsafe fn destroy(mut self, context: &mut Context<B>) {
+        context.device.wait_idle().unwrap();
+        self.framebuffer_state.destroy(&context.device);
+        self.swapchain.destroy(&context.device);
+        self.renderer.destroy(context);
+        if let Some(post) = self.post {
+            post.destroy(context);
+        }
+        context.device.destroy_command_pool(self.command_pool.into_raw());
+        context.device.destroy_semaphore(self.frame_semaphore);
+        context.device.destroy_semaphore(self.present_semaphore);
+        context.device.destroy_fence(self.frame_fence);
+        context.remove_surface(self.surface);
+    }
+}
+
+/// Clear the per-frame state of the context. Handlers are reset after each
+/// update instead, so that input arriving on frames without an update is not
+/// lost.
+fn begin_frame(ctx: &mut AppContext) {
+    ctx.resized_layers.clear();
+    ctx.redraw_requested = false;
+    ctx.input.clear_events();
+    for view in ctx.windows.values_mut() {
+        view.resized_layers.clear();
+        view.input.clear_events();
+    }
+}
+
+/// Check the frame's events for window management on the blackboard, then
+/// pass them along the input chain and those left over to the app.
+fn dispatch_input<A: App>(app: &mut A, ctx: &mut AppContext, blackboard: &mut Blackboard) {
+    blackboard.observe(ctx.input.events());
+    ctx.should_quit |= blackboard.should_quit;
+    ctx.input.dispatch();
+    for event in ctx.input.events().to_vec() {
+        app.on_event(ctx, &event);
+    }
+}
+
+/// Run `updates` updates of `step` seconds, advancing the input handlers
+/// before each and resetting them after.
+fn run_updates<A: App>(app: &mut A, ctx: &mut AppContext, updates: u32, step: f32) {
+    for _ in 0..updates {
+        ctx.input.update_handlers(step);
+        for view in ctx.windows.values_mut() {
+            view.input.update_handlers(step);
+        }
+        app.update(ctx, step);
+        if ctx.actions.borrow().was_pressed("toggle_fullscreen") {
+            ctx.window_command(WindowCommand::ToggleFullscreen);
+        }
+        ctx.input.reset_handlers();
+        for view in ctx.windows.values_mut() {
+            view.input.reset_handlers();
+        }
+    }
+}
+
+/// Runs a `Runner` on the backend picked by `select`.
+struct RunVisitor<'r, 'a, A> {
+    runner: Runner<'a>,
+    app: &'r mut A,
+}
+
+impl<'r, 'a, A: App> BackendVisitor for RunVisitor<'r, 'a, A> {
+    type Output = ();
+
+    fn visit<B: WindowBackend>(self, instance: InstanceWrapper<B>) -> Result<(), Self> {
+        let mut window = Window::new(self.runner.config, instance.kind());
+        match instance.create_context(&mut window) {
+            Ok(context) => {
+                self.runner.run_with(instance, window, context, self.app);
+                Ok(())
+            }
+            Err(err) => {
+                warn!("unable to create a {:?} context: {}", instance.kind(), err);
+                Err(self)
+            }
+        }
+    }
+}
+
+/// Open the window for a `Secondary`, logging a warning if it cannot be
+/// opened or shares no queue family with the main window.
+fn open_secondary<B: WindowBackend>(
+    handle: WindowHandle,
+    window_config: &WindowConfig,
+    instance: &InstanceWrapper<B>,
+    window: &Window,
+    context: &mut Context<B>,
+    config: &Config,
+    main: &MeshConsoleRenderer<B>,
+) -> Option<Secondary<B>> {
+    if !instance.supports_multiple_windows() {
+        warn!("unable to open window {:?}: the backend supports a single window", handle);
+        return None;
+    }
+    let os_window = match window_config.build(&window.events_loop) {
+        Ok(os_window) => os_window,
+        Err(err) => {
+            warn!("unable to open window {:?}: {:?}", handle, err);
+            return None;
+        }
+    };
+    let surface = instance.create_surface(&os_window).expect("Backend supports multiple windows!");
+    match context.add_surface(surface) {
+        Ok(surface) => Some(Secondary::new(handle, os_window, surface, window_config.scaling, context, config, main)),
+        Err(err) => {
+            warn!("unable to draw to window {:?}: {}", handle, err);
+            None
+        }
+    }
+}
+
+/// Rebuild the framebuffers the renderer draws into from the swapchain, or
+/// an offscreen target followed by the post-processing chain's framebuffers.
+/// On error `framebuffer_state` is left empty, so that the next frame
+/// retries.
+fn rebuild_framebuffers<B: gfx_hal::Backend>(
+    context: &Context<B>,
+    renderer: &MeshConsoleRenderer<B>,
+    framebuffer_state: &mut FramebufferState<B>,
+    post: Option<&mut PostProcessChain<B>>,
+    swapchain: &mut SwapchainState<B>,
+) -> Result<(), TextureError> {
+    match post {
+        Some(post) => {
+            framebuffer_state.rebuild_offscreen(context, &renderer.render_pass, swapchain.extent)?;
+            if let Err(err) = post.rebuild(context, framebuffer_state, swapchain) {
+                framebuffer_state.destroy(&context.device);
+                return Err(err);
+            }
+            Ok(())
+        }
+        None => framebuffer_state.rebuild_from_swapchain(context, &renderer.render_pass, swapchain),
+    }
+}
+
+/// Record a frame into swapchain image `frame_index`, through the
+/// post-processing chain if there is one, and finish the command buffer.
+unsafe fn render_frame<B: gfx_hal::Backend>(
+    renderer: &MeshConsoleRenderer<B>,
+    post: Option<&PostProcessChain<B>>,
+    command_buffer: &mut CommandBuffer<B, Graphics, OneShot>,
+    framebuffer_state: &FramebufferState<B>,
+    frame_index: SwapImageIndex,
+    transform: &ViewportTransform,
+) {
+    let framebuffers = framebuffer_state.framebuffers();
+    match post {
+        Some(post) => {
+            // the scene has a single offscreen framebuffer
+            post.record(command_buffer, frame_index, framebuffer_state, |command_buffer| {
+                renderer.record(command_buffer, framebuffers, 0, transform)
+            });
+            command_buffer.finish();
+        }
+        None => renderer.render(command_buffer, framebuffers, frame_index, transform),
+    }
+}
+
+/// Pass a winit event to the input chain of the window it belongs to.
+fn route_event<B: gfx_hal::Backend>(
+    event: Event,
+    main: &mut RootEventHandler,
+    secondaries: &[Secondary<B>],
+    views: &mut HashMap<WindowHandle, WindowView>,
+) {
+    if let Event::WindowEvent { window_id, .. } = event {
+        let handle = secondaries
+            .iter()
+            .find(|secondary| secondary.window.id() == window_id)
+            .map(|secondary| secondary.handle);
+        if let Some(view) = handle.and_then(|handle| views.get_mut(&handle)) {
+            view.input.handle_event(event);
+            return;
+        }
+    }
+    main.handle_event(event);
+}
+
+/// Get the number of physical pixels per content pixel under the `Resize`
+/// scaling policy.
+fn cell_scale(config: &Config, ctx: &AppContext) -> f32 {
+    if config.window.scale_cells_by_dpi {
+        ctx.hidpi_factor as f32
+    } else {
+        1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{InputHandler, WindowInput};
+    use crate::keyboard::{KeyRepeat, KeyboardState, ModifiersState, VirtualKeyCode};
+    use crate::winit::{ElementState, KeyboardInput};
+    use std::io::Cursor;
+
+    /// Logs what its handlers saw on each update.
+    #[derive(Default)]
+    struct Logger {
+        keyboard: Rc<RefCell<KeyboardState>>,
+        log: Vec<String>,
+    }
+
+    impl App for Logger {
+        fn init(&mut self, ctx: &mut AppContext) {
+            let repeat = KeyRepeat { delay: 0.05, interval: 0.02 };
+            self.keyboard = Rc::new(RefCell::new(KeyboardState::default().with_repeat(repeat)));
+            ctx.input.push_handler(1, Rc::clone(&self.keyboard));
+        }
+
+        fn update(&mut self, ctx: &mut AppContext, _dt: f32) {
+            let keyboard = self.keyboard.borrow();
+            let actions = ctx.actions.borrow();
+            self.log.push(format!(
+                "update {}: left {} {} west {}",
+                self.log.len(),
+                keyboard.was_pressed(VirtualKeyCode::Left),
+                keyboard.was_repeated(VirtualKeyCode::Left),
+                actions.was_triggered("move_west"),
+            ));
+        }
+    }
+
+    fn key(state: ElementState) -> InputEvent {
+        InputEvent::Window(WindowInput::KeyboardInput {
+            input: KeyboardInput {
+                scancode: 0,
+                state,
+                virtual_keycode: Some(VirtualKeyCode::Left),
+                modifiers: ModifiersState::default(),
+            },
+        })
+    }
+
+    #[test]
+    fn fixed_step_caps_updates_per_frame() {
+        let timing = TimingConfig {
+            updates_per_second: 20.0,
+            max_updates_per_frame: 2,
+            ..TimingConfig::default()
+        };
+        let mut fixed = FixedStep::new(&timing);
+        assert_eq!(fixed.advance(0.025), 0);
+        assert_eq!(fixed.advance(0.025), 1);
+        // a long frame is capped, and the updates beyond the limit dropped
+        assert_eq!(fixed.advance(MAX_FRAME_TIME * 2.0), 2);
+        assert_eq!(fixed.advance(0.025), 0);
+    }
+
+    #[test]
+    fn window_close_is_seen_past_a_modal_handler() {
+        struct Modal;
+
+        impl InputHandler for Modal {
+            fn handle_event(&mut self, _event: InputEvent) -> Option<InputEvent> {
+                None
+            }
+        }
+
+        let config = Config::default();
+        let mut app = Logger::default();
+        let mut ctx = AppContext::new(&config, LayerStack::default(), AtlasInfo::default(), 1.0);
+        let mut blackboard = Blackboard::default();
+        ctx.input.push_handler(100, Modal);
+        ctx.input.push(InputEvent::Window(WindowInput::HiDpiFactorChanged(2.0)));
+        ctx.input.push(InputEvent::Window(WindowInput::CloseRequested));
+        dispatch_input(&mut app, &mut ctx, &mut blackboard);
+
+        assert!(ctx.should_quit);
+        assert!(blackboard.should_rebuild_swapchain);
+        assert!(ctx.input.events().is_empty());
+    }
+
+    #[test]
+    fn headless_replay_matches_the_recorded_session() {
+        let config = Config::default();
+        let frames = vec![
+            (0.004, vec![]),
+            (0.013, vec![key(ElementState::Pressed)]),
+            (0.021, vec![]),
+            (0.002, vec![]),
+            (0.04, vec![key(ElementState::Pressed)]),
+            (0.3, vec![]),
+            (0.011, vec![key(ElementState::Released)]),
+            (0.017, vec![]),
+        ];
+
+        // run the session as the windowed loop would, recording it
+        let mut live = Logger::default();
+        let mut ctx = AppContext::new(&config, LayerStack::default(), AtlasInfo::default(), 1.0);
+        live.init(&mut ctx);
+        let mut recorder = InputRecorder::new(Vec::new());
+        let mut blackboard = Blackboard::default();
+        let mut fixed = FixedStep::new(&config.timing);
+        for (dt, events) in frames {
+            begin_frame(&mut ctx);
+            for event in events {
+                ctx.input.push(event);
+            }
+            recorder.record(&ctx.input, dt).unwrap();
+            let updates = fixed.advance(dt);
+            dispatch_input(&mut live, &mut ctx, &mut blackboard);
+            run_updates(&mut live, &mut ctx, updates, fixed.step());
+            blackboard.reset();
+        }
+
+        let mut replayed = Logger::default();
+        let replay = InputReplay::new(Cursor::new(recorder.into_inner())).unwrap();
+        Runner::new(&config).run_headless(replay, &mut replayed).unwrap();
+
+        assert!(live.log.iter().any(|line| line.contains("left true")));
+        assert!(live.log.iter().any(|line| line.contains("false true")));
+        assert_eq!(replayed.log, live.log);
+    }
+}
@@ -0,0 +1,252 @@
+use serde_derive::{Deserialize, Serialize};
+
+use crate::context::{Context, ContextError, InstanceWrapper};
+use crate::window::{PlatformWindow, Window};
+
+/// A graphics backend, compiled in by enabling the cargo feature of the same
+/// name in lower case. Several may be compiled in at once, and the first
+/// usable one in `GraphicsConfig::backends` is picked at startup.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum BackendKind {
+    Vulkan,
+    Metal,
+    Dx12,
+    Gl,
+    /// Draws nothing and has no adapters, so it is never picked. Lets code
+    /// generic over `WindowBackend` build and be tested without a GPU.
+    Empty,
+}
+
+impl BackendKind {
+    /// Every backend that can draw, in the default order of preference.
+    pub const ALL: [BackendKind; 4] = [BackendKind::Vulkan, BackendKind::Metal, BackendKind::Dx12, BackendKind::Gl];
+
+    /// Check if the backend's feature was enabled.
+    pub fn is_compiled(self) -> bool {
+        match self {
+            BackendKind::Vulkan => cfg!(feature = "vulkan"),
+            BackendKind::Metal => cfg!(feature = "metal"),
+            BackendKind::Dx12 => cfg!(feature = "dx12"),
+            BackendKind::Gl => cfg!(feature = "gl"),
+            BackendKind::Empty => cfg!(feature = "empty"),
+        }
+    }
+
+    /// Check if contexts of the backend can draw to more than one window.
+    /// The GL backend ties each surface to its own GL context, so it cannot.
+    pub fn supports_multiple_windows(self) -> bool {
+        self != BackendKind::Gl
+    }
+
+    /// Check if a render pass drawing straight to the window clears its depth
+    /// buffer. The GL backend leaves the window's own depth buffer alone.
+    pub fn clears_window_depth(self) -> bool {
+        self != BackendKind::Gl
+    }
+}
+
+/// The parts of creating windows and contexts that differ between backends.
+/// Code generic over this trait runs on whichever backend was picked.
+pub trait WindowBackend: gfx_hal::Backend {
+    const KIND: BackendKind;
+
+    /// Whatever must outlive the backend's contexts and surfaces.
+    type Instance;
+
+    /// Create the instance, or None if the backend is unusable on this
+    /// machine, such as when no driver is installed.
+    fn create_instance() -> Option<Self::Instance>;
+
+    /// Create a context drawing to `window`, which must have been opened for
+    /// this backend.
+    fn create_context(instance: &Self::Instance, window: &mut Window) -> Result<Context<Self>, ContextError>;
+
+    /// Create a surface for another window, if the backend supports it.
+    fn create_surface(instance: &Self::Instance, window: &PlatformWindow) -> Option<Self::Surface>;
+
+    /// Get the platform window of `window`, which may have moved into the
+    /// context's surface.
+    fn platform_window<'a>(window: &'a Window, context: &'a Context<Self>) -> &'a PlatformWindow;
+}
+
+/// Implement `WindowBackend` for a backend whose instance creates surfaces
+/// from plain winit windows.
+macro_rules! winit_backend {
+    ($krate:ident, $kind:expr) => {
+        impl WindowBackend for $krate::Backend {
+            const KIND: BackendKind = $kind;
+
+            type Instance = $krate::Instance;
+
+            fn create_instance() -> Option<Self::Instance> {
+                // the backends panic when their driver cannot be loaded
+                let create = || $krate::Instance::create("jadis", 1);
+                match std::panic::catch_unwind(std::panic::AssertUnwindSafe(create)) {
+                    Ok(ref instance) if gfx_hal::Instance::enumerate_adapters(instance).is_empty() => {
+                        log::warn!("no {:?} adapters found", Self::KIND);
+                        None
+                    }
+                    Ok(instance) => Some(instance),
+                    Err(_) => {
+                        log::warn!("unable to create {:?} instance", Self::KIND);
+                        None
+                    }
+                }
+            }
+
+            fn create_context(instance: &Self::Instance, window: &mut Window) -> Result<Context<Self>, ContextError> {
+                let window = window.window.as_ref().expect("Window was not opened for this backend!");
+                // as with instances, the backends panic when they cannot
+                // create a surface
+                let create = || instance.create_surface(window);
+                let surface = std::panic::catch_unwind(std::panic::AssertUnwindSafe(create))
+                    .map_err(|_| ContextError::SurfaceCreationFailed)?;
+                let adapters = gfx_hal::Instance::enumerate_adapters(instance);
+                Context::new(Self::KIND, surface, adapters)
+            }
+
+            fn create_surface(instance: &Self::Instance, window: &PlatformWindow) -> Option<Self::Surface> {
+                Some(instance.create_surface(window))
+            }
+
+            fn platform_window<'a>(window: &'a Window, _context: &'a Context<Self>) -> &'a PlatformWindow {
+                window.window.as_ref().expect("Window was not opened for this backend!")
+            }
+        }
+    };
+}
+
+#[cfg(feature = "vulkan")]
+winit_backend!(gfx_backend_vulkan, BackendKind::Vulkan);
+#[cfg(feature = "metal")]
+winit_backend!(gfx_backend_metal, BackendKind::Metal);
+#[cfg(feature = "dx12")]
+winit_backend!(gfx_backend_dx12, BackendKind::Dx12);
+
+#[cfg(feature = "gl")]
+impl WindowBackend for gfx_backend_gl::Backend {
+    const KIND: BackendKind = BackendKind::Gl;
+
+    /// GL has no instance; adapters come from the window's GL context.
+    type Instance = ();
+
+    fn create_instance() -> Option<Self::Instance> {
+        Some(())
+    }
+
+    fn create_context(_instance: &Self::Instance, window: &mut Window) -> Result<Context<Self>, ContextError> {
+        let window = window.gl_window.take().expect("Window already has a context!");
+        let surface = gfx_backend_gl::Surface::from_window(window);
+        let adapters = gfx_hal::Instance::enumerate_adapters(&surface);
+        Context::new(Self::KIND, surface, adapters)
+    }
+
+    fn create_surface(_instance: &Self::Instance, _window: &PlatformWindow) -> Option<Self::Surface> {
+        None
+    }
+
+    fn platform_window<'a>(_window: &'a Window, context: &'a Context<Self>) -> &'a PlatformWindow {
+        context.surface.get_window()
+    }
+}
+
+#[cfg(feature = "empty")]
+impl WindowBackend for gfx_backend_empty::Backend {
+    const KIND: BackendKind = BackendKind::Empty;
+
+    type Instance = gfx_backend_empty::Instance;
+
+    /// The empty backend cannot draw, so it is never usable.
+    fn create_instance() -> Option<Self::Instance> {
+        None
+    }
+
+    fn create_context(instance: &Self::Instance, _window: &mut Window) -> Result<Context<Self>, ContextError> {
+        let adapters = gfx_hal::Instance::enumerate_adapters(instance);
+        Context::new(Self::KIND, gfx_backend_empty::Surface, adapters)
+    }
+
+    fn create_surface(_instance: &Self::Instance, _window: &PlatformWindow) -> Option<Self::Surface> {
+        None
+    }
+
+    fn platform_window<'a>(window: &'a Window, _context: &'a Context<Self>) -> &'a PlatformWindow {
+        window.window.as_ref().expect("Window was not opened for this backend!")
+    }
+}
+
+/// Called by `select` with the instance of each backend tried. Generic code
+/// cannot be passed as a closure, so entry points that work on any backend
+/// implement this instead.
+pub trait BackendVisitor: Sized {
+    type Output;
+
+    /// Use the backend, or return the visitor back so that the next backend
+    /// is tried, such as when no context can be created on it.
+    fn visit<B: WindowBackend>(self, instance: InstanceWrapper<B>) -> Result<Self::Output, Self>;
+}
+
+/// Pass the instance of each backend in `order` that is compiled in and
+/// usable to `visitor`, until one visit succeeds. Returns the visitor back if
+/// none does.
+pub fn select<V: BackendVisitor>(order: &[BackendKind], mut visitor: V) -> Result<V::Output, V> {
+    for &kind in order {
+        if !kind.is_compiled() {
+            continue;
+        }
+        #[allow(unreachable_patterns)]
+        let visited = match kind {
+            #[cfg(feature = "vulkan")]
+            BackendKind::Vulkan => try_backend::<gfx_backend_vulkan::Backend, _>(visitor),
+            #[cfg(feature = "metal")]
+            BackendKind::Metal => try_backend::<gfx_backend_metal::Backend, _>(visitor),
+            #[cfg(feature = "dx12")]
+            BackendKind::Dx12 => try_backend::<gfx_backend_dx12::Backend, _>(visitor),
+            #[cfg(feature = "gl")]
+            BackendKind::Gl => try_backend::<gfx_backend_gl::Backend, _>(visitor),
+            #[cfg(feature = "empty")]
+            BackendKind::Empty => try_backend::<gfx_backend_empty::Backend, _>(visitor),
+            _ => Err(visitor),
+        };
+        visitor = match visited {
+            Ok(output) => return Ok(output),
+            Err(visitor) => visitor,
+        };
+    }
+    Err(visitor)
+}
+
+/// Visit backend `B` if its instance can be created.
+#[allow(dead_code)]
+fn try_backend<B: WindowBackend, V: BackendVisitor>(visitor: V) -> Result<V::Output, V> {
+    match InstanceWrapper::<B>::new() {
+        Some(instance) => visitor.visit(instance),
+        None => Err(visitor),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts the backends it visits, refusing each.
+    struct Count(usize);
+
+    impl BackendVisitor for Count {
+        type Output = BackendKind;
+
+        fn visit<B: WindowBackend>(self, _instance: InstanceWrapper<B>) -> Result<BackendKind, Self> {
+            Err(Count(self.0 + 1))
+        }
+    }
+
+    #[test]
+    fn empty_backend_is_never_selected() {
+        assert_eq!(BackendKind::Empty.is_compiled(), cfg!(feature = "empty"));
+        assert!(!BackendKind::ALL.contains(&BackendKind::Empty));
+        match select(&[BackendKind::Empty], Count(0)) {
+            Err(visitor) => assert_eq!(visitor.0, 0),
+            Ok(kind) => panic!("selected {:?}", kind),
+        }
+    }
+}
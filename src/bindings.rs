@@ -0,0 +1,583 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::winit::{ElementState, KeyboardInput};
+use serde::de::value::{Error as ValueError, StrDeserializer};
+use serde::de::{Deserialize, IntoDeserializer};
+
+use crate::config::BindingsConfig;
+use crate::gamepad::{GamepadEvent, GamepadId};
+use crate::input::{InputEvent, InputHandler, WindowInput};
+use crate::keyboard::{ModifiersState, VirtualKeyCode};
+use crate::mouse::MouseButton;
+
+use log::warn;
+
+/// How far a gamepad axis must move before its bindings trigger.
+const AXIS_THRESHOLD: f32 = 0.5;
+
+/// The context whose bindings trigger whichever context is active, such as
+/// "toggle_fullscreen".
+pub const GLOBAL_CONTEXT: &str = "global";
+
+#[derive(Debug, PartialEq)]
+pub enum BindingError {
+    Empty,
+    UnknownModifier(String),
+    UnknownKey(String),
+    UnknownMouseButton(String),
+    UnknownGamepadInput(String),
+}
+
+impl fmt::Display for BindingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for BindingError {}
+
+/// A physical input that an action can be bound to. Gamepad bindings apply
+/// to every connected gamepad, but each pad's inputs are held separately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InputSource {
+    Key(VirtualKeyCode),
+    Mouse(MouseButton),
+    GamepadButton(u8),
+    /// A gamepad axis pushed past `AXIS_THRESHOLD` in the positive or
+    /// negative direction.
+    GamepadAxis { axis: u8, positive: bool },
+}
+
+/// An input together with the modifiers that must be held for it to
+/// trigger, such as "Ctrl+S". Modifiers must match exactly, so that "S" does
+/// not also trigger on Ctrl+S.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Binding {
+    pub source: InputSource,
+    pub modifiers: ModifiersState,
+}
+
+impl Binding {
+    /// Check if the binding triggers for an input with the given modifiers
+    /// held.
+    fn matches(&self, source: InputSource, modifiers: ModifiersState) -> bool {
+        self.source == source && self.modifiers == modifiers
+    }
+}
+
+impl FromStr for Binding {
+    type Err = BindingError;
+
+    /// Parse a binding such as "Up", "Ctrl+Shift+S", "Mouse:Left",
+    /// "Pad:Button0" or "Pad:Axis1Neg". Key names are those of winit's
+    /// `VirtualKeyCode`; modifier names are case-insensitive.
+    fn from_str(binding: &str) -> Result<Self, Self::Err> {
+        let mut parts = binding.split('+').map(str::trim).collect::<Vec<_>>();
+        let source = match parts.pop() {
+            Some(source) if !source.is_empty() => source,
+            _ => return Err(BindingError::Empty),
+        };
+
+        let mut modifiers = ModifiersState::default();
+        for modifier in parts {
+            match modifier.to_lowercase().as_str() {
+                "shift" => modifiers.shift = true,
+                "ctrl" | "control" => modifiers.ctrl = true,
+                "alt" => modifiers.alt = true,
+                "logo" | "super" | "cmd" => modifiers.logo = true,
+                _ => return Err(BindingError::UnknownModifier(modifier.to_owned())),
+            }
+        }
+
+        let source = if source.starts_with("Mouse:") {
+            let button = &source["Mouse:".len()..];
+            InputSource::Mouse(match button {
+                "Left" => MouseButton::Left,
+                "Right" => MouseButton::Right,
+                "Middle" => MouseButton::Middle,
+                other => MouseButton::Other(
+                    other
+                        .parse()
+                        .map_err(|_| BindingError::UnknownMouseButton(other.to_owned()))?,
+                ),
+            })
+        } else if source.starts_with("Pad:") {
+            parse_gamepad_input(&source["Pad:".len()..])
+                .ok_or_else(|| BindingError::UnknownGamepadInput(source.to_owned()))?
+        } else {
+            let deserializer: StrDeserializer<ValueError> = source.into_deserializer();
+            InputSource::Key(
+                VirtualKeyCode::deserialize(deserializer)
+                    .map_err(|_| BindingError::UnknownKey(source.to_owned()))?,
+            )
+        };
+        Ok(Binding { source, modifiers })
+    }
+}
+
+/// Parse "Button<n>", "Axis<n>Pos" or "Axis<n>Neg".
+fn parse_gamepad_input(input: &str) -> Option<InputSource> {
+    if input.starts_with("Button") {
+        return input["Button".len()..].parse().ok().map(InputSource::GamepadButton);
+    }
+    if !input.starts_with("Axis") {
+        return None;
+    }
+    let axis = &input["Axis".len()..];
+    let (axis, positive) = if axis.ends_with("Pos") {
+        (&axis[..axis.len() - 3], true)
+    } else if axis.ends_with("Neg") {
+        (&axis[..axis.len() - 3], false)
+    } else {
+        return None;
+    };
+    axis.parse().ok().map(|axis| InputSource::GamepadAxis { axis, positive })
+}
+
+/// Maps keys, chords and mouse buttons to named actions. Bindings are
+/// grouped by context, and only the bindings of the active context and of
+/// `GLOBAL_CONTEXT` trigger, so that e.g. text entry does not move the
+/// player. Events are passed on unmodified.
+#[derive(Clone, Debug, Default)]
+pub struct ActionMap {
+    contexts: HashMap<String, Vec<(Binding, String)>>,
+    context: String,
+    /// Actions held down, by the input that triggered them and the gamepad
+    /// it is on.
+    held: HashMap<(InputSource, Option<GamepadId>), Vec<String>>,
+    pressed: HashSet<String>,
+    released: HashSet<String>,
+    repeated: HashSet<String>,
+}
+
+impl ActionMap {
+    /// Build an action map from the `[bindings]` section of the config, with
+    /// the "game" context active. Bindings which fail to parse are skipped.
+    pub fn from_config(config: &BindingsConfig) -> Self {
+        let mut actions = ActionMap::default();
+        for (context, bindings) in &config.contexts {
+            for (action, inputs) in bindings {
+                for input in inputs {
+                    match input.parse() {
+                        Ok(binding) => actions.bind(context, action, binding),
+                        Err(err) => warn!("ignoring binding {} for {}.{}: {}", input, context, action, err),
+                    }
+                }
+            }
+        }
+        actions.set_context("game");
+        actions
+    }
+
+    /// Bind an input to an action within a context.
+    pub fn bind(&mut self, context: &str, action: &str, binding: Binding) {
+        self.contexts
+            .entry(context.to_owned())
+            .or_insert_with(Vec::new)
+            .push((binding, action.to_owned()));
+    }
+
+    /// Remove every binding of an action within a context.
+    pub fn unbind(&mut self, context: &str, action: &str) {
+        if let Some(bindings) = self.contexts.get_mut(context) {
+            bindings.retain(|(_, bound)| bound != action);
+        }
+    }
+
+    /// Get the name of the active context.
+    pub fn context(&self) -> &str {
+        &self.context
+    }
+
+    /// Switch to another context. Actions held in the old context, and global
+    /// ones, are released.
+    pub fn set_context(&mut self, context: &str) {
+        if context == self.context {
+            return;
+        }
+        self.context = context.to_owned();
+        self.release_all();
+    }
+
+    /// Check if an action is held down.
+    pub fn is_active(&self, action: &str) -> bool {
+        self.held.values().any(|actions| actions.iter().any(|held| held == action))
+    }
+
+    /// Check if an action was first pressed this frame.
+    pub fn was_pressed(&self, action: &str) -> bool {
+        self.pressed.contains(action)
+    }
+
+    /// Check if an action was released this frame.
+    pub fn was_released(&self, action: &str) -> bool {
+        self.released.contains(action)
+    }
+
+    /// Check if an action was pressed or repeated this frame.
+    pub fn was_triggered(&self, action: &str) -> bool {
+        self.was_pressed(action) || self.repeated.contains(action)
+    }
+
+    /// Press or release a key or mouse button, triggering the bindings in
+    /// the active and global contexts whose modifiers match those held.
+    pub fn handle_input(&mut self, source: InputSource, state: ElementState, modifiers: ModifiersState) {
+        self.handle_held(source, None, state, modifiers);
+    }
+
+    /// Press or release a button on a gamepad.
+    pub fn handle_button(&mut self, pad: GamepadId, button: u8, state: ElementState) {
+        self.handle_held(InputSource::GamepadButton(button), Some(pad), state, ModifiersState::default());
+    }
+
+    /// Press or release the bindings of both directions of an axis on a
+    /// gamepad.
+    pub fn handle_axis(&mut self, pad: GamepadId, axis: u8, value: f32) {
+        for &positive in &[true, false] {
+            let source = InputSource::GamepadAxis { axis, positive };
+            let pushed = if positive {
+                value > AXIS_THRESHOLD
+            } else {
+                value < -AXIS_THRESHOLD
+            };
+            if pushed != self.held.contains_key(&(source, Some(pad))) {
+                let state = if pushed {
+                    ElementState::Pressed
+                } else {
+                    ElementState::Released
+                };
+                self.handle_held(source, Some(pad), state, ModifiersState::default());
+            }
+        }
+    }
+
+    /// Press or release an input on a gamepad, or on the keyboard or mouse if
+    /// `pad` is None. An action is pressed when the first of its inputs is
+    /// pressed and released when the last is released.
+    fn handle_held(
+        &mut self,
+        source: InputSource,
+        pad: Option<GamepadId>,
+        state: ElementState,
+        modifiers: ModifiersState,
+    ) {
+        if state == ElementState::Released {
+            if let Some(actions) = self.held.remove(&(source, pad)) {
+                let released = actions.into_iter().filter(|action| !self.is_active(action)).collect::<Vec<_>>();
+                self.released.extend(released);
+            }
+            return;
+        }
+        if let Some(actions) = self.held.get(&(source, pad)) {
+            self.repeated.extend(actions.iter().cloned());
+            return;
+        }
+
+        let global = if self.context == GLOBAL_CONTEXT {
+            None
+        } else {
+            self.contexts.get(GLOBAL_CONTEXT)
+        };
+        let actions = self
+            .contexts
+            .get(&self.context)
+            .into_iter()
+            .chain(global)
+            .flatten()
+            .filter(|(binding, _)| binding.matches(source, modifiers))
+            .map(|(_, action)| action.clone())
+            .collect::<Vec<_>>();
+        if actions.is_empty() {
+            return;
+        }
+        let pressed = actions.iter().filter(|action| !self.is_active(action)).cloned().collect::<Vec<_>>();
+        self.pressed.extend(pressed);
+        self.held.insert((source, pad), actions);
+    }
+
+    /// Release everything held on a gamepad, such as when it disconnects.
+    fn release_pad(&mut self, pad: GamepadId) {
+        let inputs = self
+            .held
+            .keys()
+            .filter(|(_, held_pad)| *held_pad == Some(pad))
+            .cloned()
+            .collect::<Vec<_>>();
+        for (source, pad) in inputs {
+            self.handle_held(source, pad, ElementState::Released, ModifiersState::default());
+        }
+    }
+
+    fn release_all(&mut self) {
+        let held = std::mem::replace(&mut self.held, HashMap::new());
+        self.released.extend(held.into_iter().flat_map(|(_, actions)| actions));
+    }
+}
+
+impl InputHandler for ActionMap {
+    /// Trigger actions from key, mouse button and gamepad events.
+    fn handle_event(&mut self, event: InputEvent) -> Option<InputEvent> {
+        match event {
+            InputEvent::Window(ref event) => match *event {
+                WindowInput::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state,
+                            virtual_keycode: Some(key),
+                            modifiers,
+                            ..
+                        },
+                    ..
+                } => self.handle_input(InputSource::Key(key), state, modifiers),
+                WindowInput::MouseInput {
+                    state,
+                    button,
+                    modifiers,
+                    ..
+                } => self.handle_input(InputSource::Mouse(button), state, modifiers),
+                // key releases are not seen while unfocused
+                WindowInput::Focused(false) => self.release_all(),
+                _ => (),
+            },
+            InputEvent::Gamepad(GamepadEvent::Button { id, button, state }) => self.handle_button(id, button, state),
+            InputEvent::Gamepad(GamepadEvent::Axis { id, axis, value }) => self.handle_axis(id, axis, value),
+            InputEvent::Gamepad(GamepadEvent::Disconnected { id }) => self.release_pad(id),
+            _ => (),
+        }
+        Some(event)
+    }
+
+    /// Clear the per-frame state, keeping held actions.
+    fn reset(&mut self) {
+        self.pressed.clear();
+        self.released.clear();
+        self.repeated.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alt() -> ModifiersState {
+        ModifiersState {
+            alt: true,
+            ..ModifiersState::default()
+        }
+    }
+
+    fn map(bindings: &[(&str, &str)]) -> ActionMap {
+        let mut actions = ActionMap::default();
+        for &(action, input) in bindings {
+            actions.bind("game", action, input.parse().unwrap());
+        }
+        actions.set_context("game");
+        actions
+    }
+
+    fn binding(binding: &str) -> Binding {
+        binding.parse().unwrap()
+    }
+
+    #[test]
+    fn bindings_are_parsed() {
+        let save = binding("Ctrl+Shift+S");
+        assert_eq!(save.source, InputSource::Key(VirtualKeyCode::S));
+        assert!(save.modifiers.ctrl && save.modifiers.shift && !save.modifiers.alt && !save.modifiers.logo);
+        assert_eq!(binding(" alt + Return ").modifiers, alt());
+        assert!(binding("Super+Q").modifiers.logo);
+
+        assert_eq!(binding("Mouse:Left").source, InputSource::Mouse(MouseButton::Left));
+        assert_eq!(binding("Mouse:4").source, InputSource::Mouse(MouseButton::Other(4)));
+        assert_eq!(binding("Pad:Button3").source, InputSource::GamepadButton(3));
+        assert_eq!(binding("Pad:Axis1Neg").source, InputSource::GamepadAxis {
+            axis: 1,
+            positive: false,
+        });
+    }
+
+    #[test]
+    fn invalid_bindings_are_errors() {
+        let error = |binding: &str| binding.parse::<Binding>().unwrap_err();
+        assert_eq!(error(""), BindingError::Empty);
+        assert_eq!(error("Ctrl+"), BindingError::Empty);
+        assert_eq!(error("Hyper+A"), BindingError::UnknownModifier("Hyper".to_owned()));
+        assert_eq!(error("Enter"), BindingError::UnknownKey("Enter".to_owned()));
+        assert_eq!(error("Mouse:Fourth"), BindingError::UnknownMouseButton("Fourth".to_owned()));
+        for &input in &["Pad:Trigger", "Pad:Axis1", "Pad:AxisXPos", "Pad:Button"] {
+            assert_eq!(error(input), BindingError::UnknownGamepadInput(input.to_owned()));
+        }
+    }
+
+    #[test]
+    fn config_bindings_that_fail_to_parse_are_skipped() {
+        let mut config = BindingsConfig::default();
+        let mut game = HashMap::new();
+        game.insert("quit".to_owned(), vec!["Nope".to_owned(), "Q".to_owned()]);
+        let mut contexts = HashMap::new();
+        contexts.insert("game".to_owned(), game);
+        config.merge(contexts);
+
+        let mut actions = ActionMap::from_config(&config);
+        assert_eq!(actions.context(), "game");
+        actions.handle_input(InputSource::Key(VirtualKeyCode::Q), ElementState::Pressed, ModifiersState::default());
+        assert!(actions.was_pressed("quit"));
+    }
+
+    #[test]
+    fn actions_are_held_until_their_last_input_is_released() {
+        let mut actions = map(&[("move_west", "Left"), ("move_west", "H")]);
+        let (left, h) = (InputSource::Key(VirtualKeyCode::Left), InputSource::Key(VirtualKeyCode::H));
+        let none = ModifiersState::default();
+
+        actions.handle_input(left, ElementState::Pressed, none);
+        actions.handle_input(h, ElementState::Pressed, none);
+        assert!(actions.was_pressed("move_west"));
+        actions.reset();
+
+        // platform key repeat arrives as another press
+        actions.handle_input(left, ElementState::Pressed, none);
+        assert!(actions.was_triggered("move_west"));
+        assert!(!actions.was_pressed("move_west"));
+
+        actions.handle_input(left, ElementState::Released, none);
+        assert!(actions.is_active("move_west"));
+        assert!(!actions.was_released("move_west"));
+        actions.handle_input(h, ElementState::Released, none);
+        assert!(!actions.is_active("move_west"));
+        assert!(actions.was_released("move_west"));
+    }
+
+    #[test]
+    fn only_the_active_context_triggers() {
+        let mut actions = map(&[("move_north", "Up")]);
+        actions.bind("menu", "previous", binding("Up"));
+        let up = InputSource::Key(VirtualKeyCode::Up);
+        let none = ModifiersState::default();
+
+        actions.handle_input(up, ElementState::Pressed, none);
+        assert!(actions.is_active("move_north"));
+        assert!(!actions.is_active("previous"));
+
+        actions.set_context("menu");
+        assert!(!actions.is_active("move_north"));
+        assert!(actions.was_released("move_north"));
+        actions.reset();
+        actions.handle_input(up, ElementState::Released, none);
+        actions.handle_input(up, ElementState::Pressed, none);
+        assert!(actions.was_pressed("previous"));
+        assert!(!actions.was_pressed("move_north"));
+    }
+
+    #[test]
+    fn unbound_actions_no_longer_trigger() {
+        let mut actions = map(&[("quit", "Escape"), ("quit", "Q")]);
+        actions.unbind("game", "quit");
+        actions.handle_input(InputSource::Key(VirtualKeyCode::Q), ElementState::Pressed, ModifiersState::default());
+        assert!(!actions.was_pressed("quit"));
+    }
+
+    #[test]
+    fn losing_focus_releases_everything() {
+        let mut actions = map(&[("fire", "Mouse:Left")]);
+        let event = |state| {
+            InputEvent::Window(WindowInput::MouseInput {
+                state,
+                button: MouseButton::Left,
+                modifiers: ModifiersState::default(),
+            })
+        };
+        actions.handle_event(event(ElementState::Pressed));
+        assert!(actions.is_active("fire"));
+        actions.handle_event(InputEvent::Window(WindowInput::Focused(false)));
+        assert!(!actions.is_active("fire"));
+        assert!(actions.was_released("fire"));
+    }
+
+    #[test]
+    fn modifiers_must_match_exactly() {
+        let mut actions = map(&[("select", "Return"), ("toggle_fullscreen", "Alt+Return")]);
+        let key = InputSource::Key(VirtualKeyCode::Return);
+
+        actions.handle_input(key, ElementState::Pressed, alt());
+        assert!(actions.was_pressed("toggle_fullscreen"));
+        assert!(!actions.was_pressed("select"));
+        actions.handle_input(key, ElementState::Released, alt());
+        actions.reset();
+
+        actions.handle_input(key, ElementState::Pressed, ModifiersState::default());
+        assert!(actions.was_pressed("select"));
+        assert!(!actions.was_pressed("toggle_fullscreen"));
+    }
+
+    #[test]
+    fn global_bindings_trigger_in_every_context() {
+        let mut actions = ActionMap::from_config(&BindingsConfig::default());
+        let key = InputSource::Key(VirtualKeyCode::Return);
+        for context in &["game", "menu", "text_entry", GLOBAL_CONTEXT] {
+            actions.set_context(context);
+            actions.reset();
+            actions.handle_input(key, ElementState::Pressed, alt());
+            assert!(actions.was_pressed("toggle_fullscreen"), "in {}", context);
+            actions.handle_input(key, ElementState::Released, alt());
+        }
+
+        actions.set_context("menu");
+        actions.reset();
+        actions.handle_input(key, ElementState::Pressed, ModifiersState::default());
+        assert!(actions.was_pressed("select"));
+        assert!(!actions.was_pressed("toggle_fullscreen"));
+    }
+
+    #[test]
+    fn extra_modifiers_do_not_trigger_a_plain_binding() {
+        let mut actions = map(&[("select", "Return")]);
+        actions.handle_input(InputSource::Key(VirtualKeyCode::Return), ElementState::Pressed, alt());
+        assert!(!actions.was_pressed("select"));
+        assert!(!actions.is_active("select"));
+    }
+
+    #[test]
+    fn gamepads_hold_the_same_button_separately() {
+        let mut actions = map(&[("jump", "Pad:Button0")]);
+        let (first, second) = (GamepadId(0), GamepadId(1));
+
+        actions.handle_button(first, 0, ElementState::Pressed);
+        assert!(actions.was_pressed("jump"));
+        actions.reset();
+
+        actions.handle_button(second, 0, ElementState::Pressed);
+        assert!(!actions.was_pressed("jump"));
+        assert!(!actions.was_triggered("jump"));
+
+        actions.handle_button(first, 0, ElementState::Released);
+        assert!(actions.is_active("jump"));
+        assert!(!actions.was_released("jump"));
+
+        actions.handle_button(second, 0, ElementState::Released);
+        assert!(!actions.is_active("jump"));
+        assert!(actions.was_released("jump"));
+    }
+
+    #[test]
+    fn gamepad_axes_are_held_per_pad() {
+        let mut actions = map(&[("move_east", "Pad:Axis0Pos")]);
+        actions.handle_axis(GamepadId(0), 0, 1.0);
+        actions.handle_axis(GamepadId(1), 0, 1.0);
+        actions.handle_axis(GamepadId(0), 0, 0.0);
+        assert!(actions.is_active("move_east"));
+        actions.handle_axis(GamepadId(1), 0, 0.0);
+        assert!(!actions.is_active("move_east"));
+    }
+
+    #[test]
+    fn disconnecting_a_gamepad_releases_its_inputs() {
+        let mut actions = map(&[("jump", "Pad:Button0")]);
+        actions.handle_button(GamepadId(3), 0, ElementState::Pressed);
+        actions.handle_event(InputEvent::Gamepad(GamepadEvent::Disconnected { id: GamepadId(3) }));
+        assert!(!actions.is_active("jump"));
+        assert!(actions.was_released("jump"));
+    }
+}
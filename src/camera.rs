@@ -0,0 +1,174 @@
+/// View onto a layer: a scroll position in pixels and a zoom factor, each
+/// eased towards a target when smoothing is enabled.
+///
+/// A layer's cells are drawn so that the point at `position` within the
+/// layer appears at the layer's offset on screen, scaled by `zoom`. Scrolling
+/// by a fraction of a cell gives sub-cell offsets for smooth map scrolling.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Camera {
+    position: (f32, f32),
+    target: (f32, f32),
+    zoom: f32,
+    target_zoom: f32,
+    smoothing: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera {
+            position: (0.0, 0.0),
+            target: (0.0, 0.0),
+            zoom: 1.0,
+            target_zoom: 1.0,
+            smoothing: 0.0,
+        }
+    }
+}
+
+impl Camera {
+    /// Set the smoothing time constant in seconds. Zero disables smoothing,
+    /// so that scrolling and zooming take effect immediately.
+    pub fn with_smoothing(mut self, seconds: f32) -> Self {
+        self.smoothing = seconds.max(0.0);
+        self
+    }
+
+    /// Get the current scroll position in pixels.
+    pub fn position(&self) -> (f32, f32) {
+        self.position
+    }
+
+    /// Get the position the camera is scrolling towards.
+    pub fn target(&self) -> (f32, f32) {
+        self.target
+    }
+
+    /// Get the current zoom factor.
+    pub fn zoom(&self) -> f32 {
+        self.zoom
+    }
+
+    /// Scroll to a position in pixels.
+    pub fn scroll_to(&mut self, x: f32, y: f32) {
+        self.target = (x, y);
+        if self.smoothing == 0.0 {
+            self.position = self.target;
+        }
+    }
+
+    /// Scroll by a distance in pixels, relative to the current target.
+    pub fn scroll_by(&mut self, dx: f32, dy: f32) {
+        let (x, y) = self.target;
+        self.scroll_to(x + dx, y + dy);
+    }
+
+    /// Scroll to a possibly fractional cell position, given the layer's cell
+    /// size in pixels.
+    pub fn scroll_to_cell(&mut self, column: f32, row: f32, cell_size: (f32, f32)) {
+        self.scroll_to(column * cell_size.0, row * cell_size.1);
+    }
+
+    /// Move to a position immediately, cancelling any smooth scroll.
+    pub fn jump_to(&mut self, x: f32, y: f32) {
+        self.target = (x, y);
+        self.position = self.target;
+    }
+
+    /// Set the zoom factor to ease towards.
+    pub fn set_zoom(&mut self, zoom: f32) {
+        self.target_zoom = zoom.max(std::f32::EPSILON);
+        if self.smoothing == 0.0 {
+            self.zoom = self.target_zoom;
+        }
+    }
+
+    /// Check if the camera is still easing towards its target.
+    pub fn is_moving(&self) -> bool {
+        self.position != self.target || self.zoom != self.target_zoom
+    }
+
+    /// Advance smooth scrolling and zooming by `dt` seconds. Returns true if
+    /// the camera moved.
+    pub fn update(&mut self, dt: f32) -> bool {
+        if !self.is_moving() {
+            return false;
+        }
+        let t = if self.smoothing == 0.0 {
+            1.0
+        } else {
+            1.0 - (-dt / self.smoothing).exp()
+        };
+        // snap once close enough to the target to let the camera settle
+        let ease = |from: f32, to: f32, epsilon: f32| {
+            let next = from + (to - from) * t;
+            if (to - next).abs() < epsilon {
+                to
+            } else {
+                next
+            }
+        };
+        self.position = (
+            ease(self.position.0, self.target.0, 0.01),
+            ease(self.position.1, self.target.1, 0.01),
+        );
+        self.zoom = ease(self.zoom, self.target_zoom, 0.0001);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsmoothed_camera_moves_immediately() {
+        let mut camera = Camera::default();
+        camera.scroll_to(10.0, 20.0);
+        camera.scroll_by(-4.0, 4.0);
+        camera.set_zoom(2.0);
+        assert_eq!(camera.position(), (6.0, 24.0));
+        assert_eq!(camera.zoom(), 2.0);
+        assert!(!camera.is_moving());
+        assert!(!camera.update(0.1));
+    }
+
+    #[test]
+    fn smoothed_camera_eases_and_settles_on_its_target() {
+        let mut camera = Camera::default().with_smoothing(0.1);
+        camera.scroll_to_cell(2.0, 1.0, (8.0, 16.0));
+        assert_eq!(camera.target(), (16.0, 16.0));
+        assert_eq!(camera.position(), (0.0, 0.0));
+
+        assert!(camera.update(0.1));
+        let (x, y) = camera.position();
+        assert!(x > 0.0 && x < 16.0);
+        assert_eq!(x, y);
+
+        let mut updates = 0;
+        while camera.update(0.1) {
+            updates += 1;
+            assert!(updates < 100, "camera never settled");
+        }
+        assert_eq!(camera.position(), (16.0, 16.0));
+    }
+
+    #[test]
+    fn scroll_by_is_relative_to_the_target() {
+        let mut camera = Camera::default().with_smoothing(1.0);
+        camera.scroll_to(10.0, 0.0);
+        camera.scroll_by(5.0, 0.0);
+        assert_eq!(camera.target(), (15.0, 0.0));
+        assert_eq!(camera.position(), (0.0, 0.0));
+
+        camera.jump_to(3.0, 4.0);
+        assert_eq!(camera.position(), (3.0, 4.0));
+        assert!(!camera.is_moving());
+    }
+
+    #[test]
+    fn zoom_stays_positive() {
+        let mut camera = Camera::default();
+        camera.set_zoom(-1.0);
+        assert!(camera.zoom() > 0.0);
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufReader, Read};
 
@@ -6,50 +7,301 @@ use fern;
 use log;
 use serde_derive::{Deserialize, Serialize};
 use toml;
-#[cfg(not(feature = "gl"))]
-use gfx_backend::winit;
-#[cfg(feature = "gl")]
-use gfx_backend::glutin;
 
+use crate::backend::BackendKind;
+use crate::window::{load_icon, PlatformWindow, WindowCreationError};
+use crate::winit;
+
+/// How the console is scaled to fit the window.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum ScalingPolicy {
+    /// Stretch the console over the whole window, ignoring aspect ratio.
+    Stretch,
+    /// Scale by the largest whole number that fits, for pixel-perfect cells.
+    Integer,
+    /// Scale to fit the window, letterboxing the remaining space.
+    Fit,
+    /// Scale to cover the window, cropping whatever overflows.
+    Fill,
+    /// Draw cells at their natural size, resizing layers which fill the
+    /// window to as many columns and rows as fit.
+    Resize,
+}
+
+impl Default for ScalingPolicy {
+    fn default() -> Self {
+        ScalingPolicy::Fit
+    }
+}
+
+/// How the window covers the screen.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum FullscreenMode {
+    /// A normal window.
+    Windowed,
+    /// An undecorated window covering the whole monitor, which is quick to
+    /// switch in and out of.
+    Borderless,
+    /// The platform's own fullscreen mode. winit keeps the desktop's video
+    /// mode, so this differs from `Borderless` only in how the platform
+    /// treats the window.
+    Exclusive,
+}
+
+impl Default for FullscreenMode {
+    fn default() -> Self {
+        FullscreenMode::Windowed
+    }
+}
+
+/// Window settings. Sizes are in logical pixels, which are scaled by the
+/// window's HiDPI factor to give physical pixels.
 #[serde(default)]
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct WindowConfig {
+    pub title: String,
     pub width: u32,
     pub height: u32,
+    pub min_size: Option<(u32, u32)>,
+    pub max_size: Option<(u32, u32)>,
     pub decorations: bool,
+    pub resizable: bool,
+    pub maximized: bool,
+    pub fullscreen: FullscreenMode,
+    /// Scale cells by the window's HiDPI factor under the `Resize` scaling
+    /// policy, so that they keep their physical size on dense displays.
+    pub scale_cells_by_dpi: bool,
+    /// Index of the monitor to go fullscreen on. The primary monitor is used
+    /// if unset or out of range.
+    pub monitor: Option<usize>,
+    /// Path to an image to use as the window icon.
+    pub icon: Option<String>,
+    pub scaling: ScalingPolicy,
 }
 
 impl Default for WindowConfig {
     fn default() -> Self {
         WindowConfig {
+            title: "jadis".to_owned(),
             width: 800,
             height: 600,
+            min_size: None,
+            max_size: None,
             decorations: true,
+            resizable: true,
+            maximized: false,
+            fullscreen: FullscreenMode::default(),
+            scale_cells_by_dpi: false,
+            monitor: None,
+            icon: None,
+            scaling: ScalingPolicy::default(),
         }
     }
 }
 
 impl WindowConfig {
-    #[cfg(feature = "gl")]
-    pub fn get_builder(&self) -> glutin::WindowBuilder {
-        glutin::WindowBuilder::new()
-            .with_title("jadis")
-            .with_dimensions((self.width, self.height).into())
-            .with_decorations(self.decorations)
-    }
-    #[cfg(not(feature = "gl"))]
+    /// Get a builder for the window in its windowed state. Fullscreen modes
+    /// are applied once the window exists, by `WindowState`.
     pub fn get_builder(&self) -> winit::WindowBuilder {
-        winit::WindowBuilder::new()
-            .with_title("jadis")
+        let mut builder = winit::WindowBuilder::new()
+            .with_title(self.title.clone())
             .with_dimensions((self.width, self.height).into())
             .with_decorations(self.decorations)
+            .with_resizable(self.resizable)
+            .with_maximized(self.maximized)
+            .with_window_icon(self.icon.as_ref().and_then(|path| load_icon(path)));
+        if let Some(min_size) = self.min_size {
+            builder = builder.with_min_dimensions(min_size.into());
+        }
+        if let Some(max_size) = self.max_size {
+            builder = builder.with_max_dimensions(max_size.into());
+        }
+        builder
     }
-    #[cfg(not(feature = "gl"))]
-    pub fn build(&self, events_loop: &winit::EventsLoop) -> Result<winit::Window, winit::CreationError> {
+    /// Build a plain window, without a GL context.
+    pub fn build(&self, events_loop: &winit::EventsLoop) -> Result<PlatformWindow, WindowCreationError> {
         self.get_builder().build(&events_loop)
     }
 }
 
+/// Description of a tileset atlas: an image laid out as a grid of equally
+/// sized tiles, read row by row.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TilesetConfig {
+    pub path: String,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub columns: u32,
+    /// Optional codepoint-to-tile overrides. Codepoints without an entry map
+    /// to the tile with the same index, as in CP437 sheets.
+    #[serde(default)]
+    pub mapping: HashMap<char, u32>,
+}
+
+/// TrueType/OpenType font rasterised into a glyph cache of `cache_size`
+/// square pixels.
+#[serde(default)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FontConfig {
+    pub path: String,
+    pub size: f32,
+    pub cache_size: u32,
+}
+
+impl Default for FontConfig {
+    fn default() -> Self {
+        FontConfig {
+            path: String::new(),
+            size: 16.0,
+            cache_size: 512,
+        }
+    }
+}
+
+/// Bindings from action names to inputs, grouped by input context such as
+/// "game", "menu" or "text_entry"; those in the "global" context trigger in
+/// every context. Inputs are written as "Up", "Ctrl+S",
+/// "Mouse:Left", "Pad:Button0" or "Pad:Axis1Neg". When loaded from a config
+/// file, each action given replaces that action's default inputs and the
+/// other defaults are kept; an empty list unbinds an action.
+#[serde(transparent)]
+#[derive(Clone, Debug, Serialize)]
+pub struct BindingsConfig {
+    pub contexts: HashMap<String, HashMap<String, Vec<String>>>,
+}
+
+impl BindingsConfig {
+    /// Replace the inputs of each action in `contexts`, adding any new
+    /// contexts and actions.
+    pub fn merge(&mut self, contexts: HashMap<String, HashMap<String, Vec<String>>>) {
+        for (context, actions) in contexts {
+            self.contexts.entry(context).or_insert_with(HashMap::new).extend(actions);
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for BindingsConfig {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let contexts = serde::Deserialize::deserialize(deserializer)?;
+        let mut bindings = BindingsConfig::default();
+        bindings.merge(contexts);
+        Ok(bindings)
+    }
+}
+
+impl Default for BindingsConfig {
+    fn default() -> Self {
+        fn context(bindings: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+            bindings
+                .iter()
+                .map(|(action, inputs)| {
+                    let inputs = inputs.iter().map(|input| input.to_string()).collect();
+                    (action.to_string(), inputs)
+                })
+                .collect()
+        }
+        let mut contexts = HashMap::new();
+        contexts.insert("global".to_owned(), context(&[
+            ("toggle_fullscreen", &["Alt+Return"]),
+        ]));
+        contexts.insert("game".to_owned(), context(&[
+            ("quit", &["Escape"]),
+            ("move_north", &["Up", "K", "Numpad8", "Pad:Axis1Neg"]),
+            ("move_south", &["Down", "J", "Numpad2", "Pad:Axis1Pos"]),
+            ("move_west", &["Left", "H", "Numpad4", "Pad:Axis0Neg"]),
+            ("move_east", &["Right", "L", "Numpad6", "Pad:Axis0Pos"]),
+        ]));
+        contexts.insert("menu".to_owned(), context(&[
+            ("cancel", &["Escape", "Mouse:Right", "Pad:Button1"]),
+            ("select", &["Return", "Space", "Mouse:Left", "Pad:Button0"]),
+            ("previous", &["Up"]),
+            ("next", &["Down", "Tab"]),
+        ]));
+        contexts.insert("text_entry".to_owned(), context(&[
+            ("cancel", &["Escape"]),
+            ("submit", &["Return", "NumpadEnter"]),
+            ("delete_back", &["Back"]),
+            ("delete_forward", &["Delete"]),
+        ]));
+        BindingsConfig { contexts }
+    }
+}
+
+/// When the main loop runs.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum LoopMode {
+    /// Update and draw frames continuously.
+    Continuous,
+    /// Sleep until input arrives, then run one update and draw only what
+    /// has changed. Keeps running while a camera or the app is animating.
+    OnDemand,
+}
+
+impl Default for LoopMode {
+    fn default() -> Self {
+        LoopMode::Continuous
+    }
+}
+
+/// Timing of the main loop. The game is updated in fixed steps of
+/// `1 / updates_per_second` seconds, catching up by at most
+/// `max_updates_per_frame` steps per frame; frames are drawn as fast as
+/// possible, or at most `frame_cap` times a second.
+#[serde(default)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct TimingConfig {
+    pub mode: LoopMode,
+    pub updates_per_second: f32,
+    pub max_updates_per_frame: u32,
+    pub frame_cap: Option<f32>,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        TimingConfig {
+            mode: LoopMode::default(),
+            updates_per_second: 60.0,
+            max_updates_per_frame: 5,
+            frame_cap: None,
+        }
+    }
+}
+
+/// Graphics settings. `backends` lists the backends to try in order; those
+/// not compiled in or unusable on the machine are skipped. `depth` attaches
+/// a depth/stencil buffer to the render pass, in the best format the device
+/// supports. `samples` above 1 turns on multisampling, lowered to the
+/// highest count the device supports. `post_process` lists full-screen
+/// passes run in order over the rendered frame before it is presented.
+#[serde(default)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GraphicsConfig {
+    pub backends: Vec<BackendKind>,
+    pub depth: bool,
+    pub samples: u8,
+    pub post_process: Vec<PostPassConfig>,
+}
+
+impl Default for GraphicsConfig {
+    fn default() -> Self {
+        GraphicsConfig {
+            backends: BackendKind::ALL.to_vec(),
+            depth: false,
+            samples: 1,
+            post_process: Vec::new(),
+        }
+    }
+}
+
+/// A post-processing pass: a fragment shader sampling the previous pass's
+/// output, and up to `postprocess::MAX_PARAMS` values passed to it.
+#[serde(default)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PostPassConfig {
+    pub shader: String,
+    pub params: Vec<f32>,
+}
+
 #[serde(default)]
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct LoggingConfig {
@@ -97,7 +349,12 @@ impl LoggingConfig {
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Config {
     pub window: WindowConfig,
+    pub timing: TimingConfig,
+    pub graphics: GraphicsConfig,
     pub logging: LoggingConfig,
+    pub tilesets: Vec<TilesetConfig>,
+    pub font: Option<FontConfig>,
+    pub bindings: BindingsConfig,
 }
 
 impl Config {
@@ -117,3 +374,114 @@ impl Config {
         toml::from_str(&config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config_uses_the_defaults() {
+        let config: Config = toml::from_str("").unwrap();
+        let defaults = Config::default();
+        assert_eq!(config.window.title, defaults.window.title);
+        assert_eq!((config.window.width, config.window.height), (800, 600));
+        assert_eq!(config.window.scaling, ScalingPolicy::Fit);
+        assert_eq!(config.timing.mode, LoopMode::Continuous);
+        assert_eq!(config.graphics.backends, BackendKind::ALL.to_vec());
+        assert!(config.tilesets.is_empty());
+        assert!(config.font.is_none());
+        assert_eq!(config.bindings.contexts, BindingsConfig::default().contexts);
+    }
+
+    #[test]
+    fn missing_fields_take_their_section_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            [window]
+            width = 1024
+            scaling = "Integer"
+            fullscreen = "Borderless"
+
+            [timing]
+            mode = "OnDemand"
+            frame_cap = 30.0
+
+            [font]
+            path = "font.ttf"
+            "#,
+        )
+        .unwrap();
+        assert_eq!((config.window.width, config.window.height), (1024, 600));
+        assert_eq!(config.window.scaling, ScalingPolicy::Integer);
+        assert_eq!(config.window.fullscreen, FullscreenMode::Borderless);
+        assert_eq!(config.timing.mode, LoopMode::OnDemand);
+        assert_eq!(config.timing.frame_cap, Some(30.0));
+        assert_eq!(config.timing.updates_per_second, 60.0);
+        let font = config.font.unwrap();
+        assert_eq!((font.path.as_str(), font.size, font.cache_size), ("font.ttf", 16.0, 512));
+    }
+
+    #[test]
+    fn tilesets_are_parsed_with_their_mapping() {
+        let config: Config = toml::from_str(
+            r#"
+            [[tilesets]]
+            path = "tiles.png"
+            tile_width = 16
+            tile_height = 16
+            columns = 16
+
+            [tilesets.mapping]
+            "@" = 64
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.tilesets.len(), 1);
+        assert_eq!(config.tilesets[0].columns, 16);
+        assert_eq!(config.tilesets[0].mapping[&'@'], 64);
+    }
+
+    #[test]
+    fn invalid_values_are_errors() {
+        assert!(toml::from_str::<Config>("[window]\nscaling = \"Zoom\"").is_err());
+        assert!(toml::from_str::<Config>("[[tilesets]]\npath = \"tiles.png\"").is_err());
+        assert!(toml::from_str::<Config>("[graphics]\nbackends = [\"Glide\"]").is_err());
+    }
+
+    #[test]
+    fn example_config_parses() {
+        let config: Config = toml::from_str(include_str!("../config.example.toml")).unwrap();
+        assert_eq!((config.window.width, config.window.height), (1024, 768));
+        assert_eq!(config.logging.level_filter, log::LevelFilter::Info);
+    }
+
+    #[test]
+    fn missing_config_file_uses_the_defaults() {
+        let config = Config::load_from_file("does/not/exist.toml").unwrap();
+        assert_eq!(config.window.title, WindowConfig::default().title);
+    }
+
+    #[test]
+    fn bindings_are_merged_over_the_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            [bindings.game]
+            quit = ["Ctrl+Q"]
+
+            [bindings.global]
+            toggle_fullscreen = []
+
+            [bindings.editor]
+            save = ["Ctrl+S"]
+            "#,
+        )
+        .unwrap();
+        let contexts = &config.bindings.contexts;
+        let game = &contexts["game"];
+        assert_eq!(game["quit"], vec!["Ctrl+Q".to_owned()]);
+        assert!(contexts["global"]["toggle_fullscreen"].is_empty());
+        assert_eq!(game["move_north"], BindingsConfig::default().contexts["game"]["move_north"]);
+        assert!(contexts.contains_key("menu"));
+        assert_eq!(contexts["editor"]["save"], vec!["Ctrl+S".to_owned()]);
+    }
+}
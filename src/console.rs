@@ -0,0 +1,212 @@
+/// A single console cell: which atlas and tile to draw, and the colours to
+/// draw it with. For font atlases the tile is a codepoint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cell {
+    pub atlas: usize,
+    pub tile: u32,
+    pub fg: [f32; 4],
+    pub bg: [f32; 4],
+}
+
+impl Cell {
+    /// A fully transparent cell, letting lower layers show through.
+    pub const TRANSPARENT: Cell = Cell {
+        atlas: 0,
+        tile: 0,
+        fg: [1.0, 1.0, 1.0, 0.0],
+        bg: [0.0, 0.0, 0.0, 0.0],
+    };
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Cell {
+            atlas: 0,
+            tile: 0,
+            fg: [1.0, 1.0, 1.0, 1.0],
+            bg: [0.0, 0.0, 0.0, 1.0],
+        }
+    }
+}
+
+/// Grid of cells, stored row-major.
+#[derive(Clone, Debug)]
+pub struct Console {
+    width: usize,
+    height: usize,
+    cells: Vec<Cell>,
+    dirty: bool,
+}
+
+impl Console {
+    /// Create a new console filled with default cells.
+    pub fn new(width: usize, height: usize) -> Self {
+        Console {
+            width,
+            height,
+            cells: vec![Cell::default(); width * height],
+            dirty: true,
+        }
+    }
+
+    /// Get the number of columns.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Get the number of rows.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Change the number of columns and rows. Cells within both the old and
+    /// new size are kept; new cells are filled with `fill`.
+    pub fn resize(&mut self, width: usize, height: usize, fill: Cell) {
+        if width == self.width && height == self.height {
+            return;
+        }
+        let mut cells = vec![fill; width * height];
+        for y in 0..height.min(self.height) {
+            for x in 0..width.min(self.width) {
+                cells[y * width + x] = self.cells[y * self.width + x];
+            }
+        }
+        self.width = width;
+        self.height = height;
+        self.cells = cells;
+        self.dirty = true;
+    }
+
+    /// Get the cell at (x, y), if it is within the console.
+    pub fn get(&self, x: usize, y: usize) -> Option<&Cell> {
+        if x < self.width && y < self.height {
+            self.cells.get(y * self.width + x)
+        } else {
+            None
+        }
+    }
+
+    /// Set the cell at (x, y). Out-of-bounds writes are ignored.
+    pub fn set(&mut self, x: usize, y: usize, cell: Cell) {
+        if x < self.width && y < self.height {
+            self.cells[y * self.width + x] = cell;
+            self.dirty = true;
+        }
+    }
+
+    /// Set the atlas and tile of the cell at (x, y), keeping its colours.
+    pub fn set_tile(&mut self, x: usize, y: usize, atlas: usize, tile: u32) {
+        if let Some(&cell) = self.get(x, y) {
+            self.set(x, y, Cell { atlas, tile, ..cell });
+        }
+    }
+
+    /// Reset every cell to `cell`.
+    pub fn clear(&mut self, cell: Cell) {
+        for slot in &mut self.cells {
+            *slot = cell;
+        }
+        self.dirty = true;
+    }
+
+    /// Iterate over ((x, y), cell) for every cell in the console.
+    pub fn iter(&self) -> impl Iterator<Item = ((usize, usize), &Cell)> {
+        let width = self.width;
+        self.cells
+            .iter()
+            .enumerate()
+            .map(move |(index, cell)| ((index % width, index / width), cell))
+    }
+
+    /// Check if the console has changed since it was last marked clean.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Mark the console as up to date with the renderer.
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(tile: u32) -> Cell {
+        Cell { tile, ..Cell::default() }
+    }
+
+    #[test]
+    fn cells_are_stored_row_major() {
+        let mut console = Console::new(3, 2);
+        console.set(2, 1, tile(7));
+        assert_eq!(console.get(2, 1), Some(&tile(7)));
+        let position = console.iter().position(|(_, cell)| cell.tile == 7);
+        assert_eq!(position, Some(5));
+        assert_eq!(console.iter().nth(5).map(|(pos, _)| pos), Some((2, 1)));
+    }
+
+    #[test]
+    fn out_of_bounds_access_is_ignored() {
+        let mut console = Console::new(3, 2);
+        console.mark_clean();
+        console.set(3, 0, tile(1));
+        console.set(0, 2, tile(1));
+        console.set_tile(5, 5, 1, 1);
+        assert!(!console.is_dirty());
+        assert_eq!(console.get(3, 0), None);
+        assert_eq!(console.get(0, 2), None);
+        assert!(console.iter().all(|(_, cell)| *cell == Cell::default()));
+    }
+
+    #[test]
+    fn set_tile_keeps_colours() {
+        let mut console = Console::new(2, 2);
+        let red = Cell {
+            fg: [1.0, 0.0, 0.0, 1.0],
+            ..Cell::default()
+        };
+        console.set(1, 1, red);
+        console.set_tile(1, 1, 2, 65);
+        let cell = console.get(1, 1).unwrap();
+        assert_eq!((cell.atlas, cell.tile), (2, 65));
+        assert_eq!(cell.fg, red.fg);
+        assert_eq!(cell.bg, red.bg);
+    }
+
+    #[test]
+    fn resize_keeps_overlapping_cells() {
+        let mut console = Console::new(3, 3);
+        for y in 0..3 {
+            for x in 0..3 {
+                console.set(x, y, tile((y * 3 + x) as u32));
+            }
+        }
+        console.mark_clean();
+
+        console.resize(2, 4, Cell::TRANSPARENT);
+        assert!(console.is_dirty());
+        assert_eq!((console.width(), console.height()), (2, 4));
+        assert_eq!(console.get(1, 2), Some(&tile(7)));
+        assert_eq!(console.get(0, 3), Some(&Cell::TRANSPARENT));
+        assert_eq!(console.get(2, 0), None);
+
+        console.mark_clean();
+        console.resize(2, 4, Cell::default());
+        assert!(!console.is_dirty());
+    }
+
+    #[test]
+    fn writes_mark_the_console_dirty() {
+        let mut console = Console::new(1, 1);
+        assert!(console.is_dirty());
+        console.mark_clean();
+        console.set(0, 0, tile(1));
+        assert!(console.is_dirty());
+        console.mark_clean();
+        console.clear(Cell::TRANSPARENT);
+        assert!(console.is_dirty());
+        assert_eq!(console.get(0, 0), Some(&Cell::TRANSPARENT));
+    }
+}
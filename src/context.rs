@@ -1,39 +1,97 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::backend::{BackendKind, WindowBackend};
 use crate::hal_prelude::*;
-#[cfg(not(feature = "gl"))]
-use crate::window::Window;
+use crate::window::{PlatformWindow, Window};
+use gfx_hal::error::DeviceCreationError;
 use gfx_hal::window::SurfaceCapabilities;
+use gfx_hal::QueueFamily;
 
-use log::info;
+use log::{info, warn};
 
-pub struct InstanceWrapper {
-    #[cfg(not(feature = "gl"))]
-    instance: gfx_backend::Instance,
+#[derive(Debug)]
+pub enum SurfaceError {
+    /// The context's queue family cannot present to the surface.
+    UnsupportedQueueFamily,
+    /// The surface does not support the context's colour format.
+    UnsupportedFormat(Format),
 }
 
-impl InstanceWrapper {
-    pub fn new() -> Self {
-        InstanceWrapper {
-            #[cfg(not(feature = "gl"))]
-            instance: gfx_backend::Instance::create("jadis", 1),
-        }
+impl fmt::Display for SurfaceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
     }
+}
+impl Error for SurfaceError {}
+
+/// Error creating a `Context`, after which the next backend is tried.
+#[derive(Debug)]
+pub enum ContextError {
+    /// The backend found no adapters.
+    NoAdapters,
+    /// The backend could not create a surface for the window.
+    SurfaceCreationFailed,
+    /// No adapter could open a device whose queues present to the surface.
+    /// Holds the error from the last adapter tried.
+    DeviceCreationFailed(DeviceCreationError),
+    /// The surface supports no sRGB colour format on any adapter.
+    NoSrgbFormat,
+}
 
-    #[cfg(not(feature = "gl"))]
-    pub fn create_context(&self, window: &Window) -> Context<gfx_backend::Backend> {
-        Context::new(
-            self.instance.create_surface(&window.window),
-            self.instance.enumerate_adapters(),
-        )
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
     }
+}
+impl Error for ContextError {}
 
-    #[cfg(feature = "gl")]
-    pub fn create_context(
-        &self,
-        window: gfx_backend::glutin::GlWindow,
-    ) -> Context<gfx_backend::Backend> {
-        let surface = gfx_backend::Surface::from_window(window);
-        let adapters = surface.enumerate_adapters();
-        Context::new(surface, adapters)
+/// Handle to one of a `Context`'s surfaces. The surface the context was
+/// created with is `SurfaceId::PRIMARY`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SurfaceId(usize);
+
+impl SurfaceId {
+    pub const PRIMARY: SurfaceId = SurfaceId(0);
+}
+
+/// The instance of a backend, from which its contexts and surfaces are
+/// created.
+pub struct InstanceWrapper<B: WindowBackend> {
+    instance: B::Instance,
+}
+
+impl<B: WindowBackend> InstanceWrapper<B> {
+    /// Create the backend's instance, or None if it is unusable on this
+    /// machine.
+    pub fn new() -> Option<Self> {
+        let instance = B::create_instance()?;
+        info!("using the {:?} backend", B::KIND);
+        Some(InstanceWrapper { instance })
+    }
+
+    /// Get which backend this is.
+    pub fn kind(&self) -> BackendKind {
+        B::KIND
+    }
+
+    /// Create a context drawing to `window`, which must have been opened
+    /// for this backend. The GL backend moves the window into the
+    /// context's surface, so create only one context per window.
+    pub fn create_context(&self, window: &mut Window) -> Result<Context<B>, ContextError> {
+        B::create_context(&self.instance, window)
+    }
+
+    /// Check if a context can draw to more than one window.
+    pub fn supports_multiple_windows(&self) -> bool {
+        B::KIND.supports_multiple_windows()
+    }
+
+    /// Create a surface for another window, to be added to a context with
+    /// `Context::add_surface`. Returns None if `supports_multiple_windows`
+    /// is false.
+    pub fn create_surface(&self, window: &PlatformWindow) -> Option<B::Surface> {
+        B::create_surface(&self.instance, window)
     }
 }
 
@@ -53,6 +111,7 @@ where
 }
 
 pub struct Context<B: gfx_hal::Backend> {
+    kind: BackendKind,
     adapter: usize,
     available_adapters: Vec<gfx_hal::Adapter<B>>,
     pub device: B::Device,
@@ -60,13 +119,20 @@ pub struct Context<B: gfx_hal::Backend> {
     pub surface_colour_format: Format,
     pub surface_caps: SurfaceCapabilities,
     pub surface: <B as gfx_hal::Backend>::Surface,
+    /// Surfaces of additional windows, by `SurfaceId` less one. Removed
+    /// surfaces leave a gap so that ids stay valid.
+    extra_surfaces: Vec<Option<<B as gfx_hal::Backend>::Surface>>,
 }
 
 impl<B: gfx_hal::Backend> Context<B> {
+    /// Open a device presenting to `surface` on the most preferred of
+    /// `available_adapters` that can, trying the others in turn if it
+    /// cannot. `kind` is the backend `B` is.
     pub fn new(
+        kind: BackendKind,
         surface: <B as gfx_hal::Backend>::Surface,
-        mut available_adapters: Vec<gfx_hal::Adapter<B>>,
-    ) -> Self {
+        available_adapters: Vec<gfx_hal::Adapter<B>>,
+    ) -> Result<Self, ContextError> {
         for adapter in &available_adapters {
             info!(
                 "Found adapter: {} ({:?})",
@@ -74,9 +140,10 @@ impl<B: gfx_hal::Backend> Context<B> {
             );
         }
 
-        let adapter = get_preferred_adapter(&available_adapters, |(_index, adapter)| {
+        let mut order = (0..available_adapters.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&index| {
             use gfx_hal::adapter::DeviceType;
-            match adapter.info.device_type {
+            match available_adapters[index].info.device_type {
                 DeviceType::IntegratedGpu => 0,
                 DeviceType::DiscreteGpu => 1,
                 DeviceType::VirtualGpu => 2,
@@ -84,37 +151,88 @@ impl<B: gfx_hal::Backend> Context<B> {
                 DeviceType::Other => 4,
             }
         });
-        let (device, physical_device, queue_group) = {
-            let actual_adapter = &mut available_adapters[adapter];
+
+        let mut error = ContextError::NoAdapters;
+        for adapter in order {
+            let actual_adapter = &available_adapters[adapter];
             info!(
-                "==> Using adapter: {} ({:?})",
+                "==> Trying adapter: {} ({:?})",
                 actual_adapter.info.name, actual_adapter.info.device_type
             );
             let num_queues = 1;
-            let (device, queue_group) = actual_adapter
-                .open_with::<_, Graphics>(num_queues, |family| {
-                    surface.supports_queue_family(family)
-                })
-                .unwrap();
-            let physical_device = &actual_adapter.physical_device;
-
-            (device, physical_device, queue_group)
-        };
+            let opened = actual_adapter.open_with::<_, Graphics>(num_queues, |family| {
+                surface.supports_queue_family(family)
+            });
+            let (device, queue_group) = match opened {
+                Ok(opened) => opened,
+                Err(err) => {
+                    warn!("unable to open a device on {}: {:?}", actual_adapter.info.name, err);
+                    error = ContextError::DeviceCreationFailed(err);
+                    continue;
+                }
+            };
+
+            let (surface_caps, formats, _present_mode, _composite_alpha) =
+                surface.compatibility(&actual_adapter.physical_device);
+            let surface_colour_format = match Context::<B>::pick_surface_colour_format(formats) {
+                Some(format) => format,
+                None => {
+                    warn!("no sRGB surface format on {}", actual_adapter.info.name);
+                    error = ContextError::NoSrgbFormat;
+                    continue;
+                }
+            };
+
+            return Ok(Context {
+                kind,
+                adapter,
+                available_adapters,
+                device,
+                queue_group,
+                surface_colour_format,
+                surface_caps,
+                surface,
+                extra_surfaces: Vec::new(),
+            });
+        }
+        Err(error)
+    }
+
+    /// Add the surface of another window, so that it can be presented to
+    /// with the context's device and queues.
+    pub fn add_surface(&mut self, surface: <B as gfx_hal::Backend>::Surface) -> Result<SurfaceId, SurfaceError> {
+        let actual_adapter = &self.available_adapters[self.adapter];
+        let family = actual_adapter
+            .queue_families
+            .iter()
+            .find(|family| family.id() == self.queue_group.family());
+        if !family.map_or(false, |family| surface.supports_queue_family(family)) {
+            return Err(SurfaceError::UnsupportedQueueFamily);
+        }
+        let (_, formats, _, _) = surface.compatibility(&actual_adapter.physical_device);
+        if let Some(formats) = formats {
+            if !formats.contains(&self.surface_colour_format) {
+                return Err(SurfaceError::UnsupportedFormat(self.surface_colour_format));
+            }
+        }
+        self.extra_surfaces.push(Some(surface));
+        Ok(SurfaceId(self.extra_surfaces.len()))
+    }
 
-        let (surface_caps, formats, _present_mode, _composite_alpha) = surface.compatibility(physical_device);
-        let surface_colour_format = Context::<B>::pick_surface_colour_format(formats);
-
-        Context {
-            adapter,
-            available_adapters,
-            device,
-            queue_group,
-            surface_colour_format,
-            surface_caps,
-            surface,
+    /// Remove a surface added with `add_surface`, once its swapchain has been
+    /// destroyed.
+    pub fn remove_surface(&mut self, id: SurfaceId) -> Option<<B as gfx_hal::Backend>::Surface> {
+        match id.0.checked_sub(1) {
+            Some(index) => self.extra_surfaces.get_mut(index).and_then(Option::take),
+            None => None,
         }
     }
 
+    /// Get which backend the context is on.
+    pub fn kind(&self) -> BackendKind {
+        self.kind
+    }
+
     pub fn physical_device(&self) -> &B::PhysicalDevice {
         let actual_adapter = &self.available_adapters[self.adapter];
         &actual_adapter.physical_device
@@ -127,10 +245,26 @@ impl<B: gfx_hal::Backend> Context<B> {
         Option<Vec<Format>>,
         Vec<gfx_hal::PresentMode>,
         Vec<gfx_hal::CompositeAlpha>,
+    ) {
+        self.get_surface_compatibility(SurfaceId::PRIMARY)
+    }
+
+    pub fn get_surface_compatibility(
+        &self,
+        id: SurfaceId,
+    ) -> (
+        SurfaceCapabilities,
+        Option<Vec<Format>>,
+        Vec<gfx_hal::PresentMode>,
+        Vec<gfx_hal::CompositeAlpha>,
     ) {
         let actual_adapter = &self.available_adapters[self.adapter];
         let physical_device = &actual_adapter.physical_device;
-        self.surface.compatibility(physical_device)
+        let surface = match id.0.checked_sub(1) {
+            Some(index) => self.extra_surfaces[index].as_ref().expect("Surface was removed!"),
+            None => &self.surface,
+        };
+        surface.compatibility(physical_device)
     }
 
     pub fn create_command_pool(
@@ -150,10 +284,24 @@ impl<B: gfx_hal::Backend> Context<B> {
         &mut self,
         config: SwapchainConfig,
         old_swapchain: Option<B::Swapchain>,
+    ) -> (B::Swapchain, gfx_hal::Backbuffer<B>) {
+        self.create_surface_swapchain(SurfaceId::PRIMARY, config, old_swapchain)
+    }
+
+    pub fn create_surface_swapchain(
+        &mut self,
+        id: SurfaceId,
+        config: SwapchainConfig,
+        old_swapchain: Option<B::Swapchain>,
     ) -> (B::Swapchain, gfx_hal::Backbuffer<B>) {
         unsafe {
+            // borrow the surface and device separately
+            let surface = match id.0.checked_sub(1) {
+                Some(index) => self.extra_surfaces[index].as_mut().expect("Surface was removed!"),
+                None => &mut self.surface,
+            };
             self.device
-                .create_swapchain(&mut self.surface, config, old_swapchain)
+                .create_swapchain(surface, config, old_swapchain)
                 .expect("Failed to create swapchain!")
         }
     }
@@ -184,30 +332,101 @@ impl<B: gfx_hal::Backend> Context<B> {
         }
     }
 
+    /// Create a framebuffer per image view, each with `extra_views` attached
+    /// after its own view.
     pub fn image_views_to_fbos(
         &self,
         image_views: &[B::ImageView],
+        extra_views: &[&B::ImageView],
         render_pass: &B::RenderPass,
         extent: Extent,
     ) -> Result<Vec<B::Framebuffer>, gfx_hal::device::OutOfMemory> {
         image_views
             .iter()
             .map(|image_view| unsafe {
+                let attachments = Some(image_view).into_iter().chain(extra_views.iter().cloned());
                 self.device
-                    .create_framebuffer(&render_pass, vec![image_view], extent)
+                    .create_framebuffer(&render_pass, attachments, extent)
             })
             .collect()
     }
 
+    /// Pick the highest sample count no more than `requested` that the
+    /// device supports for colour attachments, and for depth/stencil
+    /// attachments too if `depth`.
+    pub fn pick_sample_count(&self, requested: u8, depth: bool) -> u8 {
+        let limits = self.physical_device().limits();
+        let mut supported = limits.framebuffer_color_samples_count;
+        if depth {
+            supported &= limits.framebuffer_depth_samples_count & limits.framebuffer_stencil_samples_count;
+        }
+        // counts are bit masks; backends without multisampled render
+        // targets, such as GL, report none
+        if requested <= 1 || supported == 0 {
+            return 1;
+        }
+        let samples = (0..8)
+            .rev()
+            .map(|bit| 1_u8 << bit)
+            .find(|&count| count <= requested && supported & count != 0)
+            .unwrap_or(1);
+        if samples != requested {
+            warn!("{} samples per pixel unsupported, using {}", requested, samples);
+        }
+        samples
+    }
+
+    /// Pick a format usable as a depth attachment, preferring those with a
+    /// stencil aspect and more depth bits. Returns None if the device
+    /// supports none of them.
+    pub fn pick_depth_format(&self) -> Option<Format> {
+        let candidates = [
+            Format::D32FloatS8Uint,
+            Format::D24UnormS8Uint,
+            Format::D32Float,
+            Format::D16Unorm,
+        ];
+        candidates.iter().cloned().find(|&format| {
+            self.physical_device()
+                .format_properties(Some(format))
+                .optimal_tiling
+                .contains(ImageFeature::DEPTH_STENCIL_ATTACHMENT)
+        })
+    }
+
     /// We pick a colour format from the list of supported formats. If there
-    /// is no list, we default to 'Rgba8Srgb'.
-    fn pick_surface_colour_format(formats: Option<Vec<Format>>) -> Format {
+    /// is no list, we default to 'Rgba8Srgb'. Returns None if no format in
+    /// the list is sRGB.
+    fn pick_surface_colour_format(formats: Option<Vec<Format>>) -> Option<Format> {
         match formats {
             Some(choices) => choices
                 .into_iter()
-                .find(|format| format.base_format().1 == ChannelType::Srgb)
-                .unwrap(),
-            None => Format::Rgba8Srgb,
+                .find(|format| format.base_format().1 == ChannelType::Srgb),
+            None => Some(Format::Rgba8Srgb),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "empty"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_without_adapters_is_an_error() {
+        let context = Context::<gfx_backend_empty::Backend>::new(BackendKind::Empty, gfx_backend_empty::Surface, vec![]);
+        match context {
+            Err(ContextError::NoAdapters) => (),
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("context created without adapters"),
         }
     }
+
+    #[test]
+    fn surface_colour_format_must_be_srgb() {
+        type EmptyContext = Context<gfx_backend_empty::Backend>;
+        assert_eq!(EmptyContext::pick_surface_colour_format(None), Some(Format::Rgba8Srgb));
+        let formats = vec![Format::Bgra8Unorm, Format::Bgra8Srgb];
+        assert_eq!(EmptyContext::pick_surface_colour_format(Some(formats)), Some(Format::Bgra8Srgb));
+        assert_eq!(EmptyContext::pick_surface_colour_format(Some(vec![Format::Bgra8Unorm])), None);
+    }
 }
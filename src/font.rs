@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+
+use gfx_hal::queue::capability::Graphics;
+use rusttype::{point, Scale};
+
+use crate::config::FontConfig;
+use crate::context::Context;
+use crate::hal_prelude::*;
+use crate::texture::{self, Texture, TextureError, TextureOptions, TextureRegion};
+use crate::tileset::grid_uv_rect;
+
+use log::info;
+
+#[derive(Debug)]
+pub enum FontError {
+    LoadFail(std::io::Error),
+    ParseFail(rusttype::Error),
+    TextureFail(TextureError),
+    CacheTooSmall,
+}
+
+impl From<std::io::Error> for FontError {
+    fn from(err: std::io::Error) -> Self {
+        FontError::LoadFail(err)
+    }
+}
+
+impl From<rusttype::Error> for FontError {
+    fn from(err: rusttype::Error) -> Self {
+        FontError::ParseFail(err)
+    }
+}
+
+impl From<TextureError> for FontError {
+    fn from(err: TextureError) -> Self {
+        FontError::TextureFail(err)
+    }
+}
+
+impl fmt::Display for FontError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for FontError {}
+
+/// Rasterises glyphs from a TrueType/OpenType font into fixed-size cells.
+pub struct FontRasteriser {
+    font: rusttype::Font<'static>,
+    scale: Scale,
+    ascent: f32,
+    cell_width: u32,
+    cell_height: u32,
+}
+
+impl FontRasteriser {
+    /// Load a font from disk at the given pixel size.
+    pub fn from_file(path: &str, size: f32) -> Result<Self, FontError> {
+        let bytes = fs::read(path)?;
+        FontRasteriser::from_bytes(bytes, size)
+    }
+
+    /// Parse an in-memory font at the given pixel size. The cell size is the
+    /// advance width of 'M' by the line height.
+    pub fn from_bytes(bytes: Vec<u8>, size: f32) -> Result<Self, FontError> {
+        let font = rusttype::Font::from_bytes(bytes)?;
+        let scale = Scale::uniform(size);
+        let v_metrics = font.v_metrics(scale);
+        let advance = font.glyph('M').scaled(scale).h_metrics().advance_width;
+        let line_height = v_metrics.ascent - v_metrics.descent + v_metrics.line_gap;
+        Ok(FontRasteriser {
+            font,
+            scale,
+            ascent: v_metrics.ascent,
+            cell_width: advance.ceil().max(1.0) as u32,
+            cell_height: line_height.ceil().max(1.0) as u32,
+        })
+    }
+
+    /// Get the (width, height) of a cell in pixels.
+    pub fn cell_size(&self) -> (u32, u32) {
+        (self.cell_width, self.cell_height)
+    }
+
+    /// Rasterise a glyph into a cell of white RGBA8 pixels, with coverage in
+    /// the alpha channel. Parts of the glyph outside the cell are clipped.
+    pub fn rasterise(&self, ch: char) -> Vec<u8> {
+        let (width, height) = (self.cell_width as i32, self.cell_height as i32);
+        let mut pixels = vec![0; (width * height * 4) as usize];
+        let glyph = self
+            .font
+            .glyph(ch)
+            .scaled(self.scale)
+            .positioned(point(0.0, self.ascent));
+        if let Some(bb) = glyph.pixel_bounding_box() {
+            glyph.draw(|x, y, coverage| {
+                let x = x as i32 + bb.min.x;
+                let y = y as i32 + bb.min.y;
+                if x >= 0 && x < width && y >= 0 && y < height {
+                    let index = ((y * width + x) * 4) as usize;
+                    pixels[index..index + 3].copy_from_slice(&[255, 255, 255]);
+                    pixels[index + 3] = (coverage * 255.0).round() as u8;
+                }
+            });
+        }
+        pixels
+    }
+}
+
+/// Slot allocator for glyphs, evicting the least recently used glyph when
+/// full. Usage is tracked per frame: a glyph used in the current frame is
+/// never evicted while older glyphs remain.
+#[derive(Debug)]
+pub struct GlyphCache {
+    slots: HashMap<char, usize>,
+    owners: Vec<Option<char>>,
+    last_used: Vec<u64>,
+    frame: u64,
+    evictions: u64,
+}
+
+impl GlyphCache {
+    /// Create an empty cache with `capacity` slots.
+    pub fn new(capacity: usize) -> Self {
+        GlyphCache {
+            slots: HashMap::with_capacity(capacity),
+            owners: vec![None; capacity],
+            last_used: vec![0; capacity],
+            frame: 0,
+            evictions: 0,
+        }
+    }
+
+    /// Get the number of slots.
+    pub fn capacity(&self) -> usize {
+        self.owners.len()
+    }
+
+    /// Get the number of glyphs currently cached.
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Check if the cache holds no glyphs.
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Check if a glyph is cached, without marking it as used.
+    pub fn contains(&self, ch: char) -> bool {
+        self.slots.contains_key(&ch)
+    }
+
+    /// Get the slot for a glyph, marking it as used this frame. Returns the
+    /// slot and whether the glyph was newly inserted and must be rasterised.
+    pub fn get_or_insert(&mut self, ch: char) -> Result<(usize, bool), FontError> {
+        if let Some(&slot) = self.slots.get(&ch) {
+            self.last_used[slot] = self.frame;
+            return Ok((slot, false));
+        }
+
+        let slot = match self.owners.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None => {
+                let slot = (0..self.capacity())
+                    .min_by_key(|&slot| self.last_used[slot])
+                    .ok_or(FontError::CacheTooSmall)?;
+                if self.last_used[slot] == self.frame {
+                    return Err(FontError::CacheTooSmall);
+                }
+                slot
+            }
+        };
+
+        if let Some(evicted) = self.owners[slot].take() {
+            self.slots.remove(&evicted);
+            self.evictions += 1;
+        }
+        self.owners[slot] = Some(ch);
+        self.slots.insert(ch, slot);
+        self.last_used[slot] = self.frame;
+        Ok((slot, true))
+    }
+
+    /// Start a new frame.
+    pub fn next_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    /// Get the number of glyphs evicted since the cache was created.
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+}
+
+/// GPU glyph-cache texture for a font, with glyphs rasterised on demand.
+pub struct FontAtlas<B: gfx_hal::Backend> {
+    pub texture: Texture<B>,
+    rasteriser: FontRasteriser,
+    cache: GlyphCache,
+    columns: u32,
+    pending: Vec<(usize, char)>,
+}
+
+impl<B: gfx_hal::Backend> FontAtlas<B> {
+    /// Load the font described by `config` and create an empty cache texture.
+    pub fn load(
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+        config: &FontConfig,
+    ) -> Result<Self, FontError> {
+        // a cache too large to address is rejected before anything loads
+        let blank_len = texture::rgba_len(config.cache_size, config.cache_size)?;
+        let rasteriser = FontRasteriser::from_file(&config.path, config.size)?;
+        let (cell_width, cell_height) = rasteriser.cell_size();
+        let columns = config.cache_size / cell_width;
+        let rows = config.cache_size / cell_height;
+        let capacity = columns as usize * rows as usize;
+        if capacity == 0 {
+            return Err(FontError::CacheTooSmall);
+        }
+        info!(
+            "font {} at {}px: {}x{} cells, {} cached glyphs",
+            config.path,
+            config.size,
+            cell_width,
+            cell_height,
+            capacity
+        );
+
+        let options = TextureOptions {
+            format: Format::Rgba8Unorm,
+            filter: Filter::Linear,
+            ..Default::default()
+        };
+        let blank = vec![0; blank_len];
+        let texture = Texture::from_rgba(
+            context,
+            command_pool,
+            config.cache_size,
+            config.cache_size,
+            &blank,
+            options,
+        )?;
+        Ok(FontAtlas {
+            texture,
+            rasteriser,
+            cache: GlyphCache::new(capacity),
+            columns,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Get the (width, height) of a cell in pixels.
+    pub fn cell_size(&self) -> (u32, u32) {
+        self.rasteriser.cell_size()
+    }
+
+    /// Get the cache slot holding a glyph, queueing it for rasterisation if
+    /// it is not already cached. Fails with `CacheTooSmall` if every slot is
+    /// in use this frame.
+    pub fn tile_for_char(&mut self, ch: char) -> Result<u32, FontError> {
+        let (slot, inserted) = self.cache.get_or_insert(ch)?;
+        if inserted {
+            self.pending.retain(|&(pending, _)| pending != slot);
+            self.pending.push((slot, ch));
+        }
+        Ok(slot as u32)
+    }
+
+    /// Get the number of glyphs evicted from the cache. Slots handed out
+    /// before an eviction may since hold another glyph.
+    pub fn evictions(&self) -> u64 {
+        self.cache.evictions()
+    }
+
+    /// Check if any glyphs are waiting to be uploaded.
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Get the top-left and bottom-right texture coordinates of a slot.
+    pub fn uv_rect(&self, tile: u32) -> ([f32; 2], [f32; 2]) {
+        grid_uv_rect(self.texture.extent(), self.cell_size(), self.columns, tile)
+    }
+
+    /// Rasterise and upload any glyphs queued since the last flush in a
+    /// single batch, then start a new cache frame. If the upload fails the
+    /// glyphs stay queued and are retried on the next flush.
+    pub fn flush(
+        &mut self,
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+    ) -> Result<(), TextureError> {
+        self.cache.next_frame();
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let (cell_width, cell_height) = self.cell_size();
+        let rasterised: Vec<_> = self
+            .pending
+            .iter()
+            .map(|&(slot, ch)| (slot as u32, self.rasteriser.rasterise(ch)))
+            .collect();
+        let regions: Vec<_> = rasterised
+            .iter()
+            .map(|(slot, pixels)| TextureRegion {
+                offset: (slot % self.columns * cell_width, slot / self.columns * cell_height),
+                size: (cell_width, cell_height),
+                pixels,
+            })
+            .collect();
+        self.texture.write_regions(context, command_pool, &regions)?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Destroy the font atlas.
+    pub fn destroy(&mut self, device: &B::Device) {
+        self.texture.destroy(device);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expect_full(result: Result<(usize, bool), FontError>) {
+        match result {
+            Err(FontError::CacheTooSmall) => (),
+            other => panic!("expected a full cache, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cached_glyphs_keep_their_slot() {
+        let mut cache = GlyphCache::new(2);
+        assert!(cache.is_empty());
+        assert_eq!(cache.get_or_insert('a').unwrap(), (0, true));
+        assert_eq!(cache.get_or_insert('b').unwrap(), (1, true));
+        assert_eq!(cache.get_or_insert('a').unwrap(), (0, false));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains('a') && cache.contains('b'));
+    }
+
+    #[test]
+    fn least_recently_used_glyph_is_evicted() {
+        let mut cache = GlyphCache::new(2);
+        cache.get_or_insert('a').unwrap();
+        cache.get_or_insert('b').unwrap();
+        cache.next_frame();
+        cache.get_or_insert('a').unwrap();
+        cache.next_frame();
+        assert_eq!(cache.evictions(), 0);
+
+        assert_eq!(cache.get_or_insert('c').unwrap(), (1, true));
+        assert!(!cache.contains('b'));
+        assert!(cache.contains('a') && cache.contains('c'));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.evictions(), 1);
+    }
+
+    #[test]
+    fn glyphs_used_this_frame_are_not_evicted() {
+        let mut cache = GlyphCache::new(2);
+        cache.get_or_insert('a').unwrap();
+        cache.get_or_insert('b').unwrap();
+        expect_full(cache.get_or_insert('c'));
+        assert!(cache.contains('a') && cache.contains('b'));
+
+        cache.next_frame();
+        assert_eq!(cache.get_or_insert('c').unwrap(), (0, true));
+        assert!(!cache.contains('a'));
+    }
+
+    #[test]
+    fn cache_without_slots_is_always_full() {
+        let mut cache = GlyphCache::new(0);
+        assert_eq!(cache.capacity(), 0);
+        expect_full(cache.get_or_insert('a'));
+    }
+}
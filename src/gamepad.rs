@@ -0,0 +1,536 @@
+use std::collections::{HashMap, HashSet};
+#[cfg(target_os = "linux")]
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read};
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::winit::EventsLoopProxy;
+pub use crate::winit::ElementState;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::input::{InputEvent, InputHandler, RootEventHandler};
+
+use log::{info, warn};
+
+/// Directory scanned for Linux joystick devices.
+#[cfg(target_os = "linux")]
+const JOYSTICK_DIR: &str = "/dev/input";
+
+/// How often a reader waiting on a device with nothing to read checks
+/// whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Handle to a connected gamepad.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct GamepadId(pub usize);
+
+/// Gamepad event, distributed alongside window events by `RootEventHandler`.
+/// Axis values are in -1..=1.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum GamepadEvent {
+    Connected { id: GamepadId, name: String },
+    Disconnected { id: GamepadId },
+    Button { id: GamepadId, button: u8, state: ElementState },
+    Axis { id: GamepadId, axis: u8, value: f32 },
+}
+
+/// Event in the format of the Linux joystick API, as read from
+/// `/dev/input/js*`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JoystickEvent {
+    /// Timestamp in milliseconds.
+    pub time: u32,
+    pub value: i16,
+    pub kind: u8,
+    pub number: u8,
+}
+
+impl JoystickEvent {
+    pub const BUTTON: u8 = 0x01;
+    pub const AXIS: u8 = 0x02;
+    /// Flag set on the synthetic events describing the initial state.
+    pub const INIT: u8 = 0x80;
+    pub const SIZE: usize = 8;
+
+    /// Decode an event from the device's native-endian layout.
+    pub fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        JoystickEvent {
+            time: u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            value: i16::from_ne_bytes([bytes[4], bytes[5]]),
+            kind: bytes[6],
+            number: bytes[7],
+        }
+    }
+
+    /// Encode an event in the device's layout, for feeding synthetic
+    /// devices.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let time = self.time.to_ne_bytes();
+        let value = self.value.to_ne_bytes();
+        [time[0], time[1], time[2], time[3], value[0], value[1], self.kind, self.number]
+    }
+
+    /// Convert to a `GamepadEvent`, if the event is a button or axis event.
+    pub fn to_gamepad_event(&self, id: GamepadId) -> Option<GamepadEvent> {
+        match self.kind & !Self::INIT {
+            Self::BUTTON => Some(GamepadEvent::Button {
+                id,
+                button: self.number,
+                state: if self.value != 0 {
+                    ElementState::Pressed
+                } else {
+                    ElementState::Released
+                },
+            }),
+            Self::AXIS => Some(GamepadEvent::Axis {
+                id,
+                axis: self.number,
+                value: (f32::from(self.value) / f32::from(std::i16::MAX)).max(-1.0),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Read one event, waiting while a non-blocking device has nothing to read.
+/// Returns None at the end of the device or once `stop` is set.
+fn read_event<R: Read>(device: &mut R, stop: &AtomicBool) -> io::Result<Option<JoystickEvent>> {
+    let mut bytes = [0; JoystickEvent::SIZE];
+    let mut filled = 0;
+    while filled < bytes.len() {
+        if stop.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        match device.read(&mut bytes[filled..]) {
+            Ok(0) => return Ok(None),
+            Ok(read) => filled += read,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(Some(JoystickEvent::from_bytes(bytes)))
+}
+
+/// The background thread reading a gamepad.
+struct Reader {
+    id: GamepadId,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Reader {
+    fn join(self) {
+        if self.thread.join().is_err() {
+            warn!("gamepad {:?} reader panicked", self.id);
+        }
+    }
+}
+
+/// Reads gamepads on background threads and feeds their events to a
+/// `RootEventHandler`. Devices are Linux joysticks found by `scan`, or any
+/// reader of `JoystickEvent`s passed to `attach`, which lets tests drive
+/// synthetic devices from memory or a pipe. Readers are told to stop when
+/// this is dropped; use `stop` to also wait for them.
+pub struct Gamepads {
+    sender: Sender<GamepadEvent>,
+    receiver: Receiver<GamepadEvent>,
+    /// Paths of the joystick devices currently being read.
+    open: Arc<Mutex<HashSet<PathBuf>>>,
+    readers: Vec<Reader>,
+    /// Woken on each event, so that a loop blocked waiting for window events
+    /// sees gamepad input too.
+    waker: Option<EventsLoopProxy>,
+    next_id: usize,
+}
+
+impl Default for Gamepads {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Gamepads {
+            sender,
+            receiver,
+            open: Arc::new(Mutex::new(HashSet::new())),
+            readers: Vec::new(),
+            waker: None,
+            next_id: 0,
+        }
+    }
+}
+
+impl Drop for Gamepads {
+    fn drop(&mut self) {
+        for reader in &self.readers {
+            reader.stop.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Gamepads {
+    /// Wake an events loop whenever a gamepad event arrives. Only devices
+    /// opened afterwards are affected.
+    pub fn set_waker(&mut self, waker: EventsLoopProxy) {
+        self.waker = Some(waker);
+    }
+
+    /// Open any Linux joystick devices that are not already open. Call again
+    /// to pick up controllers plugged in later.
+    #[cfg(target_os = "linux")]
+    pub fn scan(&mut self) {
+        let entries = match fs::read_dir(JOYSTICK_DIR) {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("unable to scan {} for gamepads: {}", JOYSTICK_DIR, err);
+                return;
+            }
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            let is_joystick = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map_or(false, |name| name.starts_with("js"));
+            if !is_joystick || self.open.lock().unwrap().contains(&path) {
+                continue;
+            }
+            // non-blocking, so that the reader can notice when it is stopped
+            let file = OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(&path);
+            match file {
+                Ok(file) => {
+                    self.open.lock().unwrap().insert(path.clone());
+                    let open = Arc::clone(&self.open);
+                    let name = path.display().to_string();
+                    self.spawn(name, file, move || {
+                        open.lock().unwrap().remove(&path);
+                    });
+                }
+                Err(err) => warn!("unable to open gamepad {}: {}", path.display(), err),
+            }
+        }
+    }
+
+    /// Read events from a device, such as an in-memory synthetic gamepad.
+    /// The gamepad disconnects when the reader ends or fails, or when it is
+    /// stopped. A reader which blocks is only stopped once a read returns;
+    /// one which returns `WouldBlock` is polled and stops promptly.
+    pub fn attach<R: Read + Send + 'static>(&mut self, name: &str, device: R) -> GamepadId {
+        self.spawn(name.to_owned(), device, || ())
+    }
+
+    /// Pass the events received since the last poll to `root`.
+    pub fn poll(&mut self, root: &mut RootEventHandler) {
+        let events = self.receiver.try_iter().collect::<Vec<_>>();
+        for event in events {
+            // the reader sends nothing after this, so it is about to finish
+            if let GamepadEvent::Disconnected { id } = event {
+                if let Some(reader) = self.take_reader(id) {
+                    reader.join();
+                }
+            }
+            root.push(event);
+        }
+    }
+
+    /// Stop reading a gamepad and wait for its thread to finish. It is
+    /// reported as disconnected on the next poll.
+    pub fn disconnect(&mut self, id: GamepadId) {
+        if let Some(reader) = self.take_reader(id) {
+            reader.stop.store(true, Ordering::SeqCst);
+            reader.join();
+        }
+    }
+
+    /// Stop reading every gamepad and wait for their threads to finish.
+    pub fn stop(&mut self) {
+        for reader in &self.readers {
+            reader.stop.store(true, Ordering::SeqCst);
+        }
+        for reader in self.readers.drain(..) {
+            reader.join();
+        }
+    }
+
+    fn take_reader(&mut self, id: GamepadId) -> Option<Reader> {
+        let index = self.readers.iter().position(|reader| reader.id == id)?;
+        Some(self.readers.remove(index))
+    }
+
+    fn spawn<R, F>(&mut self, name: String, mut device: R, on_disconnect: F) -> GamepadId
+    where
+        R: Read + Send + 'static,
+        F: FnOnce() + Send + 'static,
+    {
+        let id = GamepadId(self.next_id);
+        self.next_id += 1;
+        info!("gamepad {:?} connected: {}", id, name);
+
+        let sender = self.sender.clone();
+        let waker = self.waker.clone();
+        let send = move |event| {
+            let sent = sender.send(event).is_ok();
+            if let Some(ref waker) = waker {
+                let _ = waker.wakeup();
+            }
+            sent
+        };
+        send(GamepadEvent::Connected { id, name });
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            loop {
+                let event = match read_event(&mut device, &stopped) {
+                    Ok(Some(event)) => event,
+                    Ok(None) => break,
+                    Err(err) => {
+                        warn!("gamepad {:?} read failed: {}", id, err);
+                        break;
+                    }
+                };
+                if let Some(event) = event.to_gamepad_event(id) {
+                    if !send(event) {
+                        break;
+                    }
+                }
+            }
+            on_disconnect();
+            send(GamepadEvent::Disconnected { id });
+        });
+        self.readers.push(Reader { id, stop, thread });
+        id
+    }
+}
+
+/// State of one gamepad.
+#[derive(Clone, Debug, Default)]
+pub struct Gamepad {
+    pub name: String,
+    held: HashSet<u8>,
+    pressed: HashSet<u8>,
+    released: HashSet<u8>,
+    axes: HashMap<u8, f32>,
+}
+
+impl Gamepad {
+    /// Check if a button is held down.
+    pub fn is_held(&self, button: u8) -> bool {
+        self.held.contains(&button)
+    }
+
+    /// Check if a button was pressed this frame.
+    pub fn was_pressed(&self, button: u8) -> bool {
+        self.pressed.contains(&button)
+    }
+
+    /// Check if a button was released this frame.
+    pub fn was_released(&self, button: u8) -> bool {
+        self.released.contains(&button)
+    }
+
+    /// Get the position of an axis in -1..=1, or 0 if it has not moved.
+    pub fn axis(&self, axis: u8) -> f32 {
+        self.axes.get(&axis).cloned().unwrap_or(0.0)
+    }
+}
+
+/// Tracks buttons and axes of every connected gamepad. Events are passed on
+/// unmodified.
+#[derive(Clone, Debug, Default)]
+pub struct GamepadState {
+    gamepads: HashMap<GamepadId, Gamepad>,
+}
+
+impl GamepadState {
+    pub fn get(&self, id: GamepadId) -> Option<&Gamepad> {
+        self.gamepads.get(&id)
+    }
+
+    /// Iterate over the connected gamepads.
+    pub fn iter(&self) -> impl Iterator<Item = (GamepadId, &Gamepad)> {
+        self.gamepads.iter().map(|(&id, gamepad)| (id, gamepad))
+    }
+}
+
+impl InputHandler for GamepadState {
+    /// Track connection, button and axis events.
+    fn handle_event(&mut self, event: InputEvent) -> Option<InputEvent> {
+        if let InputEvent::Gamepad(ref event) = event {
+            match *event {
+                GamepadEvent::Connected { id, ref name } => {
+                    self.gamepads.insert(id, Gamepad {
+                        name: name.clone(),
+                        ..Gamepad::default()
+                    });
+                }
+                GamepadEvent::Disconnected { id } => {
+                    self.gamepads.remove(&id);
+                }
+                GamepadEvent::Button { id, button, state } => {
+                    let gamepad = self.gamepads.entry(id).or_insert_with(Gamepad::default);
+                    match state {
+                        ElementState::Pressed => {
+                            gamepad.held.insert(button);
+                            gamepad.pressed.insert(button);
+                        }
+                        ElementState::Released => {
+                            gamepad.held.remove(&button);
+                            gamepad.released.insert(button);
+                        }
+                    }
+                }
+                GamepadEvent::Axis { id, axis, value } => {
+                    let gamepad = self.gamepads.entry(id).or_insert_with(Gamepad::default);
+                    gamepad.axes.insert(axis, value);
+                }
+            }
+        }
+        Some(event)
+    }
+
+    /// Clear the per-frame state, keeping held buttons and axis positions.
+    fn reset(&mut self) {
+        for gamepad in self.gamepads.values_mut() {
+            gamepad.pressed.clear();
+            gamepad.released.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::time::Instant;
+
+    fn event(kind: u8, number: u8, value: i16) -> JoystickEvent {
+        JoystickEvent {
+            time: 0,
+            value,
+            kind,
+            number,
+        }
+    }
+
+    /// Poll until the gamepad disconnects, returning every event seen.
+    fn poll_until_disconnected(gamepads: &mut Gamepads, id: GamepadId) -> Vec<InputEvent> {
+        let mut root = RootEventHandler::default();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let disconnected = InputEvent::Gamepad(GamepadEvent::Disconnected { id });
+        while !root.events().contains(&disconnected) {
+            assert!(Instant::now() < deadline, "gamepad never disconnected");
+            gamepads.poll(&mut root);
+            thread::sleep(Duration::from_millis(1));
+        }
+        root.events().to_vec()
+    }
+
+    /// A device which never has anything to read.
+    struct Idle;
+
+    impl Read for Idle {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    #[test]
+    fn joystick_events_round_trip() {
+        let original = JoystickEvent {
+            time: 123_456,
+            value: -300,
+            kind: JoystickEvent::AXIS | JoystickEvent::INIT,
+            number: 7,
+        };
+        assert_eq!(JoystickEvent::from_bytes(original.to_bytes()), original);
+    }
+
+    #[test]
+    fn axis_values_are_normalised() {
+        let id = GamepadId(0);
+        let value = |raw| match event(JoystickEvent::AXIS, 0, raw).to_gamepad_event(id) {
+            Some(GamepadEvent::Axis { value, .. }) => value,
+            other => panic!("expected an axis event, got {:?}", other),
+        };
+        assert_eq!(value(std::i16::MAX), 1.0);
+        assert_eq!(value(std::i16::MIN), -1.0);
+        assert_eq!(value(0), 0.0);
+        assert_eq!(event(0x04, 0, 1).to_gamepad_event(id), None);
+    }
+
+    #[test]
+    fn attached_device_is_polled() {
+        let mut bytes = Vec::new();
+        for joystick_event in &[
+            event(JoystickEvent::BUTTON | JoystickEvent::INIT, 2, 0),
+            event(JoystickEvent::BUTTON, 2, 1),
+            event(JoystickEvent::BUTTON, 2, 0),
+            event(JoystickEvent::AXIS, 1, std::i16::MIN),
+        ] {
+            bytes.extend_from_slice(&joystick_event.to_bytes());
+        }
+
+        let mut gamepads = Gamepads::default();
+        let id = gamepads.attach("synthetic", Cursor::new(bytes));
+        let button = |state| InputEvent::Gamepad(GamepadEvent::Button { id, button: 2, state });
+        assert_eq!(poll_until_disconnected(&mut gamepads, id), vec![
+            InputEvent::Gamepad(GamepadEvent::Connected {
+                id,
+                name: "synthetic".to_owned(),
+            }),
+            button(ElementState::Released),
+            button(ElementState::Pressed),
+            button(ElementState::Released),
+            InputEvent::Gamepad(GamepadEvent::Axis { id, axis: 1, value: -1.0 }),
+            InputEvent::Gamepad(GamepadEvent::Disconnected { id }),
+        ]);
+        assert!(gamepads.readers.is_empty());
+    }
+
+    #[test]
+    fn gamepad_state_tracks_events() {
+        let id = GamepadId(4);
+        let mut state = GamepadState::default();
+        state.handle_event(GamepadEvent::Connected { id, name: "pad".to_owned() }.into());
+        state.handle_event(GamepadEvent::Button { id, button: 1, state: ElementState::Pressed }.into());
+        state.handle_event(GamepadEvent::Axis { id, axis: 0, value: 0.5 }.into());
+        let gamepad = state.get(id).unwrap();
+        assert_eq!(gamepad.name, "pad");
+        assert!(gamepad.is_held(1) && gamepad.was_pressed(1));
+        assert_eq!(gamepad.axis(0), 0.5);
+
+        state.reset();
+        assert!(!state.get(id).unwrap().was_pressed(1));
+        state.handle_event(GamepadEvent::Disconnected { id }.into());
+        assert!(state.get(id).is_none());
+    }
+
+    #[test]
+    fn idle_readers_can_be_stopped() {
+        let mut gamepads = Gamepads::default();
+        let first = gamepads.attach("first", Idle);
+        gamepads.attach("second", Idle);
+
+        gamepads.disconnect(first);
+        assert_eq!(gamepads.readers.len(), 1);
+        gamepads.stop();
+        assert!(gamepads.readers.is_empty());
+
+        let mut root = RootEventHandler::default();
+        gamepads.poll(&mut root);
+        let disconnects = root
+            .events()
+            .iter()
+            .filter(|event| match event {
+                InputEvent::Gamepad(GamepadEvent::Disconnected { .. }) => true,
+                _ => false,
+            })
+            .count();
+        assert_eq!(disconnects, 2);
+    }
+}
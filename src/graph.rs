@@ -0,0 +1,630 @@
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
+use crate::context::Context;
+use crate::hal_prelude::*;
+use crate::swapchain::AttachmentImage;
+use crate::texture::TextureError;
+
+/// Handle to an image in a `RenderGraph`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ResourceId(usize);
+
+/// Handle to a pass in a `RenderGraph`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PassId(usize);
+
+/// A colour image allocated by the graph at the size it is executed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageDesc {
+    pub format: Format,
+    pub samples: u8,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GraphError {
+    /// The passes, by name, that depend on each other in a cycle.
+    Cycle(Vec<String>),
+    /// A resource written by more than one pass.
+    MultipleWriters(String),
+    /// A pass sampling an image it also draws to.
+    ReadWrite { pass: String, resource: String },
+    /// A pass reading a transient image that no pass writes.
+    UnwrittenRead { pass: String, resource: String },
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for GraphError {}
+
+#[derive(Clone, Debug)]
+enum ResourceKind {
+    Transient(ImageDesc),
+    /// An image owned outside the graph, in `initial` before the first pass
+    /// and left in `final_layout`, if given, after the last.
+    Imported { initial: Layout, final_layout: Option<Layout> },
+}
+
+#[derive(Clone, Debug)]
+struct Resource {
+    name: String,
+    kind: ResourceKind,
+}
+
+#[derive(Clone, Debug)]
+struct Pass {
+    name: String,
+    reads: Vec<ResourceId>,
+    writes: Vec<ResourceId>,
+}
+
+/// How a pass uses an image: sampled by its fragment shader, or drawn to as
+/// a colour attachment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Usage {
+    Read,
+    Write,
+}
+
+impl Usage {
+    fn state(self) -> img::State {
+        match self {
+            Usage::Read => (Access::SHADER_READ, Layout::ShaderReadOnlyOptimal),
+            Usage::Write => (Access::COLOR_ATTACHMENT_WRITE, Layout::ColorAttachmentOptimal),
+        }
+    }
+
+    fn stage(self) -> PipelineStage {
+        match self {
+            Usage::Read => PipelineStage::FRAGMENT_SHADER,
+            Usage::Write => PipelineStage::COLOR_ATTACHMENT_OUTPUT,
+        }
+    }
+}
+
+/// A layout transition and memory dependency on one image, recorded as a
+/// pipeline barrier.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transition {
+    pub resource: ResourceId,
+    pub stages: Range<PipelineStage>,
+    pub states: Range<img::State>,
+}
+
+/// The passes of a frame and the images they read and write. Compiling the
+/// graph orders the passes so that each runs after the writers of what it
+/// reads, drops passes that do not lead to an output, packs transient images
+/// into as few physical images as their lifetimes allow, and works out the
+/// barriers and layout transitions between passes.
+///
+/// Render passes run by the graph should leave their colour target in
+/// `ColorAttachmentOptimal`; the graph moves it into
+/// `ShaderReadOnlyOptimal` for the passes that sample it.
+#[derive(Clone, Debug, Default)]
+pub struct RenderGraph {
+    resources: Vec<Resource>,
+    passes: Vec<Pass>,
+}
+
+impl RenderGraph {
+    pub fn new() -> Self {
+        RenderGraph::default()
+    }
+
+    /// Add an image allocated by the graph, whose contents do not outlive
+    /// the frame.
+    pub fn create_image(&mut self, name: &str, desc: ImageDesc) -> ResourceId {
+        self.add_resource(name, ResourceKind::Transient(desc))
+    }
+
+    /// Add an image owned outside the graph, such as a swapchain image. It is
+    /// in `initial` when the graph starts, and is transitioned to
+    /// `final_layout` after the last pass if one is given.
+    pub fn import_image(&mut self, name: &str, initial: Layout, final_layout: Option<Layout>) -> ResourceId {
+        self.add_resource(name, ResourceKind::Imported { initial, final_layout })
+    }
+
+    /// Add a pass sampling `reads` and drawing to `writes`.
+    pub fn add_pass(&mut self, name: &str, reads: &[ResourceId], writes: &[ResourceId]) -> PassId {
+        self.passes.push(Pass {
+            name: name.to_owned(),
+            reads: reads.to_vec(),
+            writes: writes.to_vec(),
+        });
+        PassId(self.passes.len() - 1)
+    }
+
+    fn add_resource(&mut self, name: &str, kind: ResourceKind) -> ResourceId {
+        self.resources.push(Resource {
+            name: name.to_owned(),
+            kind,
+        });
+        ResourceId(self.resources.len() - 1)
+    }
+
+    /// Plan the passes needed to write `outputs`.
+    pub fn compile(&self, outputs: &[ResourceId]) -> Result<GraphPlan, GraphError> {
+        let writers = self.writers()?;
+
+        // walk back from the outputs to find the passes that matter
+        let mut needed = vec![false; self.passes.len()];
+        let mut stack = outputs.iter().filter_map(|resource| writers[resource.0]).collect::<Vec<_>>();
+        while let Some(pass) = stack.pop() {
+            if needed[pass] {
+                continue;
+            }
+            needed[pass] = true;
+            for resource in &self.passes[pass].reads {
+                match writers[resource.0] {
+                    Some(writer) => stack.push(writer),
+                    None if self.is_transient(*resource) => {
+                        return Err(GraphError::UnwrittenRead {
+                            pass: self.passes[pass].name.clone(),
+                            resource: self.resources[resource.0].name.clone(),
+                        });
+                    }
+                    None => (),
+                }
+            }
+        }
+
+        let order = self.order(&needed, &writers)?;
+        let (slots, slot_descs) = self.allocate(&order);
+        let (before, after) = self.transitions(&order, &slots);
+        Ok(GraphPlan {
+            order: order.into_iter().map(PassId).collect(),
+            before,
+            after,
+            slots,
+            slot_descs,
+        })
+    }
+
+    /// Find the pass writing each resource, checking that no pass reads
+    /// what it writes.
+    fn writers(&self) -> Result<Vec<Option<usize>>, GraphError> {
+        let mut writers = vec![None; self.resources.len()];
+        for (index, pass) in self.passes.iter().enumerate() {
+            for resource in &pass.writes {
+                if pass.reads.contains(resource) {
+                    return Err(GraphError::ReadWrite {
+                        pass: pass.name.clone(),
+                        resource: self.resources[resource.0].name.clone(),
+                    });
+                }
+                if writers[resource.0].is_some() {
+                    return Err(GraphError::MultipleWriters(self.resources[resource.0].name.clone()));
+                }
+                writers[resource.0] = Some(index);
+            }
+        }
+        Ok(writers)
+    }
+
+    /// Sort the needed passes so that writers come before readers, keeping
+    /// the order they were added in where it does not matter.
+    fn order(&self, needed: &[bool], writers: &[Option<usize>]) -> Result<Vec<usize>, GraphError> {
+        let dependencies = self
+            .passes
+            .iter()
+            .map(|pass| pass.reads.iter().filter_map(|resource| writers[resource.0]).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let mut done = vec![false; self.passes.len()];
+        let mut order = Vec::new();
+        let count = needed.iter().filter(|&&needed| needed).count();
+        while order.len() < count {
+            let ready = (0..self.passes.len())
+                .find(|&pass| needed[pass] && !done[pass] && dependencies[pass].iter().all(|&dep| done[dep]));
+            match ready {
+                Some(pass) => {
+                    done[pass] = true;
+                    order.push(pass);
+                }
+                None => {
+                    let stuck = (0..self.passes.len())
+                        .filter(|&pass| needed[pass] && !done[pass])
+                        .map(|pass| self.passes[pass].name.clone())
+                        .collect();
+                    return Err(GraphError::Cycle(stuck));
+                }
+            }
+        }
+        Ok(order)
+    }
+
+    /// Assign each transient image a physical slot, sharing slots between
+    /// images of the same description whose lifetimes do not overlap.
+    fn allocate(&self, order: &[usize]) -> (Vec<Option<usize>>, Vec<ImageDesc>) {
+        let mut lifetimes = vec![None; self.resources.len()];
+        for (position, &pass) in order.iter().enumerate() {
+            let pass = &self.passes[pass];
+            for resource in pass.reads.iter().chain(&pass.writes) {
+                let lifetime = lifetimes[resource.0].get_or_insert(position..position);
+                lifetime.end = position;
+            }
+        }
+
+        let mut slots = vec![None; self.resources.len()];
+        let mut slot_descs = Vec::new();
+        // the position of each slot's last use
+        let mut slot_ends: Vec<usize> = Vec::new();
+        let mut transients = (0..self.resources.len())
+            .filter_map(|index| match (&self.resources[index].kind, &lifetimes[index]) {
+                (ResourceKind::Transient(desc), Some(lifetime)) => Some((index, *desc, lifetime.clone())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        transients.sort_by_key(|(_, _, lifetime)| lifetime.start);
+        for (index, desc, lifetime) in transients {
+            let free = (0..slot_descs.len()).find(|&slot| slot_descs[slot] == desc && slot_ends[slot] < lifetime.start);
+            let slot = match free {
+                Some(slot) => slot,
+                None => {
+                    slot_descs.push(desc);
+                    slot_ends.push(0);
+                    slot_descs.len() - 1
+                }
+            };
+            slot_ends[slot] = lifetime.end;
+            slots[index] = Some(slot);
+        }
+        (slots, slot_descs)
+    }
+
+    /// Work out the transitions before each pass, and those after the last
+    /// to the imports' final layouts.
+    fn transitions(&self, order: &[usize], slots: &[Option<usize>]) -> (Vec<Vec<Transition>>, Vec<Transition>) {
+        // the state of each physical image, which aliased transients share
+        let mut states = self
+            .resources
+            .iter()
+            .map(|resource| match resource.kind {
+                ResourceKind::Imported { initial, .. } => ((Access::empty(), initial), PipelineStage::TOP_OF_PIPE),
+                ResourceKind::Transient(_) => ((Access::empty(), Layout::Undefined), PipelineStage::TOP_OF_PIPE),
+            })
+            .collect::<Vec<_>>();
+        let physical = |resource: ResourceId| match slots[resource.0] {
+            // transients are tracked in the entry of the first resource in their slot
+            Some(slot) => slots.iter().position(|&other| other == Some(slot)).unwrap(),
+            None => resource.0,
+        };
+        let mut started = vec![false; self.resources.len()];
+
+        let mut before = Vec::with_capacity(order.len());
+        for &pass in order {
+            let pass = &self.passes[pass];
+            let uses = pass
+                .reads
+                .iter()
+                .map(|&resource| (resource, Usage::Read))
+                .chain(pass.writes.iter().map(|&resource| (resource, Usage::Write)));
+            let mut transitions = Vec::new();
+            for (resource, usage) in uses {
+                let entry = &mut states[physical(resource)];
+                let ((access, mut layout), stage) = *entry;
+                // a transient's first use discards what an aliased image left
+                if !started[resource.0] && self.is_transient(resource) {
+                    layout = Layout::Undefined;
+                }
+                started[resource.0] = true;
+                let to = usage.state();
+                if (access, layout) != to || usage == Usage::Write {
+                    transitions.push(Transition {
+                        resource,
+                        stages: stage..usage.stage(),
+                        states: (access, layout)..to,
+                    });
+                }
+                *entry = (to, usage.stage());
+            }
+            before.push(transitions);
+        }
+
+        let after = self
+            .resources
+            .iter()
+            .enumerate()
+            .filter_map(|(index, resource)| match resource.kind {
+                ResourceKind::Imported { final_layout: Some(final_layout), .. } => {
+                    let ((access, layout), stage) = states[index];
+                    if layout == final_layout {
+                        return None;
+                    }
+                    Some(Transition {
+                        resource: ResourceId(index),
+                        stages: stage..PipelineStage::BOTTOM_OF_PIPE,
+                        states: (access, layout)..(Access::empty(), final_layout),
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        (before, after)
+    }
+
+    fn is_transient(&self, resource: ResourceId) -> bool {
+        match self.resources[resource.0].kind {
+            ResourceKind::Transient(_) => true,
+            ResourceKind::Imported { .. } => false,
+        }
+    }
+}
+
+/// The result of compiling a `RenderGraph`: the passes to run in order, the
+/// transitions to record before each, and the physical images to allocate.
+#[derive(Clone, Debug)]
+pub struct GraphPlan {
+    order: Vec<PassId>,
+    before: Vec<Vec<Transition>>,
+    after: Vec<Transition>,
+    slots: Vec<Option<usize>>,
+    slot_descs: Vec<ImageDesc>,
+}
+
+impl GraphPlan {
+    /// Iterate over the passes in the order they must run, with the
+    /// transitions to record before each.
+    pub fn passes(&self) -> impl Iterator<Item = (PassId, &[Transition])> {
+        self.order.iter().cloned().zip(self.before.iter().map(Vec::as_slice))
+    }
+
+    /// Get the transitions to record after the last pass.
+    pub fn final_transitions(&self) -> &[Transition] {
+        &self.after
+    }
+
+    /// Get the physical image backing a transient resource.
+    pub fn slot(&self, resource: ResourceId) -> Option<usize> {
+        self.slots[resource.0]
+    }
+
+    /// Get the descriptions of the physical images to allocate.
+    pub fn slot_descs(&self) -> &[ImageDesc] {
+        &self.slot_descs
+    }
+}
+
+/// The physical images of a `GraphPlan`, at one size.
+pub struct GraphImages<B: gfx_hal::Backend> {
+    images: Vec<AttachmentImage<B>>,
+}
+
+impl<B: gfx_hal::Backend> GraphImages<B> {
+    /// Allocate the plan's images. If any cannot be created, those already
+    /// created are destroyed.
+    pub fn new(context: &Context<B>, plan: &GraphPlan, extent: Extent) -> Result<Self, TextureError> {
+        let usage = img::Usage::COLOR_ATTACHMENT | img::Usage::SAMPLED;
+        let mut images = GraphImages { images: Vec::new() };
+        for desc in plan.slot_descs() {
+            match AttachmentImage::new(context, desc.format, extent, desc.samples, usage) {
+                Ok(image) => images.images.push(image),
+                Err(err) => {
+                    images.destroy(&context.device);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(images)
+    }
+
+    /// Get the image backing a transient resource.
+    pub fn image(&self, plan: &GraphPlan, resource: ResourceId) -> Option<&B::Image> {
+        plan.slot(resource).map(|slot| self.images[slot].image())
+    }
+
+    /// Get the view of the image backing a transient resource.
+    pub fn view(&self, plan: &GraphPlan, resource: ResourceId) -> Option<&B::ImageView> {
+        plan.slot(resource).map(|slot| self.images[slot].view())
+    }
+
+    pub fn destroy(self, device: &B::Device) {
+        for image in self.images {
+            image.destroy(device);
+        }
+    }
+}
+
+/// Record transitions as pipeline barriers. `image` looks up the image of
+/// each resource; those without one, such as a swapchain image whose render
+/// pass makes its own transitions, are skipped.
+pub unsafe fn record_transitions<'i, B, C, S, F>(
+    command_buffer: &mut CommandBuffer<B, C, S>,
+    transitions: &[Transition],
+    image: F,
+) where
+    B: gfx_hal::Backend,
+    C: gfx_hal::queue::Supports<gfx_hal::queue::capability::Graphics>
+        + gfx_hal::queue::Supports<gfx_hal::queue::capability::Transfer>,
+    S: gfx_hal::command::Shot,
+    F: Fn(ResourceId) -> Option<&'i B::Image>,
+{
+    for transition in transitions {
+        let target = match image(transition.resource) {
+            Some(target) => target,
+            None => continue,
+        };
+        let barrier = Barrier::Image {
+            states: transition.states.clone(),
+            target,
+            families: None,
+            range: SubresourceRange {
+                aspects: Aspects::COLOR,
+                levels: 0..1,
+                layers: 0..1,
+            },
+        };
+        command_buffer.pipeline_barrier(transition.stages.clone(), Dependencies::empty(), &[barrier]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COLOUR: ImageDesc = ImageDesc {
+        format: Format::Rgba8Srgb,
+        samples: 1,
+    };
+    const HDR: ImageDesc = ImageDesc {
+        format: Format::Rgba16Float,
+        samples: 1,
+    };
+
+    const WRITE: img::State = (Access::COLOR_ATTACHMENT_WRITE, Layout::ColorAttachmentOptimal);
+    const READ: img::State = (Access::SHADER_READ, Layout::ShaderReadOnlyOptimal);
+
+    fn discarded() -> img::State {
+        (Access::empty(), Layout::Undefined)
+    }
+
+    fn order(plan: &GraphPlan) -> Vec<PassId> {
+        plan.passes().map(|(pass, _)| pass).collect()
+    }
+
+    fn swapchain(graph: &mut RenderGraph) -> ResourceId {
+        graph.import_image("swapchain", Layout::Undefined, Some(Layout::Present))
+    }
+
+    #[test]
+    fn passes_run_after_their_inputs_in_insertion_order() {
+        let mut graph = RenderGraph::new();
+        let (scene, ui, out) = (graph.create_image("scene", COLOUR), graph.create_image("ui", COLOUR), swapchain(&mut graph));
+        let compose = graph.add_pass("compose", &[scene, ui], &[out]);
+        let draw_ui = graph.add_pass("ui", &[], &[ui]);
+        let draw_scene = graph.add_pass("scene", &[], &[scene]);
+
+        let plan = graph.compile(&[out]).unwrap();
+        assert_eq!(order(&plan), vec![draw_ui, draw_scene, compose]);
+    }
+
+    #[test]
+    fn passes_not_leading_to_an_output_are_culled() {
+        let mut graph = RenderGraph::new();
+        let (scene, debug, out) = (graph.create_image("scene", COLOUR), graph.create_image("debug", COLOUR), swapchain(&mut graph));
+        let draw_scene = graph.add_pass("scene", &[], &[scene]);
+        graph.add_pass("debug", &[scene], &[debug]);
+        let present = graph.add_pass("present", &[scene], &[out]);
+
+        let plan = graph.compile(&[out]).unwrap();
+        assert_eq!(order(&plan), vec![draw_scene, present]);
+        assert_eq!(plan.slot(debug), None);
+        assert_eq!(plan.slot_descs().len(), 1);
+    }
+
+    #[test]
+    fn cycles_are_errors() {
+        let mut graph = RenderGraph::new();
+        let (a, b, out) = (graph.create_image("a", COLOUR), graph.create_image("b", COLOUR), swapchain(&mut graph));
+        graph.add_pass("first", &[b], &[a]);
+        graph.add_pass("second", &[a], &[b]);
+        graph.add_pass("present", &[a], &[out]);
+
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        assert_eq!(graph.compile(&[out]).unwrap_err(), GraphError::Cycle(names(&["first", "second", "present"])));
+    }
+
+    #[test]
+    fn resources_with_several_writers_are_errors() {
+        let mut graph = RenderGraph::new();
+        let scene = graph.create_image("scene", COLOUR);
+        graph.add_pass("first", &[], &[scene]);
+        graph.add_pass("second", &[], &[scene]);
+        assert_eq!(graph.compile(&[scene]).unwrap_err(), GraphError::MultipleWriters("scene".to_owned()));
+    }
+
+    #[test]
+    fn reading_an_unwritten_transient_is_an_error() {
+        let mut graph = RenderGraph::new();
+        let (scene, out) = (graph.create_image("scene", COLOUR), swapchain(&mut graph));
+        graph.add_pass("present", &[scene], &[out]);
+        assert_eq!(graph.compile(&[out]).unwrap_err(), GraphError::UnwrittenRead {
+            pass: "present".to_owned(),
+            resource: "scene".to_owned(),
+        });
+
+        // imported images hold whatever was drawn to them outside the graph
+        let mut graph = RenderGraph::new();
+        let (history, out) = (graph.import_image("history", Layout::ShaderReadOnlyOptimal, None), swapchain(&mut graph));
+        graph.add_pass("present", &[history], &[out]);
+        assert!(graph.compile(&[out]).is_ok());
+    }
+
+    #[test]
+    fn passes_reading_what_they_write_are_errors() {
+        let mut graph = RenderGraph::new();
+        let scene = graph.create_image("scene", COLOUR);
+        graph.add_pass("blur", &[scene], &[scene]);
+        assert_eq!(graph.compile(&[scene]).unwrap_err(), GraphError::ReadWrite {
+            pass: "blur".to_owned(),
+            resource: "scene".to_owned(),
+        });
+    }
+
+    #[test]
+    fn only_transients_with_disjoint_lifetimes_share_a_slot() {
+        let mut graph = RenderGraph::new();
+        let first = graph.create_image("first", COLOUR);
+        let second = graph.create_image("second", COLOUR);
+        let third = graph.create_image("third", COLOUR);
+        let fourth = graph.create_image("fourth", HDR);
+        let out = swapchain(&mut graph);
+        graph.add_pass("a", &[], &[first]);
+        graph.add_pass("b", &[first], &[second]);
+        graph.add_pass("c", &[second], &[third]);
+        graph.add_pass("d", &[third], &[fourth]);
+        graph.add_pass("e", &[fourth], &[out]);
+
+        let plan = graph.compile(&[out]).unwrap();
+        // first and third are never alive at once; second overlaps both
+        assert_eq!(plan.slot(first), plan.slot(third));
+        assert_ne!(plan.slot(first), plan.slot(second));
+        // fourth could take second's slot but differs in format
+        assert_ne!(plan.slot(fourth), plan.slot(second));
+        assert_eq!(plan.slot(out), None);
+        assert_eq!(plan.slot_descs(), &[COLOUR, COLOUR, HDR]);
+    }
+
+    #[test]
+    fn post_processing_chain_transitions() {
+        let mut graph = RenderGraph::new();
+        let scene = graph.create_image("scene", COLOUR);
+        let bloom = graph.create_image("bloom", COLOUR);
+        let out = swapchain(&mut graph);
+        graph.add_pass("scene", &[], &[scene]);
+        graph.add_pass("bloom", &[scene], &[bloom]);
+        graph.add_pass("grade", &[bloom], &[out]);
+
+        let plan = graph.compile(&[out]).unwrap();
+        let transition = |resource, stages: Range<PipelineStage>, states: Range<img::State>| Transition {
+            resource,
+            stages,
+            states,
+        };
+        let (top, colour, fragment) = (
+            PipelineStage::TOP_OF_PIPE,
+            PipelineStage::COLOR_ATTACHMENT_OUTPUT,
+            PipelineStage::FRAGMENT_SHADER,
+        );
+        let before = plan.passes().map(|(_, transitions)| transitions.to_vec()).collect::<Vec<_>>();
+        assert_eq!(before, vec![
+            vec![transition(scene, top..colour, discarded()..WRITE)],
+            vec![
+                transition(scene, colour..fragment, WRITE..READ),
+                transition(bloom, top..colour, discarded()..WRITE),
+            ],
+            vec![
+                transition(bloom, colour..fragment, WRITE..READ),
+                transition(out, top..colour, discarded()..WRITE),
+            ],
+        ]);
+        assert_eq!(plan.final_transitions(), &[transition(
+            out,
+            colour..PipelineStage::BOTTOM_OF_PIPE,
+            WRITE..(Access::empty(), Layout::Present),
+        )]);
+    }
+}
@@ -1,5 +1,6 @@
 pub use gfx_hal::{
     Backbuffer,
+    CommandPool,
     DescriptorPool,
     Device,
     FrameSync,
@@ -15,9 +16,11 @@ pub use gfx_hal::{
     
     adapter::MemoryTypeId,
     buffer,
-    command::{BufferImageCopy, ClearColor, ClearDepthStencil, ClearValue},
+    command::{
+        BufferImageCopy, ClearColor, ClearDepthStencil, ClearValue, CommandBuffer, ImageBlit, OneShot,
+    },
     device::ShaderError,
-    format::{Aspects, ChannelType, Format, Swizzle},
+    format::{Aspects, ChannelType, Format, ImageFeature, Swizzle},
     image::{
         self as img, Access, Extent, Filter, Layout, Offset, SubresourceLayers, SubresourceRange,
         ViewCapabilities, ViewError, ViewKind, WrapMode,
@@ -31,7 +34,7 @@ pub use gfx_hal::{
     pso::{
         AttributeDesc, BlendState, ColorBlendDesc, ColorMask, Comparison, DepthStencilDesc,
         DepthTest, Descriptor, DescriptorRangeDesc, DescriptorSetLayoutBinding, DescriptorSetWrite,
-        DescriptorType, Element, EntryPoint, GraphicsPipelineDesc, GraphicsShaderSet,
+        DescriptorType, Element, EntryPoint, GraphicsPipelineDesc, GraphicsShaderSet, Multisampling,
         PipelineStage, Rasterizer, Rect, ShaderStageFlags, StencilTest, VertexBufferDesc, Viewport,
     },
     queue::Submission,
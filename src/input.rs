@@ -1,7 +1,110 @@
-#[cfg(not(feature = "gl"))]
-use gfx_backend::winit::{Event, KeyboardInput, VirtualKeyCode, WindowEvent};
-#[cfg(feature = "gl")]
-use gfx_backend::glutin::{Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+use std::cell::RefCell;
+use std::fmt;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::winit::{
+    dpi::{LogicalPosition, LogicalSize},
+    ElementState, Event, KeyboardInput, ModifiersState, MouseButton, MouseScrollDelta, TouchPhase, WindowEvent,
+};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::gamepad::GamepadEvent;
+use crate::layer::LayerResized;
+
+use log::debug;
+
+/// A winit `WindowEvent` without its window and device ids, so that it can
+/// be recorded and replayed without a window.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum WindowInput {
+    Resized(LogicalSize),
+    Moved(LogicalPosition),
+    CloseRequested,
+    Destroyed,
+    DroppedFile(PathBuf),
+    HoveredFile(PathBuf),
+    HoveredFileCancelled,
+    ReceivedCharacter(char),
+    Focused(bool),
+    KeyboardInput { input: KeyboardInput },
+    CursorMoved { position: LogicalPosition, modifiers: ModifiersState },
+    CursorEntered,
+    CursorLeft,
+    MouseWheel { delta: MouseScrollDelta, phase: TouchPhase, modifiers: ModifiersState },
+    MouseInput { state: ElementState, button: MouseButton, modifiers: ModifiersState },
+    TouchpadPressure { pressure: f32, stage: i64 },
+    AxisMotion { axis: u32, value: f64 },
+    Refresh,
+    Touch { phase: TouchPhase, location: LogicalPosition, id: u64 },
+    HiDpiFactorChanged(f64),
+}
+
+impl From<WindowEvent> for WindowInput {
+    fn from(event: WindowEvent) -> Self {
+        match event {
+            WindowEvent::Resized(size) => WindowInput::Resized(size),
+            WindowEvent::Moved(position) => WindowInput::Moved(position),
+            WindowEvent::CloseRequested => WindowInput::CloseRequested,
+            WindowEvent::Destroyed => WindowInput::Destroyed,
+            WindowEvent::DroppedFile(path) => WindowInput::DroppedFile(path),
+            WindowEvent::HoveredFile(path) => WindowInput::HoveredFile(path),
+            WindowEvent::HoveredFileCancelled => WindowInput::HoveredFileCancelled,
+            WindowEvent::ReceivedCharacter(ch) => WindowInput::ReceivedCharacter(ch),
+            WindowEvent::Focused(focused) => WindowInput::Focused(focused),
+            WindowEvent::KeyboardInput { input, .. } => WindowInput::KeyboardInput { input },
+            WindowEvent::CursorMoved { position, modifiers, .. } => WindowInput::CursorMoved { position, modifiers },
+            WindowEvent::CursorEntered { .. } => WindowInput::CursorEntered,
+            WindowEvent::CursorLeft { .. } => WindowInput::CursorLeft,
+            WindowEvent::MouseWheel {
+                delta,
+                phase,
+                modifiers,
+                ..
+            } => WindowInput::MouseWheel { delta, phase, modifiers },
+            WindowEvent::MouseInput {
+                state,
+                button,
+                modifiers,
+                ..
+            } => WindowInput::MouseInput { state, button, modifiers },
+            WindowEvent::TouchpadPressure { pressure, stage, .. } => WindowInput::TouchpadPressure { pressure, stage },
+            WindowEvent::AxisMotion { axis, value, .. } => WindowInput::AxisMotion { axis, value },
+            WindowEvent::Refresh => WindowInput::Refresh,
+            WindowEvent::Touch(touch) => WindowInput::Touch {
+                phase: touch.phase,
+                location: touch.location,
+                id: touch.id,
+            },
+            WindowEvent::HiDpiFactorChanged(factor) => WindowInput::HiDpiFactorChanged(factor),
+        }
+    }
+}
+
+/// An event passed to `InputHandler`s: either input from the window, or from
+/// a source winit does not cover.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum InputEvent {
+    Window(WindowInput),
+    Gamepad(GamepadEvent),
+}
+
+impl InputEvent {
+    /// Convert a winit event. Device events, which duplicate window events
+    /// in raw form, and events loop notifications are dropped.
+    pub fn from_winit(event: Event) -> Option<Self> {
+        match event {
+            Event::WindowEvent { event, .. } => Some(InputEvent::Window(event.into())),
+            _ => None,
+        }
+    }
+}
+
+impl From<GamepadEvent> for InputEvent {
+    fn from(event: GamepadEvent) -> Self {
+        InputEvent::Gamepad(event)
+    }
+}
 
 /// The InputHandler trait is used to react to, process and optionally modify or
 /// cancel further propagation of incoming events.
@@ -13,19 +116,125 @@ pub trait InputHandler {
     /// - returned in some modified state
     /// - not returned at all
     ///
-    fn handle_event(&mut self, event: Event) -> Option<Event> {
+    fn handle_event(&mut self, event: InputEvent) -> Option<InputEvent> {
         Some(event)
     }
+
+    /// Clear any per-frame state, such as keys pressed this frame. Called at
+    /// the start of each frame for handlers in a `RootEventHandler` chain.
+    fn reset(&mut self) {}
+
+    /// Advance any time-based state, such as software key repeat, by `dt`
+    /// seconds. Called by the `Runner` before each fixed update for handlers
+    /// in its chains.
+    fn update(&mut self, _dt: f32) {}
 }
 
-/// Root handler for events. Stores `Event`s coming from winit::EventLoop in a
-/// cache for propagation to `InputHandler`s via the `sync` method.
+/// Shared handlers, so that the main loop can read a handler's state while a
+/// `RootEventHandler` owns it as part of its chain.
+impl<H: InputHandler> InputHandler for Rc<RefCell<H>> {
+    fn handle_event(&mut self, event: InputEvent) -> Option<InputEvent> {
+        self.borrow_mut().handle_event(event)
+    }
+
+    fn reset(&mut self) {
+        self.borrow_mut().reset()
+    }
+
+    fn update(&mut self, dt: f32) {
+        self.borrow_mut().update(dt)
+    }
+}
+
+/// Handle to an `InputHandler` in a `RootEventHandler` chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HandlerId(usize);
+
+struct HandlerEntry {
+    id: HandlerId,
+    priority: i32,
+    handler: Box<dyn InputHandler>,
+}
+
+impl fmt::Debug for HandlerEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HandlerEntry")
+            .field("id", &self.id)
+            .field("priority", &self.priority)
+            .finish()
+    }
+}
+
+/// Root handler for events. Stores `Event`s coming from winit::EventLoop, and
+/// events from other input sources such as gamepads or a replay, in a cache
+/// for propagation to `InputHandler`s, either through its own chain of
+/// handlers via `dispatch` or to a single handler via `sync`.
 #[derive(Debug, Default)]
 pub struct RootEventHandler {
-    events: Vec<Event>,
+    events: Vec<InputEvent>,
+    /// Handlers in dispatch order: highest priority first, and most recently
+    /// pushed first among equal priorities.
+    handlers: Vec<HandlerEntry>,
+    next_id: usize,
 }
 
 impl RootEventHandler {
+    /// Add a handler to the chain. Handlers with a higher priority see
+    /// events first, so that e.g. a modal dialog can consume all input by
+    /// pushing a handler with a high priority which returns None.
+    pub fn push_handler<H: InputHandler + 'static>(&mut self, priority: i32, handler: H) -> HandlerId {
+        let id = HandlerId(self.next_id);
+        self.next_id += 1;
+        let index = self
+            .handlers
+            .iter()
+            .position(|entry| entry.priority <= priority)
+            .unwrap_or_else(|| self.handlers.len());
+        self.handlers.insert(index, HandlerEntry {
+            id,
+            priority,
+            handler: Box::new(handler),
+        });
+        id
+    }
+
+    /// Remove the most recently pushed handler from the chain.
+    pub fn pop_handler(&mut self) -> Option<Box<dyn InputHandler>> {
+        let newest = self.handlers.iter().map(|entry| entry.id).max_by_key(|id| id.0)?;
+        self.remove_handler(newest)
+    }
+
+    /// Remove a handler from the chain.
+    pub fn remove_handler(&mut self, id: HandlerId) -> Option<Box<dyn InputHandler>> {
+        let index = self.handlers.iter().position(|entry| entry.id == id)?;
+        Some(self.handlers.remove(index).handler)
+    }
+
+    /// Get the number of handlers in the chain.
+    pub fn handler_count(&self) -> usize {
+        self.handlers.len()
+    }
+
+    /// Pass each Event in the cache along the chain of handlers, stopping
+    /// once a handler consumes it. Events that no handler consumes are left
+    /// in the cache.
+    pub fn dispatch(&mut self) {
+        let events = std::mem::replace(&mut self.events, vec![]);
+        let handlers = &mut self.handlers;
+        self.events = events
+            .into_iter()
+            .filter_map(|event| {
+                handlers.iter_mut().try_fold(event, |event, entry| {
+                    let result = entry.handler.handle_event(event);
+                    if cfg!(debug_assertions) && result.is_none() {
+                        debug!("event consumed by handler {:?} (priority {})", entry.id, entry.priority);
+                    }
+                    result
+                })
+            })
+            .collect::<Vec<_>>();
+    }
+
     /// Pass each Event in the cache to an InputHandler, saving the return value
     /// from each invocation of handle_event as the contents of a new cache.
     pub fn sync<R: InputHandler>(&mut self, receiver: &mut R) {
@@ -36,14 +245,49 @@ impl RootEventHandler {
             .collect::<Vec<_>>();
     }
 
-    /// Empty the Event cache.
+    /// Empty the Event cache and reset the per-frame state of the handlers
+    /// in the chain.
     pub fn reset(&mut self) {
+        self.clear_events();
+        self.reset_handlers();
+    }
+
+    /// Empty the Event cache, keeping the state of the handlers.
+    pub fn clear_events(&mut self) {
         self.events.clear();
     }
 
-    /// Store an incoming Event in the cache.
+    /// Reset the per-frame state of the handlers in the chain, such as keys
+    /// pressed since the last reset.
+    pub fn reset_handlers(&mut self) {
+        for entry in &mut self.handlers {
+            entry.handler.reset();
+        }
+    }
+
+    /// Advance the time-based state of the handlers in the chain by `dt`
+    /// seconds.
+    pub fn update_handlers(&mut self, dt: f32) {
+        for entry in &mut self.handlers {
+            entry.handler.update(dt);
+        }
+    }
+
+    /// Get the events in the cache, in the order they arrived.
+    pub fn events(&self) -> &[InputEvent] {
+        &self.events
+    }
+
+    /// Store an incoming winit Event in the cache.
     pub fn handle_event(&mut self, event: Event) {
-        self.events.push(event);
+        if let Some(event) = InputEvent::from_winit(event) {
+            self.events.push(event);
+        }
+    }
+
+    /// Store an event from another input source in the cache.
+    pub fn push<E: Into<InputEvent>>(&mut self, event: E) {
+        self.events.push(event.into());
     }
 }
 
@@ -52,6 +296,8 @@ impl RootEventHandler {
 pub struct Blackboard {
     pub should_quit: bool,
     pub should_rebuild_swapchain: bool,
+    pub should_redraw: bool,
+    pub resized_layers: Vec<LayerResized>,
 }
 
 impl Default for Blackboard {
@@ -59,38 +305,108 @@ impl Default for Blackboard {
         Blackboard {
             should_quit: false,
             should_rebuild_swapchain: false,
+            should_redraw: false,
+            resized_layers: Vec::new(),
         }
     }
 }
 
 impl Blackboard {
-    /// Reset the flags to false.
-    pub fn reset(&mut self) {
-        self.should_quit = false;
-        self.should_rebuild_swapchain = false;
+    /// Check a frame's events for window resize, HiDPI factor change, redraw
+    /// and window close events. The runner calls this before dispatching the
+    /// events along its chain, so that a handler consuming all input, such
+    /// as a modal dialog, cannot swallow window management.
+    pub fn observe(&mut self, events: &[InputEvent]) {
+        for event in events {
+            self.note(event);
+        }
     }
-}
 
-impl InputHandler for Blackboard {
-    /// Check for `Esc`, window resize, and window close events.
-    fn handle_event(&mut self, event: Event) -> Option<Event> {
-        if let Event::WindowEvent { event, .. } = event {
+    fn note(&mut self, event: &InputEvent) {
+        if let InputEvent::Window(event) = event {
             match event {
-                WindowEvent::CloseRequested => self.should_quit = true,
-                WindowEvent::KeyboardInput {
-                    input:
-                        KeyboardInput {
-                            virtual_keycode: Some(VirtualKeyCode::Escape),
-                            ..
-                        },
-                    ..
-                } => self.should_quit = true,
-                WindowEvent::Resized(_) => {
+                WindowInput::CloseRequested => self.should_quit = true,
+                WindowInput::Resized(_) | WindowInput::HiDpiFactorChanged(_) => {
                     self.should_rebuild_swapchain = true;
                 }
+                WindowInput::Refresh => self.should_redraw = true,
                 _ => (),
             }
         }
+    }
+}
+
+impl InputHandler for Blackboard {
+    /// Check for window resize, HiDPI factor change, redraw and window close
+    /// events, as `observe` does. Quitting from the
+    /// keyboard is left to the "quit" action of an `ActionMap`.
+    fn handle_event(&mut self, event: InputEvent) -> Option<InputEvent> {
+        self.note(&event);
         None
     }
+
+    /// Reset the flags to false.
+    fn reset(&mut self) {
+        self.should_quit = false;
+        self.should_rebuild_swapchain = false;
+        self.should_redraw = false;
+        self.resized_layers.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Consumes every event, like a modal dialog.
+    struct Modal;
+
+    impl InputHandler for Modal {
+        fn handle_event(&mut self, _event: InputEvent) -> Option<InputEvent> {
+            None
+        }
+    }
+
+    #[test]
+    fn window_events_reach_the_blackboard_past_a_consuming_handler() {
+        let mut root = RootEventHandler::default();
+        root.push_handler(100, Modal);
+        root.push(InputEvent::Window(WindowInput::Resized(LogicalSize::new(640.0, 480.0))));
+        root.push(InputEvent::Window(WindowInput::CloseRequested));
+
+        let mut blackboard = Blackboard::default();
+        blackboard.observe(root.events());
+        root.dispatch();
+
+        assert!(root.events().is_empty());
+        assert!(blackboard.should_quit);
+        assert!(blackboard.should_rebuild_swapchain);
+        assert!(!blackboard.should_redraw);
+    }
+
+    #[test]
+    fn handlers_are_dispatched_by_priority() {
+        struct Tag(&'static str, Rc<RefCell<Vec<&'static str>>>);
+
+        impl InputHandler for Tag {
+            fn handle_event(&mut self, event: InputEvent) -> Option<InputEvent> {
+                self.1.borrow_mut().push(self.0);
+                Some(event)
+            }
+        }
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut root = RootEventHandler::default();
+        root.push_handler(0, Tag("low", Rc::clone(&seen)));
+        root.push_handler(10, Tag("high", Rc::clone(&seen)));
+        let newer = root.push_handler(0, Tag("newer low", Rc::clone(&seen)));
+        root.push(InputEvent::Window(WindowInput::Refresh));
+        root.dispatch();
+
+        assert_eq!(*seen.borrow(), vec!["high", "newer low", "low"]);
+        assert_eq!(root.events().len(), 1);
+        assert!(root.remove_handler(newer).is_some());
+        assert!(root.pop_handler().is_some());
+        assert_eq!(root.handler_count(), 1);
+    }
 }
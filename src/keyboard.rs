@@ -0,0 +1,258 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::winit::{ElementState, KeyboardInput};
+pub use crate::winit::{ModifiersState, VirtualKeyCode};
+
+use crate::input::{InputEvent, InputHandler, WindowInput};
+
+/// Delay and interval in seconds for generating key repeats in software,
+/// instead of relying on the platform's own repeat.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyRepeat {
+    pub delay: f32,
+    pub interval: f32,
+}
+
+/// Tracks held, just pressed, just released and repeated keys, the modifier
+/// state and any text typed this frame. Events are passed on unmodified.
+#[derive(Clone, Debug, Default)]
+pub struct KeyboardState {
+    /// Held keys, with the time in seconds until their next software repeat.
+    held: HashMap<VirtualKeyCode, f32>,
+    pressed: HashSet<VirtualKeyCode>,
+    released: HashSet<VirtualKeyCode>,
+    repeated: HashSet<VirtualKeyCode>,
+    modifiers: ModifiersState,
+    repeat: Option<KeyRepeat>,
+    text: String,
+}
+
+impl KeyboardState {
+    /// Generate key repeats in software with the given timing. Repeats sent
+    /// by the platform are then ignored, so that the rate is consistent.
+    /// Repeats are counted in fixed updates: a `Runner` advances them before
+    /// each `App::update`, and anything else driving the handler must call
+    /// `InputHandler::update` with the step before reading the state and
+    /// `reset` after.
+    pub fn with_repeat(mut self, repeat: KeyRepeat) -> Self {
+        self.repeat = Some(repeat);
+        self
+    }
+
+    /// Check if a key is held down.
+    pub fn is_held(&self, key: VirtualKeyCode) -> bool {
+        self.held.contains_key(&key)
+    }
+
+    /// Check if a key was first pressed this frame.
+    pub fn was_pressed(&self, key: VirtualKeyCode) -> bool {
+        self.pressed.contains(&key)
+    }
+
+    /// Check if a key was released this frame.
+    pub fn was_released(&self, key: VirtualKeyCode) -> bool {
+        self.released.contains(&key)
+    }
+
+    /// Check if a held key repeated this frame.
+    pub fn was_repeated(&self, key: VirtualKeyCode) -> bool {
+        self.repeated.contains(&key)
+    }
+
+    /// Check if a key was pressed or repeated this frame, as wanted for
+    /// movement keys and text editing.
+    pub fn was_triggered(&self, key: VirtualKeyCode) -> bool {
+        self.was_pressed(key) || self.was_repeated(key)
+    }
+
+    /// Get the modifier keys held as of the last keyboard event.
+    pub fn modifiers(&self) -> ModifiersState {
+        self.modifiers
+    }
+
+    /// Get the text typed this frame. Control characters are left out; use
+    /// the key state for backspace, enter and the like.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Take the text typed this frame, leaving it empty.
+    pub fn take_text(&mut self) -> String {
+        std::mem::replace(&mut self.text, String::new())
+    }
+
+    fn handle_key(&mut self, state: ElementState, key: VirtualKeyCode) {
+        match state {
+            ElementState::Pressed if self.held.contains_key(&key) => {
+                if self.repeat.is_none() {
+                    self.repeated.insert(key);
+                }
+            }
+            ElementState::Pressed => {
+                let delay = self.repeat.map(|repeat| repeat.delay).unwrap_or(0.0);
+                self.held.insert(key, delay);
+                self.pressed.insert(key);
+            }
+            ElementState::Released => {
+                self.held.remove(&key);
+                self.released.insert(key);
+            }
+        }
+    }
+}
+
+impl InputHandler for KeyboardState {
+    /// Track key, character and focus events.
+    fn handle_event(&mut self, event: InputEvent) -> Option<InputEvent> {
+        if let InputEvent::Window(ref event) = event {
+            match *event {
+                WindowInput::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state,
+                            virtual_keycode,
+                            modifiers,
+                            ..
+                        },
+                    ..
+                } => {
+                    self.modifiers = modifiers;
+                    if let Some(key) = virtual_keycode {
+                        self.handle_key(state, key);
+                    }
+                }
+                WindowInput::ReceivedCharacter(ch) if !ch.is_control() => self.text.push(ch),
+                // key releases are not seen while unfocused
+                WindowInput::Focused(false) => {
+                    self.released.extend(self.held.drain().map(|(key, _)| key));
+                    self.modifiers = ModifiersState::default();
+                }
+                _ => (),
+            }
+        }
+        Some(event)
+    }
+
+    /// Clear the per-frame state, keeping held keys and modifiers.
+    fn reset(&mut self) {
+        self.pressed.clear();
+        self.released.clear();
+        self.repeated.clear();
+        self.text.clear();
+    }
+
+    /// Advance software key repeat by `dt` seconds. Does nothing unless
+    /// `with_repeat` was used.
+    fn update(&mut self, dt: f32) {
+        let repeat = match self.repeat {
+            Some(repeat) => repeat,
+            None => return,
+        };
+        for (&key, remaining) in self.held.iter_mut() {
+            *remaining -= dt;
+            if *remaining <= 0.0 {
+                *remaining += repeat.interval.max(std::f32::EPSILON);
+                self.repeated.insert(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(state: ElementState, key: VirtualKeyCode) -> InputEvent {
+        InputEvent::Window(WindowInput::KeyboardInput {
+            input: KeyboardInput {
+                scancode: 0,
+                state,
+                virtual_keycode: Some(key),
+                modifiers: ModifiersState::default(),
+            },
+        })
+    }
+
+    fn repeating() -> KeyboardState {
+        KeyboardState::default().with_repeat(KeyRepeat {
+            delay: 0.5,
+            interval: 0.125,
+        })
+    }
+
+    #[test]
+    fn press_and_release_last_one_frame() {
+        let mut keyboard = KeyboardState::default();
+        keyboard.handle_event(key(ElementState::Pressed, VirtualKeyCode::A));
+        assert!(keyboard.was_pressed(VirtualKeyCode::A));
+        assert!(keyboard.is_held(VirtualKeyCode::A));
+
+        keyboard.reset();
+        assert!(!keyboard.was_pressed(VirtualKeyCode::A));
+        assert!(keyboard.is_held(VirtualKeyCode::A));
+
+        keyboard.handle_event(key(ElementState::Released, VirtualKeyCode::A));
+        assert!(keyboard.was_released(VirtualKeyCode::A));
+        assert!(!keyboard.is_held(VirtualKeyCode::A));
+    }
+
+    #[test]
+    fn software_repeat_waits_for_the_delay() {
+        let mut keyboard = repeating();
+        keyboard.handle_event(key(ElementState::Pressed, VirtualKeyCode::Left));
+        keyboard.reset();
+
+        for _ in 0..3 {
+            keyboard.update(0.125);
+            assert!(!keyboard.was_repeated(VirtualKeyCode::Left));
+            keyboard.reset();
+        }
+        keyboard.update(0.125);
+        assert!(keyboard.was_repeated(VirtualKeyCode::Left));
+        assert!(keyboard.was_triggered(VirtualKeyCode::Left));
+    }
+
+    #[test]
+    fn software_repeat_fires_every_interval() {
+        let mut keyboard = repeating();
+        keyboard.handle_event(key(ElementState::Pressed, VirtualKeyCode::Left));
+        keyboard.reset();
+
+        // the first repeat comes at the delay, then one every two steps
+        keyboard.update(0.5);
+        assert!(keyboard.was_repeated(VirtualKeyCode::Left));
+        keyboard.reset();
+        let mut repeats = 0;
+        for _ in 0..32 {
+            keyboard.update(0.0625);
+            if keyboard.was_repeated(VirtualKeyCode::Left) {
+                repeats += 1;
+            }
+            keyboard.reset();
+        }
+        assert_eq!(repeats, 16);
+    }
+
+    #[test]
+    fn software_repeat_ignores_platform_repeats_and_stops_on_release() {
+        let mut keyboard = repeating();
+        keyboard.handle_event(key(ElementState::Pressed, VirtualKeyCode::Left));
+        keyboard.handle_event(key(ElementState::Pressed, VirtualKeyCode::Left));
+        assert!(!keyboard.was_repeated(VirtualKeyCode::Left));
+
+        keyboard.handle_event(key(ElementState::Released, VirtualKeyCode::Left));
+        keyboard.reset();
+        keyboard.update(1.0);
+        assert!(!keyboard.was_repeated(VirtualKeyCode::Left));
+    }
+
+    #[test]
+    fn platform_repeats_pass_through_without_software_repeat() {
+        let mut keyboard = KeyboardState::default();
+        keyboard.handle_event(key(ElementState::Pressed, VirtualKeyCode::Left));
+        keyboard.reset();
+        keyboard.handle_event(key(ElementState::Pressed, VirtualKeyCode::Left));
+        assert!(keyboard.was_repeated(VirtualKeyCode::Left));
+        assert!(!keyboard.was_pressed(VirtualKeyCode::Left));
+    }
+}
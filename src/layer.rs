@@ -0,0 +1,285 @@
+use crate::camera::Camera;
+use crate::console::{Cell, Console};
+
+/// A console positioned on screen, drawn in z-order with the other layers.
+/// The offset and camera are applied through the layer's projection, so
+/// changing them does not require the layer's mesh to be rebuilt.
+#[derive(Clone, Debug)]
+pub struct Layer {
+    pub console: Console,
+    pub camera: Camera,
+    offset: (f32, f32),
+    cell_size: Option<(f32, f32)>,
+    z: i32,
+    visible: bool,
+    fill_window: bool,
+    dirty: bool,
+}
+
+impl Layer {
+    /// Create a visible layer at the origin, with z 0 and the renderer's
+    /// default cell size.
+    pub fn new(width: usize, height: usize) -> Self {
+        Layer {
+            console: Console::new(width, height),
+            camera: Camera::default(),
+            offset: (0.0, 0.0),
+            cell_size: None,
+            z: 0,
+            visible: true,
+            fill_window: false,
+            dirty: true,
+        }
+    }
+
+    /// Set the offset of the layer's top-left corner, in pixels.
+    pub fn with_offset(mut self, x: f32, y: f32) -> Self {
+        self.set_offset(x, y);
+        self
+    }
+
+    /// Set the size of a cell in pixels, overriding the renderer's default.
+    pub fn with_cell_size(mut self, width: f32, height: f32) -> Self {
+        self.set_cell_size(Some((width, height)));
+        self
+    }
+
+    /// Set the z-order of the layer. Higher layers are drawn on top, and
+    /// with a depth buffer also nearer, at a depth from z clamped to the
+    /// i16 range.
+    pub fn with_z(mut self, z: i32) -> Self {
+        self.set_z(z);
+        self
+    }
+
+    /// Resize the layer to fill the window under `ScalingPolicy::Resize`.
+    pub fn with_fill_window(mut self, fill_window: bool) -> Self {
+        self.fill_window = fill_window;
+        self
+    }
+
+    pub fn fills_window(&self) -> bool {
+        self.fill_window
+    }
+
+    pub fn offset(&self) -> (f32, f32) {
+        self.offset
+    }
+
+    pub fn set_offset(&mut self, x: f32, y: f32) {
+        self.offset = (x, y);
+    }
+
+    pub fn cell_size(&self) -> Option<(f32, f32)> {
+        self.cell_size
+    }
+
+    pub fn set_cell_size(&mut self, cell_size: Option<(f32, f32)>) {
+        self.cell_size = cell_size;
+        self.dirty = true;
+    }
+
+    pub fn z(&self) -> i32 {
+        self.z
+    }
+
+    pub fn set_z(&mut self, z: i32) {
+        self.z = z;
+        self.dirty = true;
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+        self.dirty = true;
+    }
+
+    /// Get the (width, height) of the layer in pixels, given the renderer's
+    /// default cell size.
+    pub fn pixel_size(&self, default_cell_size: (f32, f32)) -> (f32, f32) {
+        let (cell_width, cell_height) = self.cell_size.unwrap_or(default_cell_size);
+        (
+            self.console.width() as f32 * cell_width,
+            self.console.height() as f32 * cell_height,
+        )
+    }
+
+    /// Get the cell under a point in content pixels, undoing the layer's
+    /// offset and camera. Returns None if the point is outside the console.
+    pub fn cell_at(&self, point: (f32, f32), default_cell_size: (f32, f32)) -> Option<(usize, usize)> {
+        let (cell_width, cell_height) = self.cell_size.unwrap_or(default_cell_size);
+        let (camera_x, camera_y) = self.camera.position();
+        let zoom = self.camera.zoom();
+        let x = ((point.0 - self.offset.0) / zoom + camera_x) / cell_width;
+        let y = ((point.1 - self.offset.1) / zoom + camera_y) / cell_height;
+        if x < 0.0 || y < 0.0 {
+            return None;
+        }
+        let (x, y) = (x.floor() as usize, y.floor() as usize);
+        if x < self.console.width() && y < self.console.height() {
+            Some((x, y))
+        } else {
+            None
+        }
+    }
+
+    /// Check if the layer or its console has changed since it was last
+    /// marked clean.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty || self.console.is_dirty()
+    }
+
+    /// Mark the layer and its console as up to date with the renderer.
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+        self.console.mark_clean();
+    }
+}
+
+/// Handle to a layer in a `LayerStack`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LayerId(usize);
+
+/// Notification that a layer was resized to fill the window, so that the
+/// application can lay out its contents again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LayerResized {
+    pub id: LayerId,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Set of console layers, composited bottom to top by z-order. Layers with
+/// equal z are drawn in the order they were pushed.
+#[derive(Clone, Debug, Default)]
+pub struct LayerStack {
+    layers: Vec<Option<Layer>>,
+    dirty: bool,
+}
+
+impl LayerStack {
+    /// Add a layer, returning its handle.
+    pub fn push(&mut self, layer: Layer) -> LayerId {
+        self.dirty = true;
+        match self.layers.iter().position(Option::is_none) {
+            Some(index) => {
+                self.layers[index] = Some(layer);
+                LayerId(index)
+            }
+            None => {
+                self.layers.push(Some(layer));
+                LayerId(self.layers.len() - 1)
+            }
+        }
+    }
+
+    /// Remove a layer, returning it if it was present.
+    pub fn remove(&mut self, id: LayerId) -> Option<Layer> {
+        let layer = self.layers.get_mut(id.0).and_then(Option::take);
+        self.dirty |= layer.is_some();
+        layer
+    }
+
+    pub fn get(&self, id: LayerId) -> Option<&Layer> {
+        self.layers.get(id.0).and_then(Option::as_ref)
+    }
+
+    pub fn get_mut(&mut self, id: LayerId) -> Option<&mut Layer> {
+        self.layers.get_mut(id.0).and_then(Option::as_mut)
+    }
+
+    /// Get the number of layers.
+    pub fn len(&self) -> usize {
+        self.layers.iter().filter(|layer| layer.is_some()).count()
+    }
+
+    /// Check if there are no layers.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate over the layers from bottom to top.
+    pub fn iter(&self) -> impl Iterator<Item = (LayerId, &Layer)> {
+        let mut layers = self
+            .layers
+            .iter()
+            .enumerate()
+            .filter_map(|(index, layer)| layer.as_ref().map(|layer| (LayerId(index), layer)))
+            .collect::<Vec<_>>();
+        layers.sort_by_key(|(_, layer)| layer.z());
+        layers.into_iter()
+    }
+
+    /// Get the total number of cells in all layers.
+    pub fn cell_count(&self) -> usize {
+        self.iter()
+            .map(|(_, layer)| layer.console.width() * layer.console.height())
+            .sum()
+    }
+
+    /// Get the (width, height) in pixels of the area covered by all layers,
+    /// measured from the origin.
+    pub fn bounds(&self, default_cell_size: (f32, f32)) -> (f32, f32) {
+        self.iter().fold((0.0, 0.0), |(width, height), (_, layer)| {
+            let (x, y) = layer.offset();
+            let (layer_width, layer_height) = layer.pixel_size(default_cell_size);
+            (width.max(x + layer_width), height.max(y + layer_height))
+        })
+    }
+
+    /// Resize every layer which fills the window to as many whole cells as
+    /// fit in `size` pixels, less the layer's offset. New cells are
+    /// transparent. Returns a notification for each layer that changed size.
+    pub fn fit_to_window(&mut self, size: (f32, f32), default_cell_size: (f32, f32)) -> Vec<LayerResized> {
+        let mut resized = Vec::new();
+        for (index, layer) in self.layers.iter_mut().enumerate() {
+            let layer = match layer {
+                Some(layer) if layer.fill_window => layer,
+                _ => continue,
+            };
+            let (cell_width, cell_height) = layer.cell_size.unwrap_or(default_cell_size);
+            let (x, y) = layer.offset;
+            let width = ((size.0 - x) / cell_width).floor().max(1.0) as usize;
+            let height = ((size.1 - y) / cell_height).floor().max(1.0) as usize;
+            if (width, height) != (layer.console.width(), layer.console.height()) {
+                layer.console.resize(width, height, Cell::TRANSPARENT);
+                resized.push(LayerResized {
+                    id: LayerId(index),
+                    width,
+                    height,
+                });
+            }
+        }
+        resized
+    }
+
+    /// Advance every layer's camera by `dt` seconds. Returns true if any
+    /// camera moved.
+    pub fn update_cameras(&mut self, dt: f32) -> bool {
+        self.layers
+            .iter_mut()
+            .filter_map(Option::as_mut)
+            .fold(false, |moved, layer| layer.camera.update(dt) || moved)
+    }
+
+    /// Check if any layer's camera is still easing towards its target.
+    pub fn cameras_moving(&self) -> bool {
+        self.iter().any(|(_, layer)| layer.camera.is_moving())
+    }
+
+    /// Check if any layer has changed, or layers were added or removed.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty || self.iter().any(|(_, layer)| layer.is_dirty())
+    }
+
+    /// Mark every layer as up to date with the renderer.
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+        for layer in self.layers.iter_mut().filter_map(Option::as_mut) {
+            layer.mark_clean();
+        }
+    }
+}
@@ -1,23 +1,43 @@
 #[cfg(feature = "dx12")]
-pub extern crate gfx_backend_dx12 as gfx_backend;
+pub extern crate gfx_backend_dx12;
+/// Draws nothing. Its `Backend` type lets code generic over
+/// `gfx_hal::Backend` build and be tested without a GPU, as with
+/// `cargo test --no-default-features --features empty`.
+#[cfg(feature = "empty")]
+pub extern crate gfx_backend_empty;
 #[cfg(feature = "gl")]
-pub extern crate gfx_backend_gl as gfx_backend;
+pub extern crate gfx_backend_gl;
 #[cfg(feature = "metal")]
-pub extern crate gfx_backend_metal as gfx_backend;
+pub extern crate gfx_backend_metal;
 #[cfg(feature = "vulkan")]
-pub extern crate gfx_backend_vulkan as gfx_backend;
+pub extern crate gfx_backend_vulkan;
 
+/// The windowing library shared by every backend, re-exported so that apps
+/// use the same version.
+pub use ::winit;
 
-#[cfg(not(feature = "gl"))]
-use gfx_backend::winit;
-#[cfg(feature = "gl")]
-use gfx_backend::glutin;
-
+pub mod app;
+pub mod backend;
+pub mod bindings;
 pub mod buffer;
+pub mod camera;
 pub mod config;
+pub mod console;
 pub mod context;
+pub mod font;
+pub mod gamepad;
+pub mod graph;
 pub mod hal_prelude;
 pub mod input;
+pub mod keyboard;
+pub mod layer;
+pub mod mouse;
+pub mod postprocess;
+pub mod recording;
+pub mod renderer;
 pub mod shader;
 pub mod swapchain;
+pub mod texture;
+pub mod tileset;
+pub mod viewport;
 pub mod window;
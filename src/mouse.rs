@@ -0,0 +1,349 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::winit::{ElementState, MouseScrollDelta};
+pub use crate::winit::MouseButton;
+
+use crate::input::{InputEvent, InputHandler, WindowInput};
+use crate::layer::Layer;
+use crate::viewport::ViewportTransform;
+
+/// A change to the mouse seen during the current frame, with positions in
+/// physical pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MouseAction {
+    Moved((f32, f32)),
+    Pressed(MouseButton, (f32, f32)),
+    Released(MouseButton, (f32, f32)),
+    Left,
+}
+
+/// Tracks the cursor position, buttons and wheel. Positions are stored in
+/// physical pixels, converted from winit's logical pixels using the window's
+/// HiDPI factor. Events are passed on unmodified.
+#[derive(Clone, Debug)]
+pub struct MouseState {
+    hidpi_factor: f64,
+    position: Option<(f32, f32)>,
+    held: HashSet<MouseButton>,
+    pressed: HashSet<MouseButton>,
+    released: HashSet<MouseButton>,
+    wheel: (f32, f32),
+    wheel_pixels: (f32, f32),
+    actions: Vec<MouseAction>,
+}
+
+impl Default for MouseState {
+    fn default() -> Self {
+        MouseState {
+            hidpi_factor: 1.0,
+            position: None,
+            held: HashSet::new(),
+            pressed: HashSet::new(),
+            released: HashSet::new(),
+            wheel: (0.0, 0.0),
+            wheel_pixels: (0.0, 0.0),
+            actions: Vec::new(),
+        }
+    }
+}
+
+impl MouseState {
+    /// Set the initial HiDPI factor of the window. Later changes are picked
+    /// up from `HiDpiFactorChanged` events.
+    pub fn with_hidpi_factor(mut self, hidpi_factor: f64) -> Self {
+        self.hidpi_factor = hidpi_factor;
+        self
+    }
+
+    /// Get the cursor position in physical pixels, if it is in the window.
+    pub fn position(&self) -> Option<(f32, f32)> {
+        self.position
+    }
+
+    /// Check if a button is held down.
+    pub fn is_held(&self, button: MouseButton) -> bool {
+        self.held.contains(&button)
+    }
+
+    /// Check if a button was pressed this frame.
+    pub fn was_pressed(&self, button: MouseButton) -> bool {
+        self.pressed.contains(&button)
+    }
+
+    /// Check if a button was released this frame.
+    pub fn was_released(&self, button: MouseButton) -> bool {
+        self.released.contains(&button)
+    }
+
+    /// Get the wheel movement this frame in lines.
+    pub fn wheel(&self) -> (f32, f32) {
+        self.wheel
+    }
+
+    /// Get the wheel movement this frame in physical pixels, as reported by
+    /// touchpads.
+    pub fn wheel_pixels(&self) -> (f32, f32) {
+        self.wheel_pixels
+    }
+
+    /// Get the changes to the mouse seen this frame, in order.
+    pub fn actions(&self) -> &[MouseAction] {
+        &self.actions
+    }
+}
+
+impl InputHandler for MouseState {
+    /// Track cursor, button, wheel and HiDPI factor events.
+    fn handle_event(&mut self, event: InputEvent) -> Option<InputEvent> {
+        if let InputEvent::Window(ref event) = event {
+            match *event {
+                WindowInput::CursorMoved { position, .. } => {
+                    let position = position.to_physical(self.hidpi_factor);
+                    let position = (position.x as f32, position.y as f32);
+                    self.position = Some(position);
+                    self.actions.push(MouseAction::Moved(position));
+                }
+                WindowInput::CursorLeft => {
+                    self.position = None;
+                    self.actions.push(MouseAction::Left);
+                }
+                WindowInput::MouseInput { state, button, .. } => {
+                    match state {
+                        ElementState::Pressed => {
+                            self.held.insert(button);
+                            self.pressed.insert(button);
+                        }
+                        ElementState::Released => {
+                            self.held.remove(&button);
+                            self.released.insert(button);
+                        }
+                    }
+                    if let Some(position) = self.position {
+                        self.actions.push(match state {
+                            ElementState::Pressed => MouseAction::Pressed(button, position),
+                            ElementState::Released => MouseAction::Released(button, position),
+                        });
+                    }
+                }
+                WindowInput::MouseWheel { delta, .. } => match delta {
+                    MouseScrollDelta::LineDelta(x, y) => {
+                        self.wheel = (self.wheel.0 + x, self.wheel.1 + y);
+                    }
+                    MouseScrollDelta::PixelDelta(delta) => {
+                        let delta = delta.to_physical(self.hidpi_factor);
+                        self.wheel_pixels = (
+                            self.wheel_pixels.0 + delta.x as f32,
+                            self.wheel_pixels.1 + delta.y as f32,
+                        );
+                    }
+                },
+                WindowInput::HiDpiFactorChanged(hidpi_factor) => self.hidpi_factor = hidpi_factor,
+                _ => (),
+            }
+        }
+        Some(event)
+    }
+
+    /// Clear the per-frame state, keeping the cursor position and held
+    /// buttons.
+    fn reset(&mut self) {
+        self.pressed.clear();
+        self.released.clear();
+        self.wheel = (0.0, 0.0);
+        self.wheel_pixels = (0.0, 0.0);
+        self.actions.clear();
+    }
+}
+
+/// Mouse event in the cell space of a layer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CellEvent {
+    /// The cursor moved onto a different cell, or off the console.
+    Hover(Option<(usize, usize)>),
+    /// A button was pressed and released on the same cell without dragging.
+    Click { button: MouseButton, cell: (usize, usize) },
+    /// The cursor moved onto a new cell while a button pressed on `from` was
+    /// held.
+    Drag { button: MouseButton, from: (usize, usize), to: (usize, usize) },
+    /// A dragged button was released, over `to` if it is on the console.
+    DragEnd { button: MouseButton, from: (usize, usize), to: Option<(usize, usize)> },
+}
+
+/// Turns the pixel-space `MouseState` into hover, click and drag events in
+/// the cells of one layer. Keep one per layer that reacts to the mouse.
+#[derive(Clone, Debug, Default)]
+pub struct CellPointer {
+    hover: Option<(usize, usize)>,
+    drags: HashMap<MouseButton, ((usize, usize), bool)>,
+}
+
+impl CellPointer {
+    /// Get the cell under the cursor as of the last update.
+    pub fn hovered(&self) -> Option<(usize, usize)> {
+        self.hover
+    }
+
+    /// Replay this frame's mouse actions against `layer`, drawn with the
+    /// given viewport transform and content size. Call once per frame after
+    /// the `MouseState` has been synced.
+    pub fn update(
+        &mut self,
+        mouse: &MouseState,
+        layer: &Layer,
+        transform: &ViewportTransform,
+        content: (f32, f32),
+        default_cell_size: (f32, f32),
+    ) -> Vec<CellEvent> {
+        let to_cell = |point: (f32, f32)| layer.cell_at(transform.to_content(point, content), default_cell_size);
+        let mut events = Vec::new();
+        for action in mouse.actions() {
+            match *action {
+                MouseAction::Moved(position) => self.move_to(to_cell(position), &mut events),
+                MouseAction::Left => self.move_to(None, &mut events),
+                MouseAction::Pressed(button, position) => {
+                    let cell = to_cell(position);
+                    self.move_to(cell, &mut events);
+                    if let Some(cell) = cell {
+                        self.drags.insert(button, (cell, false));
+                    }
+                }
+                MouseAction::Released(button, position) => {
+                    let cell = to_cell(position);
+                    self.move_to(cell, &mut events);
+                    match self.drags.remove(&button) {
+                        Some((from, true)) => events.push(CellEvent::DragEnd { button, from, to: cell }),
+                        Some((from, false)) if cell == Some(from) => {
+                            events.push(CellEvent::Click { button, cell: from })
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+        // the camera or layout may have moved the cells under a still cursor
+        self.move_to(mouse.position().and_then(to_cell), &mut events);
+        // buttons released outside the window are never seen as actions
+        self.drags.retain(|button, _| mouse.is_held(*button));
+        events
+    }
+
+    fn move_to(&mut self, cell: Option<(usize, usize)>, events: &mut Vec<CellEvent>) {
+        if cell == self.hover {
+            return;
+        }
+        self.hover = cell;
+        events.push(CellEvent::Hover(cell));
+        for (&button, drag) in self.drags.iter_mut() {
+            let (from, ref mut dragging) = *drag;
+            if cell != Some(from) {
+                *dragging = true;
+            }
+            if let (true, Some(to)) = (*dragging, cell) {
+                events.push(CellEvent::Drag { button, from, to });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ScalingPolicy;
+    use crate::winit::dpi::LogicalPosition;
+    use crate::winit::ModifiersState;
+
+    const CONTENT: (f32, f32) = (80.0, 80.0);
+    const CELL: (f32, f32) = (8.0, 8.0);
+
+    fn moved(x: f64, y: f64) -> InputEvent {
+        InputEvent::Window(WindowInput::CursorMoved {
+            position: LogicalPosition::new(x, y),
+            modifiers: ModifiersState::default(),
+        })
+    }
+
+    fn left(state: ElementState) -> InputEvent {
+        InputEvent::Window(WindowInput::MouseInput {
+            state,
+            button: MouseButton::Left,
+            modifiers: ModifiersState::default(),
+        })
+    }
+
+    /// Feed a frame of events to the mouse, then update the pointer over a
+    /// 10x10 layer of 8x8 cells drawn at twice its size.
+    fn frame(mouse: &mut MouseState, pointer: &mut CellPointer, layer: &Layer, events: Vec<InputEvent>) -> Vec<CellEvent> {
+        mouse.reset();
+        for event in events {
+            mouse.handle_event(event);
+        }
+        let transform = ViewportTransform::compute(ScalingPolicy::Fit, CONTENT, (160, 160));
+        pointer.update(mouse, layer, &transform, CONTENT, CELL)
+    }
+
+    #[test]
+    fn positions_are_converted_to_physical_pixels() {
+        let mut mouse = MouseState::default().with_hidpi_factor(2.0);
+        mouse.handle_event(moved(10.0, 5.0));
+        assert_eq!(mouse.position(), Some((20.0, 10.0)));
+        mouse.handle_event(InputEvent::Window(WindowInput::HiDpiFactorChanged(1.0)));
+        mouse.handle_event(moved(10.0, 5.0));
+        assert_eq!(mouse.position(), Some((10.0, 5.0)));
+        mouse.handle_event(InputEvent::Window(WindowInput::CursorLeft));
+        assert_eq!(mouse.position(), None);
+    }
+
+    #[test]
+    fn press_and_release_on_a_cell_clicks_it() {
+        let (mut mouse, mut pointer, layer) = (MouseState::default(), CellPointer::default(), Layer::new(10, 10));
+        let events = frame(&mut mouse, &mut pointer, &layer, vec![
+            moved(20.0, 20.0),
+            left(ElementState::Pressed),
+            left(ElementState::Released),
+        ]);
+        assert_eq!(events, vec![
+            CellEvent::Hover(Some((1, 1))),
+            CellEvent::Click {
+                button: MouseButton::Left,
+                cell: (1, 1),
+            },
+        ]);
+        assert_eq!(pointer.hovered(), Some((1, 1)));
+    }
+
+    #[test]
+    fn moving_a_held_button_drags() {
+        let (mut mouse, mut pointer, layer) = (MouseState::default(), CellPointer::default(), Layer::new(10, 10));
+        frame(&mut mouse, &mut pointer, &layer, vec![moved(20.0, 20.0), left(ElementState::Pressed)]);
+
+        let events = frame(&mut mouse, &mut pointer, &layer, vec![
+            moved(40.0, 20.0),
+            left(ElementState::Released),
+        ]);
+        assert_eq!(events, vec![
+            CellEvent::Hover(Some((2, 1))),
+            CellEvent::Drag {
+                button: MouseButton::Left,
+                from: (1, 1),
+                to: (2, 1),
+            },
+            CellEvent::DragEnd {
+                button: MouseButton::Left,
+                from: (1, 1),
+                to: Some((2, 1)),
+            },
+        ]);
+
+        let events = frame(&mut mouse, &mut pointer, &layer, vec![InputEvent::Window(WindowInput::CursorLeft)]);
+        assert_eq!(events, vec![CellEvent::Hover(None)]);
+    }
+
+    #[test]
+    fn scrolling_under_a_still_cursor_changes_the_hovered_cell() {
+        let (mut mouse, mut pointer, mut layer) = (MouseState::default(), CellPointer::default(), Layer::new(10, 10));
+        frame(&mut mouse, &mut pointer, &layer, vec![moved(20.0, 20.0)]);
+        layer.camera.jump_to(8.0, 0.0);
+        let events = frame(&mut mouse, &mut pointer, &layer, vec![]);
+        assert_eq!(events, vec![CellEvent::Hover(Some((2, 1)))]);
+    }
+}
@@ -0,0 +1,428 @@
+use std::time::Instant;
+
+use crate::buffer::Buffer;
+use crate::config::{Config, PostPassConfig};
+use crate::context::Context;
+use crate::graph::{self, GraphImages, GraphPlan, ImageDesc, PassId, RenderGraph, ResourceId};
+use crate::hal_prelude::*;
+use crate::renderer::MeshConsoleRenderer;
+use crate::shader::{ShaderHandle, ShaderSource};
+use crate::swapchain::{FramebufferState, PassAttachments, SwapchainState};
+use crate::texture::TextureError;
+
+use log::{info, warn};
+
+/// Most values a post-processing pass can be given in its config.
+pub const MAX_PARAMS: usize = 8;
+
+/// Shared vertex shader drawing a triangle over the whole target.
+const VERTEX_SHADER: &str = "assets/post.vert";
+
+/// Uniform block of every pass, laid out as std140 to match
+/// `uniform PostParams { vec2 resolution; float time; vec4 params[2]; }`.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct PostParams {
+    resolution: [f32; 2],
+    time: f32,
+    _padding: f32,
+    params: [f32; MAX_PARAMS],
+}
+
+/// One full-screen pass, with its uniforms and its input bound at set 0.
+struct PostPass<B: gfx_hal::Backend> {
+    frag: ShaderHandle<B>,
+    pipeline: B::GraphicsPipeline,
+    params: PostParams,
+    uniform: Buffer<B>,
+    desc_set: B::DescriptorSet,
+    node: PassId,
+    input: ResourceId,
+    output: ResourceId,
+    /// Framebuffer drawing to `output`, unless it is the swapchain.
+    framebuffer: Option<B::Framebuffer>,
+}
+
+/// Runs the passes of `GraphicsConfig::post_process` in order over the
+/// console renderer's offscreen target, the last of them drawing into the
+/// swapchain. The scene and the passes form a `RenderGraph`, which places
+/// the barriers between them and shares images between intermediate
+/// results that are not alive at once.
+///
+/// Each pass samples its input at binding 0 and reads a `PostParams` block
+/// at binding 1, holding the target's resolution, the seconds since the
+/// chain was created and the pass's params. Effects animated by time only
+/// move while frames are drawn, so `OnDemand` loops must call
+/// `AppContext::request_redraw` for them.
+pub struct PostProcessChain<B: gfx_hal::Backend> {
+    vert: ShaderHandle<B>,
+    offscreen_pass: B::RenderPass,
+    present_pass: B::RenderPass,
+    set_layout: B::DescriptorSetLayout,
+    pipeline_layout: B::PipelineLayout,
+    desc_pool: B::DescriptorPool,
+    sampler: B::Sampler,
+    passes: Vec<PostPass<B>>,
+    plan: GraphPlan,
+    scene: ResourceId,
+    scene_node: PassId,
+    images: Option<GraphImages<B>>,
+    swap_framebuffers: FramebufferState<B>,
+    extent: Extent,
+    start: Instant,
+}
+
+impl<B: gfx_hal::Backend> PostProcessChain<B> {
+    /// Load the configured passes, or return None if there are none. Passes
+    /// whose shader is missing or fails to compile are dropped with a
+    /// warning. The framebuffers are built by `rebuild`.
+    pub unsafe fn new(context: &Context<B>, config: &Config) -> Option<Self> {
+        let pass_configs = config
+            .graphics
+            .post_process
+            .iter()
+            .filter_map(|pass_config| Some((pass_config, PostProcessChain::load_shader(context, &pass_config.shader)?)))
+            .collect::<Vec<_>>();
+        if pass_configs.is_empty() {
+            return None;
+        }
+
+        let offscreen = PassAttachments {
+            offscreen: true,
+            ..PassAttachments::default()
+        };
+        let offscreen_pass = MeshConsoleRenderer::build_render_pass(context, offscreen);
+        let present_pass = MeshConsoleRenderer::build_render_pass(context, PassAttachments::default());
+
+        let set_layout = context.device.create_descriptor_set_layout(
+            &[
+                DescriptorSetLayoutBinding {
+                    binding: 0,
+                    ty: DescriptorType::CombinedImageSampler,
+                    count: 1,
+                    stage_flags: ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: false,
+                },
+                DescriptorSetLayoutBinding {
+                    binding: 1,
+                    ty: DescriptorType::UniformBuffer,
+                    count: 1,
+                    stage_flags: ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: false,
+                },
+            ],
+            &[],
+        ).expect("Failed to create descriptor set layout!");
+        let pipeline_layout = context.device
+            .create_pipeline_layout(vec![&set_layout], &[])
+            .expect("Failed to create pipeline layout!");
+        let mut desc_pool = context.device.create_descriptor_pool(
+            pass_configs.len(),
+            &[
+                DescriptorRangeDesc {
+                    ty: DescriptorType::CombinedImageSampler,
+                    count: pass_configs.len(),
+                },
+                DescriptorRangeDesc {
+                    ty: DescriptorType::UniformBuffer,
+                    count: pass_configs.len(),
+                },
+            ],
+        ).expect("Unable to create descriptor pool!");
+        let sampler = context.device
+            .create_sampler(img::SamplerInfo::new(Filter::Linear, WrapMode::Clamp))
+            .expect("Failed to create sampler!");
+
+        let source = ShaderSource::from_glsl_path(VERTEX_SHADER).expect("Couldn't find vertex shader");
+        let vert = ShaderHandle::new(&context.device, source).expect("Failed to load vertex shader");
+
+        let mut graph = RenderGraph::new();
+        let scene = graph.import_image("scene", Layout::Undefined, None);
+        let swapchain = graph.import_image("swapchain", Layout::Undefined, None);
+        let scene_node = graph.add_pass("scene", &[], &[scene]);
+        let desc = ImageDesc {
+            format: context.surface_colour_format,
+            samples: 1,
+        };
+
+        let last = pass_configs.len() - 1;
+        let mut input = scene;
+        let mut passes = Vec::with_capacity(pass_configs.len());
+        for (index, (pass_config, frag)) in pass_configs.into_iter().enumerate() {
+            let (render_pass, output) = if index == last {
+                (&present_pass, swapchain)
+            } else {
+                (&offscreen_pass, graph.create_image(&pass_config.shader, desc))
+            };
+            let pipeline = PostProcessChain::build_pipeline(context, &vert, &frag, render_pass, &pipeline_layout);
+            let (params, uniform, desc_set) = PostProcessChain::load_params(context, pass_config, &mut desc_pool, &set_layout);
+            passes.push(PostPass {
+                frag,
+                pipeline,
+                params,
+                uniform,
+                desc_set,
+                node: graph.add_pass(&pass_config.shader, &[input], &[output]),
+                input,
+                output,
+                framebuffer: None,
+            });
+            input = output;
+        }
+        let plan = graph.compile(&[swapchain]).expect("Failed to compile post-processing graph!");
+
+        Some(PostProcessChain {
+            vert,
+            offscreen_pass,
+            present_pass,
+            set_layout,
+            pipeline_layout,
+            desc_pool,
+            sampler,
+            passes,
+            plan,
+            scene,
+            scene_node,
+            images: None,
+            swap_framebuffers: FramebufferState::new_empty(PassAttachments::default()),
+            extent: Extent { width: 1, height: 1, depth: 1 },
+            start: Instant::now(),
+        })
+    }
+
+    /// Load a pass's fragment shader, or warn and return None if it is
+    /// missing or fails to compile.
+    fn load_shader(context: &Context<B>, path: &str) -> Option<ShaderHandle<B>> {
+        let source = match ShaderSource::from_glsl_path(path) {
+            Some(source) => source,
+            None => {
+                warn!("couldn't find post-processing shader {}, dropping its pass", path);
+                return None;
+            }
+        };
+        match ShaderHandle::new(&context.device, source) {
+            Ok(frag) => {
+                info!("loaded post-processing shader {}", path);
+                Some(frag)
+            }
+            Err(err) => {
+                warn!("failed to load post-processing shader {}, dropping its pass: {}", path, err);
+                None
+            }
+        }
+    }
+
+    /// Create a pass's uniform buffer holding its params, and a descriptor
+    /// set binding it. The input is bound by `rebuild`.
+    unsafe fn load_params(
+        context: &Context<B>,
+        pass_config: &PostPassConfig,
+        desc_pool: &mut B::DescriptorPool,
+        set_layout: &B::DescriptorSetLayout,
+    ) -> (PostParams, Buffer<B>, B::DescriptorSet) {
+        if pass_config.params.len() > MAX_PARAMS {
+            warn!("{} has {} params, only the first {} are used", pass_config.shader, pass_config.params.len(), MAX_PARAMS);
+        }
+        let mut params = PostParams {
+            resolution: [1.0, 1.0],
+            time: 0.0,
+            _padding: 0.0,
+            params: [0.0; MAX_PARAMS],
+        };
+        for (param, &value) in params.params.iter_mut().zip(&pass_config.params) {
+            *param = value;
+        }
+
+        let memory_types = &context.physical_device().memory_properties().memory_types;
+        let uniform = Buffer::new_uniform(
+            &context.device,
+            &[params],
+            &memory_types,
+            Properties::CPU_VISIBLE
+        ).expect("Unable to create uniform buffer!");
+        let desc_set = desc_pool.allocate_set(set_layout).unwrap();
+        context.device.write_descriptor_sets(vec![DescriptorSetWrite {
+            set: &desc_set,
+            binding: 1,
+            array_offset: 0,
+            descriptors: Some(Descriptor::Buffer(uniform.buffer.as_ref().unwrap(), None..None))
+        }]);
+        (params, uniform, desc_set)
+    }
+
+    fn build_pipeline(
+        context: &Context<B>,
+        vert: &ShaderHandle<B>,
+        frag: &ShaderHandle<B>,
+        render_pass: &B::RenderPass,
+        pipeline_layout: &B::PipelineLayout,
+    ) -> B::GraphicsPipeline {
+        let shader_entries = GraphicsShaderSet {
+            vertex: vert.entry_point("main").unwrap(),
+            hull: None,
+            domain: None,
+            geometry: None,
+            fragment: Some(frag.entry_point("main").unwrap()),
+        };
+        let subpass = Subpass {
+            index: 0,
+            main_pass: render_pass
+        };
+        // the vertex shader makes its triangle from the vertex index alone
+        let mut pipeline_desc = GraphicsPipelineDesc::new(shader_entries,
+                                                            Primitive::TriangleList,
+                                                            Rasterizer::FILL,
+                                                            pipeline_layout,
+                                                            subpass);
+        pipeline_desc.blender
+                    .targets
+                    .push(ColorBlendDesc(ColorMask::ALL, BlendState::Off));
+        unsafe {
+            context.device.create_graphics_pipeline(&pipeline_desc, None)
+                .unwrap()
+        }
+    }
+
+    /// Allocate the graph's images at the swapchain's size, build each pass's
+    /// framebuffer and bind its input. `scene` must have been rebuilt
+    /// offscreen first. Nothing is left allocated if an image cannot be
+    /// created.
+    pub fn rebuild(&mut self, context: &Context<B>, scene: &FramebufferState<B>, swap_state: &mut SwapchainState<B>) -> Result<(), TextureError> {
+        self.extent = swap_state.extent;
+        let images = GraphImages::new(context, &self.plan, self.extent)?;
+        if let Err(err) = self.swap_framebuffers.rebuild_from_swapchain(context, &self.present_pass, swap_state) {
+            images.destroy(&context.device);
+            return Err(err);
+        }
+
+        let (offscreen_pass, extent) = (&self.offscreen_pass, self.extent);
+        for pass in &mut self.passes {
+            pass.framebuffer = images.view(&self.plan, pass.output).map(|view| {
+                let mut framebuffers = context
+                    .image_views_to_fbos(std::slice::from_ref(view), &[], offscreen_pass, extent)
+                    .unwrap();
+                framebuffers.remove(0)
+            });
+
+            let input = if pass.input == self.scene {
+                scene.target_view()
+            } else {
+                images.view(&self.plan, pass.input)
+            };
+            let input = input.expect("Post-processing input was not drawn offscreen!");
+            unsafe {
+                context.device.write_descriptor_sets(vec![DescriptorSetWrite {
+                    set: &pass.desc_set,
+                    binding: 0,
+                    array_offset: 0,
+                    descriptors: Some(Descriptor::CombinedImageSampler(input, Layout::ShaderReadOnlyOptimal, &self.sampler)),
+                }]);
+            }
+        }
+        self.images = Some(images);
+        Ok(())
+    }
+
+    /// Write the current time and resolution into every pass's uniforms.
+    /// The last frame recorded with the chain must have finished on the GPU,
+    /// as the uniforms are written in place.
+    pub fn update(&mut self, device: &B::Device) {
+        let elapsed = self.start.elapsed();
+        let time = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 * 1e-9;
+        let resolution = [self.extent.width as f32, self.extent.height as f32];
+        for pass in &mut self.passes {
+            pass.params.time = time;
+            pass.params.resolution = resolution;
+            pass.uniform.fill(device, &[pass.params]).expect("Unable to fill uniform buffer!");
+        }
+    }
+
+    /// Record the frame in the graph's order: the scene, drawn into
+    /// `scene`'s offscreen target by `draw_scene`, then every pass, the last
+    /// drawing into the swapchain image `frame_index`, with the barriers
+    /// between them. The command buffer is left open.
+    pub unsafe fn record<C, S, F>(
+        &self,
+        command_buffer: &mut CommandBuffer<B, C, S>,
+        frame_index: u32,
+        scene: &FramebufferState<B>,
+        draw_scene: F,
+    ) where
+        C: gfx_hal::queue::Supports<gfx_hal::queue::capability::Graphics>
+            + gfx_hal::queue::Supports<gfx_hal::queue::capability::Transfer>,
+        S: gfx_hal::command::Shot,
+        F: FnOnce(&mut CommandBuffer<B, C, S>),
+    {
+        let images = self.images.as_ref().expect("Post-processing chain was not rebuilt!");
+        // the swapchain image is left to the present pass, which moves it
+        // into the layout for presenting
+        let image_of = |resource: ResourceId| {
+            if resource == self.scene {
+                scene.target_image()
+            } else {
+                images.image(&self.plan, resource)
+            }
+        };
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            w: self.extent.width as i16,
+            h: self.extent.height as i16,
+        };
+        let clear_values = [ClearValue::Color(ClearColor::Float([0.0, 0.0, 0.0, 1.0]))];
+
+        let mut draw_scene = Some(draw_scene);
+        for (node, transitions) in self.plan.passes() {
+            graph::record_transitions(command_buffer, transitions, &image_of);
+            if node == self.scene_node {
+                if let Some(draw_scene) = draw_scene.take() {
+                    draw_scene(command_buffer);
+                }
+                continue;
+            }
+
+            let pass = self.passes.iter().find(|pass| pass.node == node).unwrap();
+            let (render_pass, framebuffer) = match pass.framebuffer {
+                Some(ref framebuffer) => (&self.offscreen_pass, framebuffer),
+                None => (&self.present_pass, &self.swap_framebuffers.framebuffers()[frame_index as usize]),
+            };
+            command_buffer.set_viewports(0, &[Viewport { rect, depth: 0.0..1.0 }]);
+            command_buffer.set_scissors(0, &[rect]);
+            command_buffer.bind_graphics_pipeline(&pass.pipeline);
+            let mut encoder = command_buffer.begin_render_pass_inline(render_pass, framebuffer, rect, &clear_values);
+            encoder.bind_graphics_descriptor_sets(&self.pipeline_layout, 0, vec![&pass.desc_set], &[]);
+            encoder.draw(0..3, 0..1);
+        }
+        graph::record_transitions(command_buffer, self.plan.final_transitions(), &image_of);
+    }
+
+    /// Destroy the graph's images and the framebuffers, before the swapchain
+    /// is rebuilt.
+    pub fn destroy_framebuffers(&mut self, device: &B::Device) {
+        for pass in &mut self.passes {
+            if let Some(framebuffer) = pass.framebuffer.take() {
+                unsafe { device.destroy_framebuffer(framebuffer) };
+            }
+        }
+        if let Some(images) = self.images.take() {
+            images.destroy(device);
+        }
+        self.swap_framebuffers.destroy(device);
+    }
+
+    pub unsafe fn destroy(mut self, context: &Context<B>) {
+        self.destroy_framebuffers(&context.device);
+        for mut pass in self.passes {
+            context.device.destroy_graphics_pipeline(pass.pipeline);
+            pass.uniform.destroy(&context.device);
+            pass.frag.destroy(&context.device);
+        }
+        context.device.destroy_descriptor_pool(self.desc_pool);
+        context.device.destroy_pipeline_layout(self.pipeline_layout);
+        context.device.destroy_descriptor_set_layout(self.set_layout);
+        context.device.destroy_sampler(self.sampler);
+        context.device.destroy_render_pass(self.offscreen_pass);
+        context.device.destroy_render_pass(self.present_pass);
+        self.vert.destroy(&context.device);
+    }
+}
@@ -0,0 +1,198 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Lines, Write};
+use std::time::Instant;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::input::{InputEvent, RootEventHandler};
+
+#[derive(Debug)]
+pub enum RecordingError {
+    IoError(io::Error),
+    FormatError(serde_json::Error),
+}
+
+impl From<io::Error> for RecordingError {
+    fn from(err: io::Error) -> Self {
+        RecordingError::IoError(err)
+    }
+}
+
+impl From<serde_json::Error> for RecordingError {
+    fn from(err: serde_json::Error) -> Self {
+        RecordingError::FormatError(err)
+    }
+}
+
+impl fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl Error for RecordingError {}
+
+/// The events seen during one frame, with the frame number, the time in
+/// seconds since recording started, and the frame time in seconds that was
+/// fed to the fixed-step updates.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct FrameRecord {
+    pub frame: u64,
+    pub time: f64,
+    pub dt: f32,
+    pub events: Vec<InputEvent>,
+}
+
+/// Writes each frame's events and timing to a file as they arrive, one JSON
+/// record per line. Every frame is recorded, even without events, so that
+/// a replay runs the same updates.
+pub struct InputRecorder<W: Write = BufWriter<File>> {
+    writer: W,
+    start: Instant,
+    frame: u64,
+}
+
+impl InputRecorder {
+    /// Start recording to a new file at `path`.
+    pub fn create(path: &str) -> Result<Self, RecordingError> {
+        Ok(InputRecorder::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> InputRecorder<W> {
+    pub fn new(writer: W) -> Self {
+        InputRecorder {
+            writer,
+            start: Instant::now(),
+            frame: 0,
+        }
+    }
+
+    /// Record the events cached by `root` as the next frame, which lasted
+    /// `dt` seconds. Call once per frame, after polling and before syncing
+    /// any handlers.
+    pub fn record(&mut self, root: &RootEventHandler, dt: f32) -> Result<(), RecordingError> {
+        let frame = self.frame;
+        self.frame += 1;
+        let elapsed = self.start.elapsed();
+        let record = FrameRecord {
+            frame,
+            time: elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9,
+            dt,
+            events: root.events().to_vec(),
+        };
+        serde_json::to_writer(&mut self.writer, &record)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Write any buffered records out.
+    pub fn flush(&mut self) -> Result<(), RecordingError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Stop recording, returning the writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Feeds recorded events back to a `RootEventHandler` frame by frame, so
+/// that handlers see the same input without a window. Each frame's recorded
+/// `dt` should drive the updates in place of the clock.
+pub struct InputReplay<R: BufRead = BufReader<File>> {
+    lines: Lines<R>,
+    next: Option<FrameRecord>,
+}
+
+impl InputReplay {
+    /// Open a recording made by `InputRecorder`.
+    pub fn open(path: &str) -> Result<Self, RecordingError> {
+        InputReplay::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: BufRead> InputReplay<R> {
+    pub fn new(reader: R) -> Result<Self, RecordingError> {
+        let mut replay = InputReplay {
+            lines: reader.lines(),
+            next: None,
+        };
+        replay.next = replay.read_record()?;
+        Ok(replay)
+    }
+
+    /// Check if every recorded frame has been replayed.
+    pub fn is_finished(&self) -> bool {
+        self.next.is_none()
+    }
+
+    /// Push the events recorded for the next frame into `root`, returning
+    /// the frame's record, or None once every frame has been replayed. Call
+    /// once per frame in place of polling the window.
+    pub fn next_frame(&mut self, root: &mut RootEventHandler) -> Result<Option<FrameRecord>, RecordingError> {
+        if self.next.is_none() {
+            return Ok(None);
+        }
+        let following = self.read_record()?;
+        let record = std::mem::replace(&mut self.next, following);
+        if let Some(ref record) = record {
+            for event in &record.events {
+                root.push(event.clone());
+            }
+        }
+        Ok(record)
+    }
+
+    fn read_record(&mut self) -> Result<Option<FrameRecord>, RecordingError> {
+        for line in &mut self.lines {
+            let line = line?;
+            if !line.trim().is_empty() {
+                return Ok(Some(serde_json::from_str(&line)?));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::WindowInput;
+    use std::io::Cursor;
+
+    #[test]
+    fn every_frame_is_replayed_with_its_time() {
+        let frames = vec![
+            (0.016, vec![InputEvent::Window(WindowInput::Focused(true))]),
+            (0.5, vec![]),
+            (0.001, vec![
+                InputEvent::Window(WindowInput::ReceivedCharacter('a')),
+                InputEvent::Window(WindowInput::CursorLeft),
+            ]),
+        ];
+
+        let mut recorder = InputRecorder::new(Vec::new());
+        let mut root = RootEventHandler::default();
+        for (dt, events) in &frames {
+            root.clear_events();
+            for event in events {
+                root.push(event.clone());
+            }
+            recorder.record(&root, *dt).unwrap();
+        }
+
+        let mut replay = InputReplay::new(Cursor::new(recorder.into_inner())).unwrap();
+        for (frame, (dt, events)) in frames.iter().enumerate() {
+            root.clear_events();
+            let record = replay.next_frame(&mut root).unwrap().unwrap();
+            assert_eq!(record.frame, frame as u64);
+            assert_eq!(record.dt, *dt);
+            assert_eq!(root.events(), &events[..]);
+        }
+        assert!(replay.is_finished());
+        assert_eq!(replay.next_frame(&mut root).unwrap(), None);
+    }
+}
@@ -0,0 +1,906 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::rc::Rc;
+
+use gfx_hal::queue::capability::Graphics;
+
+use crate::buffer::Buffer;
+use crate::camera::Camera;
+use crate::config::Config;
+use crate::context::Context;
+use crate::font::FontAtlas;
+use crate::hal_prelude::*;
+use crate::layer::{Layer, LayerStack};
+use crate::shader::{ShaderHandle, ShaderSource};
+use crate::swapchain::PassAttachments;
+use crate::texture::Texture;
+use crate::tileset::Tileset;
+use crate::viewport::ViewportTransform;
+
+use log::{info, warn};
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct Vertex {
+    position: [f32; 3],
+    uv: [f32; 2],
+    fg: [f32; 4],
+    bg: [f32; 4],
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct UniformBlock {
+    projection: [[f32; 4]; 4]
+}
+
+
+/// An atlas bound by the console renderer: either a fixed tileset or a font
+/// glyph cache whose tiles are codepoints.
+enum Atlas<B: gfx_hal::Backend> {
+    Tiles(Tileset<B>),
+    Font(FontAtlas<B>),
+}
+
+impl<B: gfx_hal::Backend> Atlas<B> {
+    fn texture(&self) -> &Texture<B> {
+        match self {
+            Atlas::Tiles(tileset) => &tileset.texture,
+            Atlas::Font(font) => &font.texture,
+        }
+    }
+
+    /// Get the texture coordinates for a cell's tile, caching the glyph first
+    /// if this is a font atlas. Returns None if the glyph could not be cached.
+    fn uv_rect(&mut self, tile: u32) -> Option<([f32; 2], [f32; 2])> {
+        match self {
+            Atlas::Tiles(tileset) => Some(tileset.uv_rect(tile)),
+            Atlas::Font(font) => {
+                let ch = std::char::from_u32(tile).unwrap_or('?');
+                match font.tile_for_char(ch) {
+                    Ok(slot) => Some(font.uv_rect(slot)),
+                    Err(err) => {
+                        warn!("Unable to cache glyph {:?}: {}", ch, err);
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    fn destroy(&mut self, device: &B::Device) {
+        match self {
+            Atlas::Tiles(tileset) => tileset.destroy(device),
+            Atlas::Font(font) => font.destroy(device),
+        }
+    }
+}
+
+/// What each of the renderer's atlases holds, so that tiles can be chosen
+/// for characters without access to the renderer itself.
+#[derive(Clone, Debug, Default)]
+pub struct AtlasInfo {
+    /// For each atlas, the tileset's mapping and tile count, or None for the
+    /// font.
+    tilesets: Vec<Option<(HashMap<char, u32>, u32)>>,
+    font_atlas: Option<usize>,
+    cell_size: (f32, f32),
+}
+
+impl AtlasInfo {
+    fn new<B: gfx_hal::Backend>(atlases: &[Atlas<B>], font_atlas: Option<usize>, cell_size: (f32, f32)) -> Self {
+        let tilesets = atlases
+            .iter()
+            .map(|atlas| match atlas {
+                Atlas::Tiles(tileset) => Some((tileset.mapping().clone(), tileset.tile_count())),
+                Atlas::Font(_) => None,
+            })
+            .collect();
+        AtlasInfo {
+            tilesets,
+            font_atlas,
+            cell_size,
+        }
+    }
+
+    /// Get the number of atlases.
+    pub fn len(&self) -> usize {
+        self.tilesets.len()
+    }
+
+    /// Check if there are no atlases.
+    pub fn is_empty(&self) -> bool {
+        self.tilesets.is_empty()
+    }
+
+    /// Get the atlas index of the font, if one is loaded.
+    pub fn font_atlas(&self) -> Option<usize> {
+        self.font_atlas
+    }
+
+    /// Get the default (width, height) of a cell in pixels: the font's cell
+    /// size if one is loaded, otherwise the first tileset's tile size.
+    pub fn cell_size(&self) -> (f32, f32) {
+        self.cell_size
+    }
+
+    /// Get the tile to use for a character in the given atlas.
+    pub fn tile_for_char(&self, atlas: usize, ch: char) -> u32 {
+        match self.tilesets.get(atlas) {
+            Some(Some((mapping, tile_count))) => {
+                let tile = mapping.get(&ch).cloned().unwrap_or(ch as u32);
+                if tile < *tile_count {
+                    tile
+                } else {
+                    0
+                }
+            }
+            Some(None) => ch as u32,
+            None => 0,
+        }
+    }
+}
+
+/// Maximum number of visible layers, each of which needs its own projection.
+const MAX_LAYERS: usize = 16;
+
+/// The visible layers that are drawn, bottom to top. Past `MAX_LAYERS` the
+/// lowest are dropped, so that the layers on top stay visible.
+fn drawn_layers(layers: &LayerStack) -> impl Iterator<Item = &Layer> {
+    let visible = layers.iter().filter(|(_, layer)| layer.is_visible()).map(|(_, layer)| layer).collect::<Vec<_>>();
+    let hidden = visible.len().saturating_sub(MAX_LAYERS);
+    visible.into_iter().skip(hidden)
+}
+
+/// A range of vertices to draw, with the index of its layer in draw order and
+/// the atlas to bind.
+type DrawRange = (usize, usize, Range<u32>);
+
+/// Build one quad per cell for every visible layer, bottom to top, in the
+/// layer's own pixel space. Within a layer cells are grouped by atlas.
+/// Returns the vertices and the ranges to draw, in draw order.
+fn build_mesh<B: gfx_hal::Backend>(layers: &LayerStack, atlases: &mut [Atlas<B>], default_cell_size: (f32, f32)) -> (Vec<Vertex>, Vec<DrawRange>) {
+    let mut mesh = Vec::with_capacity(layers.cell_count() * 6);
+    let mut ranges = Vec::with_capacity(layers.len() * atlases.len());
+
+    for (layer_index, layer) in drawn_layers(layers).enumerate() {
+        let (cell_width, cell_height) = layer.cell_size().unwrap_or(default_cell_size);
+        for (index, atlas) in atlases.iter_mut().enumerate() {
+            let start = mesh.len() as u32;
+            for ((x, y), cell) in layer.console.iter().filter(|(_, cell)| cell.atlas == index) {
+                // a glyph that could not be cached leaves just the background
+                let (([u0, v0], [u1, v1]), fg) = match atlas.uv_rect(cell.tile) {
+                    Some(uv) => (uv, cell.fg),
+                    None => (([0.0, 0.0], [0.0, 0.0]), [0.0; 4]),
+                };
+                let (x, y) = (x as f32, y as f32);
+                let corners = [
+                    (0.0, 0.0, u0, v0),
+                    (0.0, 1.0, u0, v1),
+                    (1.0, 0.0, u1, v0),
+                    (1.0, 0.0, u1, v0),
+                    (0.0, 1.0, u0, v1),
+                    (1.0, 1.0, u1, v1),
+                ];
+                for &(dx, dy, u, v) in &corners {
+                    mesh.push(Vertex {
+                        position: [(x + dx) * cell_width, (y + dy) * cell_height, 0.0],
+                        uv: [u, v],
+                        fg,
+                        bg: cell.bg,
+                    });
+                }
+            }
+            if start != mesh.len() as u32 {
+                ranges.push((layer_index, index, start..mesh.len() as u32));
+            }
+        }
+    }
+    (mesh, ranges)
+}
+
+/// Get the depth a layer is drawn at, from 1 for the lowest z to 0 for the
+/// highest, so that higher layers pass a `LessEqual` depth test over lower
+/// ones. z is clamped to the i16 range, whose steps a 16-bit depth buffer
+/// can still tell apart.
+fn layer_depth(z: i32) -> f32 {
+    let z = z.max(i32::from(std::i16::MIN)).min(i32::from(std::i16::MAX));
+    (i32::from(std::i16::MAX) - z) as f32 / std::u16::MAX as f32
+}
+
+/// Orthographic projection mapping the area covered by all layers to clip
+/// space, with a layer's offset and camera applied, at the depth of the
+/// layer's z.
+fn get_matrix_for_layer(bounds: (f32, f32), offset: (f32, f32), camera: &Camera, z: i32) -> [[f32; 4]; 4] {
+    let (width, height) = bounds;
+    let (camera_x, camera_y) = camera.position();
+    let zoom = camera.zoom();
+    [
+        [2.0 * zoom / width, 0.0, 0.0, 0.0],
+        [0.0, 2.0 * zoom / height, 0.0, 0.0],
+        [0.0, 0.0, 2.0, -1.0],
+        [
+            2.0 * (offset.0 - camera_x * zoom) / width - 1.0,
+            2.0 * (offset.1 - camera_y * zoom) / height - 1.0,
+            layer_depth(z),
+            1.0,
+        ],
+    ]
+}
+
+/// Draws a `LayerStack` as one textured quad per cell, with a projection per
+/// layer.
+pub struct MeshConsoleRenderer<B: gfx_hal::Backend> {
+    pub render_pass: B::RenderPass,
+    attachments: PassAttachments,
+    vert: ShaderHandle<B>,
+    frag: ShaderHandle<B>,
+    pipeline: B::GraphicsPipeline,
+    pipeline_layout: B::PipelineLayout,
+    ranges: Vec<DrawRange>,
+    vertex_buffer: Buffer<B>,
+    /// Shared with the renderers of other windows on the same device.
+    atlases: Rc<RefCell<Vec<Atlas<B>>>>,
+    info: AtlasInfo,
+    /// Font evictions when the mesh was last built. A renderer sharing the
+    /// font may evict glyphs this one draws, which forces a rebuild.
+    font_evictions: u64,
+    uniform_layout: B::DescriptorSetLayout,
+    texture_layout: B::DescriptorSetLayout,
+    desc_pool: B::DescriptorPool,
+    texture_sets: Vec<B::DescriptorSet>,
+    layer_uniforms: Vec<(Buffer<B>, B::DescriptorSet)>,
+    /// Projections last written to `layer_uniforms`.
+    projections: Vec<[[f32; 4]; 4]>,
+    /// Top-left corner and size of each visible layer in content pixels, to
+    /// scissor its draws.
+    layer_rects: Vec<((f32, f32), (f32, f32))>,
+    /// Size of the content the layer rects are measured in.
+    content: (f32, f32),
+}
+
+
+impl<B: gfx_hal::Backend> MeshConsoleRenderer<B> {
+    /// Load the renderer's shaders and atlases. With `offscreen` set the
+    /// layers are drawn into an offscreen target, for a `PostProcessChain`
+    /// to present.
+    pub unsafe fn new(
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+        config: &Config,
+        layers: &LayerStack,
+        offscreen: bool,
+    ) -> Self {
+        let mut atlases = MeshConsoleRenderer::load_tilesets(context, command_pool, config)
+            .into_iter()
+            .map(Atlas::Tiles)
+            .collect::<Vec<_>>();
+
+        // the font, if any, is bound after the tilesets and sets the cell size
+        let (font_atlas, cell_size) = match MeshConsoleRenderer::load_font(context, command_pool, config) {
+            Some(font) => {
+                let (width, height) = font.cell_size();
+                atlases.push(Atlas::Font(font));
+                (Some(atlases.len() - 1), (width as f32, height as f32))
+            }
+            None => {
+                let (width, height) = match &atlases[0] {
+                    Atlas::Tiles(tileset) => tileset.tile_size(),
+                    Atlas::Font(font) => font.cell_size(),
+                };
+                (None, (width as f32, height as f32))
+            }
+        };
+        let info = AtlasInfo::new(&atlases, font_atlas, cell_size);
+        MeshConsoleRenderer::with_atlases(context, config, layers, offscreen, Rc::new(RefCell::new(atlases)), info)
+    }
+
+    /// Create a renderer drawing with the atlases of `other`, on the same
+    /// device, instead of loading copies of them. The atlases are destroyed
+    /// with the last renderer using them.
+    pub unsafe fn sharing_atlases(
+        context: &Context<B>,
+        config: &Config,
+        layers: &LayerStack,
+        offscreen: bool,
+        other: &MeshConsoleRenderer<B>,
+    ) -> Self {
+        MeshConsoleRenderer::with_atlases(context, config, layers, offscreen, other.atlases.clone(), other.info.clone())
+    }
+
+    unsafe fn with_atlases(
+        context: &Context<B>,
+        config: &Config,
+        layers: &LayerStack,
+        offscreen: bool,
+        atlases: Rc<RefCell<Vec<Atlas<B>>>>,
+        info: AtlasInfo,
+    ) -> Self {
+        let (vert, frag) = MeshConsoleRenderer::load_shaders(context);
+        let depth_format = if config.graphics.depth && !offscreen && !context.kind().clears_window_depth() {
+            // layers would be tested against the last frame's depth
+            warn!("the {:?} backend cannot clear the window's depth buffer; drawing without one", context.kind());
+            None
+        } else if config.graphics.depth {
+            let format = context.pick_depth_format();
+            if format.is_none() {
+                warn!("no supported depth format; drawing without a depth buffer");
+            }
+            format
+        } else {
+            None
+        };
+        let attachments = PassAttachments {
+            offscreen,
+            depth: depth_format,
+            samples: context.pick_sample_count(config.graphics.samples, depth_format.is_some()),
+        };
+        let render_pass = MeshConsoleRenderer::build_render_pass(context, attachments);
+        let atlas_count = info.len();
+
+        // set 0 holds a layer's projection, set 1 an atlas
+        let uniform_layout = context.device.create_descriptor_set_layout(
+            &[DescriptorSetLayoutBinding {
+                binding: 0,
+                ty: DescriptorType::UniformBuffer,
+                count: 1,
+                stage_flags: ShaderStageFlags::VERTEX,
+                immutable_samplers: false,
+            }],
+            &[],
+        ).expect("Failed to create descriptor set layout!");
+        let texture_layout = context.device.create_descriptor_set_layout(
+            &[Texture::<B>::layout_binding(0)],
+            &[],
+        ).expect("Failed to create descriptor set layout!");
+
+        let mut desc_pool = context.device.create_descriptor_pool(
+            MAX_LAYERS + atlas_count, // maximum number of descriptor sets
+            &[
+                DescriptorRangeDesc {
+                    ty: DescriptorType::UniformBuffer,
+                    count: MAX_LAYERS // amount of space
+                },
+                DescriptorRangeDesc {
+                    ty: DescriptorType::CombinedImageSampler,
+                    count: atlas_count
+                },
+            ]
+        ).expect("Unable to create descriptor pool!");
+        let texture_sets = (0..atlas_count)
+            .map(|_| desc_pool.allocate_set(&texture_layout).unwrap())
+            .collect::<Vec<_>>();
+        for (desc_set, atlas) in texture_sets.iter().zip(atlases.borrow().iter()) {
+            context.device.write_descriptor_sets(vec![atlas.texture().descriptor_write(desc_set, 0)]);
+        }
+        let pipeline_layout = context.device
+            .create_pipeline_layout(vec![&uniform_layout, &texture_layout], &[])
+            .expect("Failed to create pipeline layout!");
+
+
+        // each layer's projection places its cells at the depth of its z,
+        // nearer for higher layers; layers sharing a z share a depth, and
+        // are drawn bottom to top so that later ones still draw over
+        let depth_stencil = match depth_format {
+            Some(_) => DepthStencilDesc {
+                depth: DepthTest::On {
+                    fun: Comparison::LessEqual,
+                    write: true,
+                },
+                depth_bounds: false,
+                stencil: StencilTest::Off,
+            },
+            None => DepthStencilDesc::default(),
+        };
+        let pipeline = MeshConsoleRenderer::build_pipeline(context, &vert, &frag, &render_pass, &pipeline_layout, depth_stencil, attachments.samples);
+        // the mesh and layer projections are built on the first call to update
+        let memory_types = &context.physical_device().memory_properties().memory_types;
+        let vertex_buffer = Buffer::new_empty::<Vertex>(
+            &context.device,
+            layers.cell_count().max(1) * 6,
+            &memory_types,
+            Properties::CPU_VISIBLE,
+            buffer::Usage::VERTEX,
+        ).expect("Unable to create vertex buffer!");
+        let font_evictions = MeshConsoleRenderer::font_evictions(&atlases.borrow(), info.font_atlas());
+        MeshConsoleRenderer {
+            vert,
+            frag,
+            render_pass,
+            attachments,
+            pipeline,
+            pipeline_layout,
+            ranges: Vec::new(),
+            vertex_buffer,
+            font_evictions,
+            info,
+            atlases,
+            uniform_layout,
+            texture_layout,
+            desc_pool,
+            texture_sets,
+            layer_uniforms: Vec::new(),
+            projections: Vec::new(),
+            layer_rects: Vec::new(),
+            content: (1.0, 1.0),
+        }
+    }
+
+    /// Load the configured tilesets. Atlases which fail to load are replaced
+    /// by a blank atlas so that atlas indices stay stable.
+    fn load_tilesets(
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+        config: &Config,
+    ) -> Vec<Tileset<B>> {
+        let mut tilesets = Vec::with_capacity(config.tilesets.len().max(1));
+        for tileset_config in &config.tilesets {
+            match Tileset::load(context, command_pool, tileset_config) {
+                Ok(tileset) => tilesets.push(tileset),
+                Err(err) => {
+                    warn!("Unable to load tileset {}: {}", tileset_config.path, err);
+                    tilesets.push(Tileset::blank(context, command_pool).expect("Unable to create blank tileset!"));
+                }
+            }
+        }
+        if tilesets.is_empty() {
+            tilesets.push(Tileset::blank(context, command_pool).expect("Unable to create blank tileset!"));
+        }
+        tilesets
+    }
+
+    /// Load the configured font, if any.
+    fn load_font(
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+        config: &Config,
+    ) -> Option<FontAtlas<B>> {
+        let font_config = config.font.as_ref()?;
+        FontAtlas::load(context, command_pool, font_config)
+            .map_err(|err| warn!("Unable to load font {}: {}", font_config.path, err))
+            .ok()
+    }
+
+    /// Upload any glyphs cached while building the mesh. Glyphs that fail to
+    /// upload are retried on the next update.
+    fn flush_font(
+        atlases: &mut [Atlas<B>],
+        font_atlas: Option<usize>,
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+    ) {
+        if let Some(Atlas::Font(font)) = font_atlas.map(|index| &mut atlases[index]) {
+            if let Err(err) = font.flush(context, command_pool) {
+                warn!("Unable to upload glyphs: {}", err);
+            }
+        }
+    }
+
+    /// Check if the font has glyphs left over from a failed upload.
+    fn font_has_pending(atlases: &[Atlas<B>], font_atlas: Option<usize>) -> bool {
+        match font_atlas.map(|index| &atlases[index]) {
+            Some(Atlas::Font(font)) => font.has_pending(),
+            _ => false,
+        }
+    }
+
+    /// Get the number of glyphs the font has evicted, or 0 without a font.
+    fn font_evictions(atlases: &[Atlas<B>], font_atlas: Option<usize>) -> u64 {
+        match font_atlas.map(|index| &atlases[index]) {
+            Some(Atlas::Font(font)) => font.evictions(),
+            _ => 0,
+        }
+    }
+
+    /// Get the (width, height) in pixels of the area covered by the layers.
+    pub fn content_size(&self, layers: &LayerStack) -> (f32, f32) {
+        let (width, height) = layers.bounds(self.info.cell_size());
+        (width.max(1.0), height.max(1.0))
+    }
+
+    /// Get the render pass's attachments besides its colour target, for
+    /// creating matching framebuffers.
+    pub fn attachments(&self) -> PassAttachments {
+        self.attachments
+    }
+
+    /// Get a description of the loaded atlases.
+    pub fn atlas_info(&self) -> &AtlasInfo {
+        &self.info
+    }
+
+    /// Get the default (width, height) of a cell in pixels.
+    pub fn cell_size(&self) -> (f32, f32) {
+        self.info.cell_size()
+    }
+
+    /// Rebuild the vertex buffer if any layer has changed or glyphs it drew
+    /// were evicted from the font, and update each layer's projection. Returns true if anything drawn has changed since
+    /// the last update.
+    ///
+    /// The buffers are written in place, so the last frame drawn with this
+    /// renderer must have finished on the GPU first.
+    pub fn update(
+        &mut self,
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+        layers: &mut LayerStack,
+    ) -> bool {
+        let mut atlases = self.atlases.borrow_mut();
+        let font_atlas = self.info.font_atlas();
+        let rebuilt = layers.is_dirty() || MeshConsoleRenderer::font_evictions(&atlases, font_atlas) != self.font_evictions;
+        if rebuilt {
+            let visible = layers.iter().filter(|(_, layer)| layer.is_visible()).count();
+            if visible > MAX_LAYERS {
+                warn!("{} visible layers, only the highest {} will be drawn", visible, MAX_LAYERS);
+            }
+            let (mesh, ranges) = build_mesh(layers, &mut atlases, self.info.cell_size());
+            self.font_evictions = MeshConsoleRenderer::font_evictions(&atlases, font_atlas);
+            MeshConsoleRenderer::flush_font(&mut atlases, font_atlas, context, command_pool);
+            if !self.vertex_buffer.can_hold(&mesh) {
+                self.vertex_buffer.destroy(&context.device);
+                let memory_types = &context.physical_device().memory_properties().memory_types;
+                self.vertex_buffer = Buffer::new_empty::<Vertex>(
+                    &context.device,
+                    mesh.len(),
+                    &memory_types,
+                    Properties::CPU_VISIBLE,
+                    buffer::Usage::VERTEX,
+                ).expect("Unable to create vertex buffer!");
+            }
+            if !mesh.is_empty() {
+                self.vertex_buffer.fill(&context.device, &mesh).expect("Unable to fill vertex buffer!");
+            }
+            self.ranges = ranges;
+            layers.mark_clean();
+        }
+        let retried = !rebuilt && MeshConsoleRenderer::font_has_pending(&atlases, font_atlas);
+        if retried {
+            MeshConsoleRenderer::flush_font(&mut atlases, font_atlas, context, command_pool);
+        }
+        drop(atlases);
+        self.update_projections(context, layers) || rebuilt || retried
+    }
+
+    /// Write each visible layer's projection into its uniform buffer,
+    /// creating uniform buffers and descriptor sets for new layers, and
+    /// update the rects layers are clipped to. Returns true if any projection
+    /// or rect has changed. Like the vertex buffer, the uniforms must no
+    /// longer be in use by the GPU.
+    fn update_projections(&mut self, context: &Context<B>, layers: &LayerStack) -> bool {
+        let bounds = self.content_size(layers);
+
+        let default_cell_size = self.info.cell_size();
+        let layer_rects = drawn_layers(layers)
+            .map(|layer| (layer.offset(), layer.pixel_size(default_cell_size)))
+            .collect::<Vec<_>>();
+        let clipping_changed = layer_rects != self.layer_rects || bounds != self.content;
+        self.layer_rects = layer_rects;
+        self.content = bounds;
+
+        let projections = drawn_layers(layers)
+            .map(|layer| get_matrix_for_layer(bounds, layer.offset(), &layer.camera, layer.z()))
+            .collect::<Vec<_>>();
+        if projections == self.projections {
+            return clipping_changed;
+        }
+        for (index, &projection) in projections.iter().enumerate() {
+            let block = UniformBlock { projection };
+            if index < self.layer_uniforms.len() {
+                self.layer_uniforms[index].0.fill(&context.device, &[block]).expect("Unable to fill uniform buffer!");
+                continue;
+            }
+
+            let memory_types = &context.physical_device().memory_properties().memory_types;
+            let uniform = Buffer::new_uniform(
+                &context.device,
+                &[block],
+                &memory_types,
+                Properties::CPU_VISIBLE
+            ).expect("Unable to create uniform buffer!");
+            let desc_set = unsafe {
+                let desc_set = self.desc_pool.allocate_set(&self.uniform_layout).unwrap();
+                context.device.write_descriptor_sets(vec![DescriptorSetWrite{
+                    set: &desc_set,
+                    binding: 0,
+                    array_offset: 0,
+                    descriptors: Some(Descriptor::Buffer(uniform.buffer.as_ref().unwrap(), None..None))
+                }]);
+                desc_set
+            };
+            self.layer_uniforms.push((uniform, desc_set));
+        }
+        self.projections = projections;
+        true
+    }
+
+    pub unsafe fn destroy(mut self, context: &Context<B>) {
+        context.device.destroy_graphics_pipeline(self.pipeline);
+        context.device.destroy_pipeline_layout(self.pipeline_layout);
+        context.device.destroy_descriptor_pool(self.desc_pool);
+        context.device.destroy_descriptor_set_layout(self.uniform_layout);
+        context.device.destroy_descriptor_set_layout(self.texture_layout);
+
+        self.vertex_buffer.destroy(&context.device);
+        for (uniform, _) in &mut self.layer_uniforms {
+            uniform.destroy(&context.device);
+        }
+        // the last renderer sharing the atlases destroys them
+        if let Ok(atlases) = Rc::try_unwrap(self.atlases) {
+            for mut atlas in atlases.into_inner() {
+                atlas.destroy(&context.device);
+            }
+        }
+        context.device.destroy_render_pass(self.render_pass);
+
+        self.vert.destroy(&context.device);
+        self.frag.destroy(&context.device);
+    }
+
+    pub fn load_shaders(context: &Context<B>) -> (ShaderHandle<B>, ShaderHandle<B>) {
+        #[cfg(os = "windows")]
+        let vert_path = "assets\\mesh.vert";
+        #[cfg(not(os = "windows"))]
+        let vert_path = "assets/mesh.vert";
+        let source = ShaderSource::from_glsl_path(vert_path).expect("Couldn't find fragment shader");
+        let vert = ShaderHandle::new(&context.device, source).expect("Failed to load fragment shader");
+        info!("loaded vertex shader");
+
+        #[cfg(os = "windows")]
+        let frag_path = "assets\\mesh.frag";
+        #[cfg(not(os = "windows"))]
+        let frag_path = "assets/mesh.frag";
+        let source = ShaderSource::from_glsl_path(frag_path).expect("Couldn't find vertex shader");
+        let frag = ShaderHandle::new(&context.device, source).expect("Failed to load vertex shader");
+        info!("loaded fragment shader");
+
+        (vert, frag)
+    }
+
+    /// Build the render pass drawing to the swapchain image, or an offscreen
+    /// image for post-processing, followed by the `PassAttachments` in order.
+    /// When multisampled, the pass draws to the multisampled attachment and
+    /// resolves it into the colour target.
+    pub fn build_render_pass(context: &Context<B>, attachments: PassAttachments) -> B::RenderPass {
+        let multisampled = attachments.is_multisampled();
+        let colour_attachment = Attachment {
+            format: Some(context.surface_colour_format),
+            samples: 1,
+            ops: if multisampled {
+                AttachmentOps::new(AttachmentLoadOp::DontCare, AttachmentStoreOp::Store)
+            } else {
+                AttachmentOps::new(AttachmentLoadOp::Clear, AttachmentStoreOp::Store)
+            },
+            stencil_ops: AttachmentOps::DONT_CARE,
+            // an offscreen target is moved on to its readers by the render graph
+            layouts: if attachments.offscreen {
+                Layout::Undefined..Layout::ColorAttachmentOptimal
+            } else {
+                Layout::Undefined..Layout::Present
+            },
+        };
+
+        let depth_attachment = attachments.depth.map(|format| Attachment {
+            format: Some(format),
+            samples: attachments.samples,
+            ops: AttachmentOps::new(AttachmentLoadOp::Clear, AttachmentStoreOp::DontCare),
+            stencil_ops: AttachmentOps::new(AttachmentLoadOp::Clear, AttachmentStoreOp::DontCare),
+            layouts: Layout::Undefined..Layout::DepthStencilAttachmentOptimal,
+        });
+
+        let multisampled_attachment = if multisampled {
+            Some(Attachment {
+                format: Some(context.surface_colour_format),
+                samples: attachments.samples,
+                ops: AttachmentOps::new(AttachmentLoadOp::Clear, AttachmentStoreOp::DontCare),
+                stencil_ops: AttachmentOps::DONT_CARE,
+                layouts: Layout::Undefined..Layout::ColorAttachmentOptimal,
+            })
+        } else {
+            None
+        };
+
+        let depth_ref = (1, Layout::DepthStencilAttachmentOptimal);
+        let multisampled_index = if depth_attachment.is_some() { 2 } else { 1 };
+        let (colors, resolves) = if multisampled {
+            (vec![(multisampled_index, Layout::ColorAttachmentOptimal)], vec![(0, Layout::ColorAttachmentOptimal)])
+        } else {
+            (vec![(0, Layout::ColorAttachmentOptimal)], Vec::new())
+        };
+        let subpass = SubpassDesc {
+            colors: &colors,
+            depth_stencil: depth_attachment.as_ref().map(|_| &depth_ref),
+            inputs: &[],
+            preserves: &[],
+            resolves: &resolves,
+        };
+
+        let mut stages = PipelineStage::COLOR_ATTACHMENT_OUTPUT;
+        let mut accesses = Access::COLOR_ATTACHMENT_READ | Access::COLOR_ATTACHMENT_WRITE;
+        if depth_attachment.is_some() {
+            stages |= PipelineStage::EARLY_FRAGMENT_TESTS;
+            accesses |= Access::DEPTH_STENCIL_ATTACHMENT_READ | Access::DEPTH_STENCIL_ATTACHMENT_WRITE;
+        }
+        let dependency = SubpassDependency {
+            passes: SubpassRef::External..SubpassRef::Pass(0),
+            stages: stages..stages,
+            accesses: Access::empty()..accesses,
+        };
+
+        let attachments = Some(colour_attachment)
+            .into_iter()
+            .chain(depth_attachment)
+            .chain(multisampled_attachment);
+        unsafe {
+            context.device
+                .create_render_pass(attachments, &[subpass], &[dependency])
+                .unwrap()
+        }
+    }
+
+    pub fn build_pipeline(context: &Context<B>, vert: &ShaderHandle<B>, frag: &ShaderHandle<B>, render_pass: &B::RenderPass, pipeline_layout: &B::PipelineLayout, depth_stencil: DepthStencilDesc, samples: u8) -> B::GraphicsPipeline {
+        let shader_entries = GraphicsShaderSet {
+            vertex: vert.entry_point("main").unwrap(),
+            hull: None,
+            domain: None,
+            geometry: None,
+            fragment: Some(frag.entry_point("main").unwrap()),
+        };
+
+        let subpass = Subpass {
+            index: 0,
+            main_pass: render_pass
+        };
+
+        let mut pipeline_desc = GraphicsPipelineDesc::new(shader_entries,
+                                                            Primitive::TriangleList,
+                                                            Rasterizer::FILL,
+                                                            &pipeline_layout,
+                                                            subpass);
+
+        pipeline_desc.blender
+                    .targets
+                    .push(ColorBlendDesc(ColorMask::ALL, BlendState::ALPHA));
+        pipeline_desc.depth_stencil = depth_stencil;
+        if samples > 1 {
+            pipeline_desc.multisampling = Some(Multisampling {
+                rasterization_samples: samples,
+                sample_shading: None,
+                sample_mask: !0,
+                alpha_coverage: false,
+                alpha_to_one: false,
+            });
+        }
+
+        pipeline_desc.vertex_buffers.push(VertexBufferDesc {
+            binding: 0,
+            stride: std::mem::size_of::<Vertex>() as u32,
+            rate: 0
+        });
+
+        pipeline_desc.attributes.push(AttributeDesc {
+            location: 0,
+            binding: 0,
+            element: Element {
+                format: Format::Rgb32Float,
+                offset: 0
+            }
+        });
+        pipeline_desc.attributes.push(AttributeDesc {
+            location: 1,
+            binding: 0,
+            element: Element {
+                format: Format::Rg32Float,
+                offset: 12
+            }
+        });
+        pipeline_desc.attributes.push(AttributeDesc {
+            location: 2,
+            binding: 0,
+            element: Element {
+                format: Format::Rgba32Float,
+                offset: 20
+            }
+        });
+        pipeline_desc.attributes.push(AttributeDesc {
+            location: 3,
+            binding: 0,
+            element: Element {
+                format: Format::Rgba32Float,
+                offset: 36
+            }
+        });
+        unsafe {
+            context.device.create_graphics_pipeline(&pipeline_desc, None)
+                .unwrap()
+        }
+    }
+
+    /// Clear values for each attachment of the render pass.
+    fn clear_values(&self) -> Vec<ClearValue> {
+        let clear_colour = ClearValue::Color(ClearColor::Float([0.0, 0.0, 0.0, 1.0]));
+        let mut values = vec![clear_colour];
+        if self.attachments.depth.is_some() {
+            values.push(ClearValue::DepthStencil(ClearDepthStencil(1.0, 0)));
+        }
+        if self.attachments.is_multisampled() {
+            values.push(clear_colour);
+        }
+        values
+    }
+
+    pub unsafe fn render<C, S>(&self,  command_buffer: &mut CommandBuffer<B, C, S>, framebuffers: &[B::Framebuffer], frame_index: u32, transform: &ViewportTransform)
+        where C: gfx_hal::queue::Supports<gfx_hal::queue::capability::Graphics>,
+              S: gfx_hal::command::Shot {
+        self.record(command_buffer, framebuffers, frame_index, transform);
+        command_buffer.finish();
+    }
+
+    /// Record the console's render pass without finishing the command
+    /// buffer, so that further passes can follow it.
+    pub unsafe fn record<C, S>(&self,  command_buffer: &mut CommandBuffer<B, C, S>, framebuffers: &[B::Framebuffer], frame_index: u32, transform: &ViewportTransform)
+        where C: gfx_hal::queue::Supports<gfx_hal::queue::capability::Graphics>,
+              S: gfx_hal::command::Shot {
+        command_buffer.set_viewports(0, &[transform.viewport()]);
+        command_buffer.set_scissors(0, &[transform.scissor()]);
+        command_buffer.bind_graphics_pipeline(&self.pipeline);
+        command_buffer.bind_vertex_buffers(0, vec![(self.vertex_buffer.buffer.as_ref().unwrap(), 0)]);
+
+        {
+            let mut encoder = command_buffer.begin_render_pass_inline(
+                &self.render_pass,
+                &framebuffers[frame_index as usize],
+                transform.target_rect(),
+                &self.clear_values(),
+            );
+
+            for (layer, atlas, range) in &self.ranges {
+                // keep a scrolled or zoomed layer within its own cells
+                let (origin, size) = self.layer_rects[*layer];
+                encoder.set_scissors(0, &[transform.content_scissor(origin, size, self.content)]);
+                let desc_sets = vec![&self.layer_uniforms[*layer].1, &self.texture_sets[*atlas]];
+                encoder.bind_graphics_descriptor_sets(&self.pipeline_layout, 0, desc_sets, &[]);
+                encoder.draw(range.clone(), 0..1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn higher_layers_are_nearer() {
+        assert_eq!(layer_depth(std::i16::MAX.into()), 0.0);
+        assert_eq!(layer_depth(std::i16::MIN.into()), 1.0);
+        assert!(layer_depth(1) < layer_depth(0));
+        assert!(layer_depth(0) < layer_depth(-1));
+        assert_eq!(layer_depth(std::i32::MAX), layer_depth(std::i16::MAX.into()));
+        assert_eq!(layer_depth(std::i32::MIN), 1.0);
+    }
+
+    #[test]
+    fn layer_depth_is_written_to_the_projection() {
+        let camera = Camera::default();
+        let low = get_matrix_for_layer((100.0, 100.0), (0.0, 0.0), &camera, -5);
+        let high = get_matrix_for_layer((100.0, 100.0), (0.0, 0.0), &camera, 5);
+        assert!(high[3][2] < low[3][2]);
+        assert_eq!(high[3][2], layer_depth(5));
+        assert_eq!((low[0], low[1], low[3][0], low[3][1]), (high[0], high[1], high[3][0], high[3][1]));
+    }
+
+    #[test]
+    fn the_lowest_layers_are_dropped_past_the_limit() {
+        let mut layers = LayerStack::default();
+        for z in 0..MAX_LAYERS as i32 + 2 {
+            layers.push(Layer::new(1, 1).with_z(z));
+        }
+        let hidden = layers.push(Layer::new(1, 1).with_z(100));
+        layers.get_mut(hidden).unwrap().set_visible(false);
+
+        let drawn = drawn_layers(&layers).map(|layer| layer.z()).collect::<Vec<_>>();
+        assert_eq!(drawn, (2..MAX_LAYERS as i32 + 2).collect::<Vec<_>>());
+    }
+}
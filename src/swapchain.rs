@@ -7,24 +7,34 @@ use crate::hal_prelude::*;
 
 use log::info;
 
-use crate::context::Context;
+use crate::context::{Context, SurfaceId};
+use crate::texture::TextureError;
 
 pub struct SwapchainState<B: gfx_hal::Backend> {
     pub swapchain: Option<B::Swapchain>,
     pub back_buffer: Option<gfx_hal::Backbuffer<B>>,
     pub extent: Extent,
+    surface: SurfaceId,
 }
 
 impl<B: gfx_hal::Backend> SwapchainState<B> {
-    pub fn new(backend: &mut Context<B>) -> Self {
-        let (caps, _, _, _) = backend.get_compatibility();
-        let swap_config = SwapchainConfig::from_caps(&caps, backend.surface_colour_format, Extent2D { height: 1024, width: 768 });
+    /// Create a swapchain. `extent` is the window's inner size in physical
+    /// pixels, used when the surface does not report its own size.
+    pub fn new(backend: &mut Context<B>, extent: Extent2D) -> Self {
+        SwapchainState::for_surface(backend, SurfaceId::PRIMARY, extent)
+    }
+
+    /// Create a swapchain for one of the context's other surfaces.
+    pub fn for_surface(backend: &mut Context<B>, surface: SurfaceId, extent: Extent2D) -> Self {
+        let (caps, _, _, _) = backend.get_surface_compatibility(surface);
+        let swap_config = SwapchainConfig::from_caps(&caps, backend.surface_colour_format, extent);
         let extent = swap_config.extent.to_extent();
-        let (swapchain, back_buffer) = backend.create_swapchain(swap_config, None);
+        let (swapchain, back_buffer) = backend.create_surface_swapchain(surface, swap_config, None);
         SwapchainState {
             swapchain: Some(swapchain),
             back_buffer: Some(back_buffer),
             extent,
+            surface,
         }
     }
 
@@ -33,13 +43,13 @@ impl<B: gfx_hal::Backend> SwapchainState<B> {
         self.swapchain.is_some()
     }
 
-    /// Rebuild the swapchain.
-    pub fn rebuild(&mut self, backend: &mut Context<B>) {
+    /// Rebuild the swapchain at the window's new size in physical pixels.
+    pub fn rebuild(&mut self, backend: &mut Context<B>, extent: Extent2D) {
         self.destroy(&backend.device);
-        let (caps, _, _, _) = backend.get_compatibility();
-        let swap_config = SwapchainConfig::from_caps(&caps, backend.surface_colour_format, Extent2D { height: 1024, width: 768 });
+        let (caps, _, _, _) = backend.get_surface_compatibility(self.surface);
+        let swap_config = SwapchainConfig::from_caps(&caps, backend.surface_colour_format, extent);
         let extent = swap_config.extent.to_extent();
-        let (swapchain, back_buffer) = backend.create_swapchain(swap_config, None);
+        let (swapchain, back_buffer) = backend.create_surface_swapchain(self.surface, swap_config, None);
         self.swapchain = Some(swapchain);
         self.back_buffer = Some(back_buffer);
         self.extent = extent;
@@ -54,35 +64,191 @@ impl<B: gfx_hal::Backend> SwapchainState<B> {
     }
 }
 
+/// The attachments of a render pass besides its colour target, which
+/// `FramebufferState` creates alongside each set of framebuffers. They
+/// follow the colour target in the order depth, then multisampled colour.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PassAttachments {
+    /// Whether the colour target is an offscreen image sampled by later
+    /// passes, rather than a swapchain image. The pass leaves it in
+    /// `ColorAttachmentOptimal` for a `RenderGraph` to transition.
+    pub offscreen: bool,
+    /// Format of the depth/stencil attachment, if there is one.
+    pub depth: Option<Format>,
+    /// Samples per pixel. Above 1, the pass draws to a multisampled colour
+    /// attachment which is resolved into the colour target.
+    pub samples: u8,
+}
+
+impl Default for PassAttachments {
+    fn default() -> Self {
+        PassAttachments {
+            offscreen: false,
+            depth: None,
+            samples: 1,
+        }
+    }
+}
+
+impl PassAttachments {
+    pub fn is_multisampled(&self) -> bool {
+        self.samples > 1
+    }
+}
+
+/// Image attached to every framebuffer of a swapchain, at the swapchain's
+/// size, such as a depth buffer.
+pub struct AttachmentImage<B: gfx_hal::Backend> {
+    image: B::Image,
+    memory: B::Memory,
+    view: B::ImageView,
+}
+
+impl<B: gfx_hal::Backend> AttachmentImage<B> {
+    pub fn new(
+        context: &Context<B>,
+        format: Format,
+        extent: Extent,
+        samples: u8,
+        usage: img::Usage,
+    ) -> Result<Self, TextureError> {
+        let device = &context.device;
+        let mut image = unsafe {
+            device.create_image(
+                img::Kind::D2(extent.width, extent.height, 1, samples),
+                1,
+                format,
+                img::Tiling::Optimal,
+                usage,
+                ViewCapabilities::empty(),
+            )?
+        };
+        let mem_req = unsafe { device.get_image_requirements(&image) };
+
+        let memory_types = context.physical_device().memory_properties().memory_types;
+        let device_type = memory_types
+            .iter()
+            .enumerate()
+            .find(|(id, ty)| {
+                let type_supported = mem_req.type_mask & (1_u64 << id) != 0;
+                type_supported && ty.properties.contains(Properties::DEVICE_LOCAL)
+            })
+            .map(|(id, _ty)| MemoryTypeId(id));
+        let device_type = match device_type {
+            Some(device_type) => device_type,
+            None => {
+                unsafe { device.destroy_image(image) };
+                return Err(TextureError::NoSuitableMemoryType);
+            }
+        };
+
+        let range = SubresourceRange {
+            aspects: format.surface_desc().aspects,
+            levels: 0..1,
+            layers: 0..1,
+        };
+        unsafe {
+            let memory = match device.allocate_memory(device_type, mem_req.size) {
+                Ok(memory) => memory,
+                Err(err) => {
+                    device.destroy_image(image);
+                    return Err(err.into());
+                }
+            };
+            if let Err(err) = device.bind_image_memory(&memory, 0, &mut image) {
+                device.destroy_image(image);
+                device.free_memory(memory);
+                return Err(err.into());
+            }
+            match device.create_image_view(&image, ViewKind::D2, format, Swizzle::NO, range) {
+                Ok(view) => Ok(AttachmentImage { image, memory, view }),
+                Err(err) => {
+                    device.destroy_image(image);
+                    device.free_memory(memory);
+                    Err(err.into())
+                }
+            }
+        }
+    }
+
+    pub fn image(&self) -> &B::Image {
+        &self.image
+    }
+
+    pub fn view(&self) -> &B::ImageView {
+        &self.view
+    }
+
+    pub fn destroy(self, device: &B::Device) {
+        unsafe {
+            device.destroy_image_view(self.view);
+            device.destroy_image(self.image);
+            device.free_memory(self.memory);
+        }
+    }
+}
+
 pub struct FramebufferState<B: gfx_hal::Backend> {
     framebuffers: Option<Vec<B::Framebuffer>>,
     image_views: Option<Vec<B::ImageView>>,
+    attachments: PassAttachments,
+    /// The colour target, when drawing offscreen.
+    target: Option<AttachmentImage<B>>,
+    depth: Option<AttachmentImage<B>>,
+    multisampled: Option<AttachmentImage<B>>,
 }
 
 impl<B: gfx_hal::Backend> FramebufferState<B> {
     pub fn new(
         context: &Context<B>,
         render_pass: &B::RenderPass,
+        attachments: PassAttachments,
         swap_state: &mut SwapchainState<B>,
-    ) -> Self {
-        let mut fbs = FramebufferState::new_empty();
-        fbs.rebuild_from_swapchain(context, render_pass, swap_state);
-        fbs
+    ) -> Result<Self, TextureError> {
+        let mut fbs = FramebufferState::new_empty(attachments);
+        fbs.rebuild_from_swapchain(context, render_pass, swap_state)?;
+        Ok(fbs)
     }
 
-    pub fn new_empty() -> Self {
+    pub fn new_empty(attachments: PassAttachments) -> Self {
         FramebufferState {
             framebuffers: None,
             image_views: None,
+            attachments,
+            target: None,
+            depth: None,
+            multisampled: None,
         }
     }
 
+    /// Rebuild as a single framebuffer drawing into an offscreen colour
+    /// image of the given size, which later passes can sample through
+    /// `target_view`. Nothing is left allocated if an image cannot be
+    /// created.
+    pub fn rebuild_offscreen(&mut self, context: &Context<B>, render_pass: &B::RenderPass, extent: Extent) -> Result<(), TextureError> {
+        let usage = img::Usage::COLOR_ATTACHMENT | img::Usage::SAMPLED;
+        let target = AttachmentImage::new(context, context.surface_colour_format, extent, 1, usage)?;
+        if let Err(err) = self.create_attachments(context, extent) {
+            target.destroy(&context.device);
+            return Err(err);
+        }
+        let framebuffers = context
+            .image_views_to_fbos(std::slice::from_ref(target.view()), &self.extra_views(), &render_pass, extent)
+            .unwrap();
+        self.framebuffers = Some(framebuffers);
+        self.image_views = Some(Vec::new());
+        self.target = Some(target);
+        Ok(())
+    }
+
+    /// Rebuild as one framebuffer per swapchain image. Nothing is left
+    /// allocated if an attachment cannot be created.
     pub fn rebuild_from_swapchain(
         &mut self,
         context: &Context<B>,
         render_pass: &B::RenderPass,
         swap_state: &mut SwapchainState<B>,
-    ) {
+    ) -> Result<(), TextureError> {
         let (image_views, framebuffers) = match swap_state.back_buffer.take().unwrap() {
             Backbuffer::Images(images) => {
                 let color_range = SubresourceRange {
@@ -94,16 +260,72 @@ impl<B: gfx_hal::Backend> FramebufferState<B> {
                 let image_views = context
                     .map_to_image_views(&images, ViewKind::D2, Swizzle::NO, color_range)
                     .unwrap();
+                if let Err(err) = self.create_attachments(context, swap_state.extent) {
+                    for image_view in image_views {
+                        unsafe { context.device.destroy_image_view(image_view) };
+                    }
+                    return Err(err);
+                }
                 let fbos = context
-                    .image_views_to_fbos(&image_views, &render_pass, swap_state.extent)
+                    .image_views_to_fbos(&image_views, &self.extra_views(), &render_pass, swap_state.extent)
                     .unwrap();
 
                 (image_views, fbos)
             }
+            // the GL default framebuffer brings its own multisampling, and a
+            // depth buffer the renderer leaves off as it is never cleared
             Backbuffer::Framebuffer(fbo) => (Vec::new(), vec![fbo]),
         };
         self.framebuffers = Some(framebuffers);
         self.image_views = Some(image_views);
+        Ok(())
+    }
+
+    /// Create the depth and multisampled images, as the attachments ask,
+    /// or neither of them.
+    fn create_attachments(&mut self, context: &Context<B>, extent: Extent) -> Result<(), TextureError> {
+        let samples = self.attachments.samples;
+        if let Some(format) = self.attachments.depth {
+            let usage = img::Usage::DEPTH_STENCIL_ATTACHMENT;
+            self.depth = Some(AttachmentImage::new(context, format, extent, samples, usage)?);
+        }
+        if self.attachments.is_multisampled() {
+            let format = context.surface_colour_format;
+            let usage = img::Usage::COLOR_ATTACHMENT | img::Usage::TRANSIENT_ATTACHMENT;
+            match AttachmentImage::new(context, format, extent, samples, usage) {
+                Ok(image) => self.multisampled = Some(image),
+                Err(err) => {
+                    if let Some(depth) = self.depth.take() {
+                        depth.destroy(&context.device);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Views of the images attached after the colour target, in order.
+    fn extra_views(&self) -> Vec<&B::ImageView> {
+        self.depth
+            .iter()
+            .chain(&self.multisampled)
+            .map(AttachmentImage::view)
+            .collect()
+    }
+
+    /// Get the offscreen colour target, if drawing offscreen.
+    pub fn target_image(&self) -> Option<&B::Image> {
+        self.target.as_ref().map(AttachmentImage::image)
+    }
+
+    /// Get the view of the offscreen colour target, if drawing offscreen.
+    pub fn target_view(&self) -> Option<&B::ImageView> {
+        self.target.as_ref().map(AttachmentImage::view)
+    }
+
+    pub fn framebuffers(&self) -> &[B::Framebuffer] {
+        self.framebuffers.as_ref().map_or(&[], Vec::as_slice)
     }
 
     pub fn is_some(&self) -> bool {
@@ -136,5 +358,14 @@ impl<B: gfx_hal::Backend> FramebufferState<B> {
                 }
             }
         }
+        if let Some(target) = self.target.take() {
+            target.destroy(device);
+        }
+        if let Some(depth) = self.depth.take() {
+            depth.destroy(device);
+        }
+        if let Some(multisampled) = self.multisampled.take() {
+            multisampled.destroy(device);
+        }
     }
 }
@@ -0,0 +1,775 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::path::Path;
+
+use gfx_hal::queue::capability::Graphics;
+
+use crate::buffer::{Buffer, BufferError};
+use crate::context::Context;
+use crate::hal_prelude::*;
+
+use log::{info, warn};
+
+#[derive(Debug)]
+pub enum TextureError {
+    AllocationError(gfx_hal::device::AllocationError),
+    BindError(gfx_hal::device::BindError),
+    BufferError(BufferError),
+    CreationError(gfx_hal::image::CreationError),
+    DecodeError(::image::ImageError),
+    OutOfMemory(gfx_hal::device::OutOfMemory),
+    UploadError(gfx_hal::device::OomOrDeviceLost),
+    ViewError(ViewError),
+    NoSuitableMemoryType,
+    /// The pixel data is not `width * height * 4` bytes long.
+    SizeMismatch { expected: usize, actual: usize },
+    /// The image is too large for its byte size to be addressed.
+    SizeOverflow,
+    /// The image, or a tile of it, has a width or height of zero.
+    ZeroSize,
+    /// A region does not fit within the texture.
+    RegionOutOfBounds,
+    /// Regions can only be written to textures without mipmaps.
+    HasMipmaps,
+}
+
+impl Error for TextureError {}
+impl Display for TextureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TextureError::NoSuitableMemoryType => {
+                write!(f, "Could not find appropriate image memory type.")
+            }
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+macro_rules! wrap_tex_error {
+    ($src: ty, $dst: ident) => {
+        impl From<$src> for TextureError {
+            fn from(err: $src) -> Self {
+                TextureError::$dst(err)
+            }
+        }
+    };
+}
+
+wrap_tex_error!(gfx_hal::device::AllocationError, AllocationError);
+wrap_tex_error!(gfx_hal::device::BindError, BindError);
+wrap_tex_error!(BufferError, BufferError);
+wrap_tex_error!(gfx_hal::image::CreationError, CreationError);
+wrap_tex_error!(::image::ImageError, DecodeError);
+wrap_tex_error!(gfx_hal::device::OutOfMemory, OutOfMemory);
+wrap_tex_error!(gfx_hal::device::OomOrDeviceLost, UploadError);
+wrap_tex_error!(ViewError, ViewError);
+
+/// Options controlling how a texture is uploaded and sampled.
+#[derive(Clone, Copy, Debug)]
+pub struct TextureOptions {
+    pub format: Format,
+    pub filter: Filter,
+    pub wrap: WrapMode,
+    pub mipmaps: bool,
+}
+
+impl Default for TextureOptions {
+    fn default() -> Self {
+        TextureOptions {
+            format: Format::Rgba8Srgb,
+            filter: Filter::Nearest,
+            wrap: WrapMode::Clamp,
+            mipmaps: false,
+        }
+    }
+}
+
+/// Number of bytes of tightly packed RGBA8 data in an image of the given
+/// size.
+pub fn rgba_len(width: u32, height: u32) -> Result<usize, TextureError> {
+    (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|len| len.checked_mul(4))
+        .ok_or(TextureError::SizeOverflow)
+}
+
+/// Check that an image of the given size has at least one texel.
+fn check_size(width: u32, height: u32) -> Result<(), TextureError> {
+    if width == 0 || height == 0 {
+        return Err(TextureError::ZeroSize);
+    }
+    Ok(())
+}
+
+/// Check that `pixels` holds exactly one image of the given size.
+fn check_rgba_len(width: u32, height: u32, pixels: &[u8]) -> Result<(), TextureError> {
+    let expected = rgba_len(width, height)?;
+    if pixels.len() != expected {
+        return Err(TextureError::SizeMismatch {
+            expected,
+            actual: pixels.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Round `value` up to a multiple of `alignment`.
+fn align_up(value: usize, alignment: usize) -> Result<usize, TextureError> {
+    let alignment = alignment.max(1);
+    value
+        .checked_add(alignment - 1)
+        .map(|value| value / alignment * alignment)
+        .ok_or(TextureError::SizeOverflow)
+}
+
+/// Lay out images of the given sizes one after another in a staging buffer,
+/// starting each at a multiple of `offset_alignment` and padding each row to
+/// a multiple of `pitch_alignment`. Returns the (offset, row pitch) of each
+/// image and the total length in bytes.
+fn staging_layout(
+    sizes: &[(u32, u32)],
+    pitch_alignment: usize,
+    offset_alignment: usize,
+) -> Result<(Vec<(usize, usize)>, usize), TextureError> {
+    // copies are given the pitch in texels, so it must be a multiple of 4
+    let pitch_alignment = align_up(pitch_alignment.max(4), 4)?;
+    let offset_alignment = align_up(offset_alignment.max(4), 4)?;
+    let mut placements = Vec::with_capacity(sizes.len());
+    let mut len = 0;
+    for &(width, height) in sizes {
+        let offset = align_up(len, offset_alignment)?;
+        let row_pitch = align_up(rgba_len(width, 1)?, pitch_alignment)?;
+        if row_pitch > std::u32::MAX as usize {
+            return Err(TextureError::SizeOverflow);
+        }
+        len = row_pitch
+            .checked_mul(height as usize)
+            .and_then(|size| size.checked_add(offset))
+            .ok_or(TextureError::SizeOverflow)?;
+        placements.push((offset, row_pitch));
+    }
+    Ok((placements, len))
+}
+
+/// A rectangle of tightly packed RGBA8 pixel data to write into a texture.
+#[derive(Clone, Copy, Debug)]
+pub struct TextureRegion<'a> {
+    pub offset: (u32, u32),
+    pub size: (u32, u32),
+    pub pixels: &'a [u8],
+}
+
+/// Number of mip levels in a full chain for an image of the given size.
+pub fn mip_levels_for(width: u32, height: u32) -> img::Level {
+    (32 - width.max(height).max(1).leading_zeros()) as img::Level
+}
+
+fn colour_range(levels: std::ops::Range<img::Level>) -> SubresourceRange {
+    SubresourceRange {
+        aspects: Aspects::COLOR,
+        levels,
+        layers: 0..1,
+    }
+}
+
+fn colour_layers(level: img::Level) -> SubresourceLayers {
+    SubresourceLayers {
+        aspects: Aspects::COLOR,
+        level,
+        layers: 0..1,
+    }
+}
+
+/// Sampled image, with its backing memory, view and sampler.
+pub struct Texture<B: gfx_hal::Backend> {
+    pub image: Option<B::Image>,
+    pub memory: Option<B::Memory>,
+    pub view: Option<B::ImageView>,
+    pub sampler: Option<B::Sampler>,
+    extent: Extent,
+    mip_levels: img::Level,
+    format: Format,
+}
+
+impl<B: gfx_hal::Backend> Texture<B> {
+    /// Load a PNG or BMP from disk and upload it to a new texture.
+    pub fn from_path<P: AsRef<Path>>(
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+        path: P,
+        options: TextureOptions,
+    ) -> Result<Self, TextureError> {
+        let pixels = ::image::open(path.as_ref())?.to_rgba();
+        info!("loaded texture {}", path.as_ref().display());
+        let (width, height) = pixels.dimensions();
+        Texture::from_rgba(context, command_pool, width, height, &pixels.into_raw(), options)
+    }
+
+    /// Decode an in-memory PNG or BMP and upload it to a new texture.
+    pub fn from_memory(
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+        bytes: &[u8],
+        options: TextureOptions,
+    ) -> Result<Self, TextureError> {
+        let pixels = ::image::load_from_memory(bytes)?.to_rgba();
+        let (width, height) = pixels.dimensions();
+        Texture::from_rgba(context, command_pool, width, height, &pixels.into_raw(), options)
+    }
+
+    /// Create a texture from tightly packed RGBA8 pixel data, uploading it
+    /// through a staging buffer and transitioning it for shader reads.
+    pub fn from_rgba(
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+        options: TextureOptions,
+    ) -> Result<Self, TextureError> {
+        check_rgba_len(width, height, pixels)?;
+        let mip_levels = if options.mipmaps && Texture::<B>::can_generate_mipmaps(context, options.format) {
+            mip_levels_for(width, height)
+        } else {
+            if options.mipmaps {
+                warn!("{:?} does not support linear blits, skipping mipmap generation", options.format);
+            }
+            1
+        };
+
+        let (image, memory) = Texture::<B>::create_image(context, width, height, mip_levels, options.format)?;
+        let mut texture = Texture {
+            image: Some(image),
+            memory: Some(memory),
+            view: None,
+            sampler: None,
+            extent: Extent { width, height, depth: 1 },
+            mip_levels,
+            format: options.format,
+        };
+
+        match texture.init(context, command_pool, pixels, options) {
+            Ok(()) => Ok(texture),
+            Err(err) => {
+                texture.destroy(&context.device);
+                Err(err)
+            }
+        }
+    }
+
+    /// Upload the pixels and create the view and sampler of a new texture.
+    fn init(
+        &mut self,
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+        pixels: &[u8],
+        options: TextureOptions,
+    ) -> Result<(), TextureError> {
+        self.upload(context, command_pool, pixels)?;
+
+        let device = &context.device;
+        let view = unsafe {
+            device.create_image_view(
+                self.image.as_ref().unwrap(),
+                ViewKind::D2,
+                options.format,
+                Swizzle::NO,
+                colour_range(0..self.mip_levels),
+            )?
+        };
+        self.view = Some(view);
+
+        let sampler = unsafe { device.create_sampler(img::SamplerInfo::new(options.filter, options.wrap))? };
+        self.sampler = Some(sampler);
+        Ok(())
+    }
+
+    /// Check whether the format supports the blits used to build a mip chain.
+    fn can_generate_mipmaps(context: &Context<B>, format: Format) -> bool {
+        let required = ImageFeature::BLIT_SRC | ImageFeature::BLIT_DST | ImageFeature::SAMPLED_LINEAR;
+        context
+            .physical_device()
+            .format_properties(Some(format))
+            .optimal_tiling
+            .contains(required)
+    }
+
+    fn create_image(
+        context: &Context<B>,
+        width: u32,
+        height: u32,
+        mip_levels: img::Level,
+        format: Format,
+    ) -> Result<(B::Image, B::Memory), TextureError> {
+        check_size(width, height)?;
+        let device = &context.device;
+        let mut usage = img::Usage::TRANSFER_DST | img::Usage::SAMPLED;
+        if mip_levels > 1 {
+            usage |= img::Usage::TRANSFER_SRC;
+        }
+
+        let (mut unbound_image, mem_req) = unsafe {
+            let unbound_image = device.create_image(
+                img::Kind::D2(width, height, 1, 1),
+                mip_levels,
+                format,
+                img::Tiling::Optimal,
+                usage,
+                ViewCapabilities::empty(),
+            )?;
+            let mem_req = device.get_image_requirements(&unbound_image);
+            (unbound_image, mem_req)
+        };
+
+        let memory_types = context.physical_device().memory_properties().memory_types;
+        let device_type = memory_types
+            .iter()
+            .enumerate()
+            .find(|(id, ty)| {
+                let type_supported = mem_req.type_mask & (1_u64 << id) != 0;
+                type_supported && ty.properties.contains(Properties::DEVICE_LOCAL)
+            })
+            .map(|(id, _ty)| MemoryTypeId(id));
+        let device_type = match device_type {
+            Some(device_type) => device_type,
+            None => {
+                unsafe { device.destroy_image(unbound_image) };
+                return Err(TextureError::NoSuitableMemoryType);
+            }
+        };
+
+        unsafe {
+            let image_memory = match device.allocate_memory(device_type, mem_req.size) {
+                Ok(image_memory) => image_memory,
+                Err(err) => {
+                    device.destroy_image(unbound_image);
+                    return Err(err.into());
+                }
+            };
+            if let Err(err) = device.bind_image_memory(&image_memory, 0, &mut unbound_image) {
+                device.destroy_image(unbound_image);
+                device.free_memory(image_memory);
+                return Err(err.into());
+            }
+            Ok((unbound_image, image_memory))
+        }
+    }
+
+    /// Copy pixel data into level 0 via a staging buffer, then either build
+    /// the mip chain or transition the image straight to shader-readable.
+    fn upload(
+        &self,
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+        pixels: &[u8],
+    ) -> Result<(), TextureError> {
+        let (mut staging, placements) = Texture::stage(context, &[((self.extent.width, self.extent.height), pixels)])?;
+        let (_, row_pitch) = placements[0];
+
+        let image = self.image.as_ref().unwrap();
+        let mut cmd_buffer = command_pool.acquire_command_buffer::<OneShot>();
+        unsafe {
+            cmd_buffer.begin();
+
+            let barrier = Barrier::Image {
+                states: (Access::empty(), Layout::Undefined)
+                    ..(Access::TRANSFER_WRITE, Layout::TransferDstOptimal),
+                target: image,
+                families: None,
+                range: colour_range(0..self.mip_levels),
+            };
+            cmd_buffer.pipeline_barrier(
+                PipelineStage::TOP_OF_PIPE..PipelineStage::TRANSFER,
+                Dependencies::empty(),
+                &[barrier],
+            );
+
+            cmd_buffer.copy_buffer_to_image(
+                staging.buffer.as_ref().unwrap(),
+                image,
+                Layout::TransferDstOptimal,
+                &[BufferImageCopy {
+                    buffer_offset: 0,
+                    buffer_width: row_pitch / 4,
+                    buffer_height: self.extent.height,
+                    image_layers: colour_layers(0),
+                    image_offset: Offset::ZERO,
+                    image_extent: self.extent,
+                }],
+            );
+
+            if self.mip_levels > 1 {
+                self.record_mipmaps(&mut cmd_buffer);
+            } else {
+                let barrier = Barrier::Image {
+                    states: (Access::TRANSFER_WRITE, Layout::TransferDstOptimal)
+                        ..(Access::SHADER_READ, Layout::ShaderReadOnlyOptimal),
+                    target: image,
+                    families: None,
+                    range: colour_range(0..1),
+                };
+                cmd_buffer.pipeline_barrier(
+                    PipelineStage::TRANSFER..PipelineStage::FRAGMENT_SHADER,
+                    Dependencies::empty(),
+                    &[barrier],
+                );
+            }
+
+            cmd_buffer.finish();
+        }
+
+        let result = unsafe { Texture::submit_and_wait(context, command_pool, cmd_buffer) };
+        staging.destroy(&context.device);
+        result
+    }
+
+    /// Replace a region of a single-level texture with tightly packed RGBA8
+    /// pixel data. The texture is left in `ShaderReadOnlyOptimal`.
+    pub fn write_region(
+        &self,
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+        offset: (u32, u32),
+        size: (u32, u32),
+        pixels: &[u8],
+    ) -> Result<(), TextureError> {
+        self.write_regions(context, command_pool, &[TextureRegion { offset, size, pixels }])
+    }
+
+    /// Replace several regions of a single-level texture through one staging
+    /// buffer and a single submission. The texture is left in
+    /// `ShaderReadOnlyOptimal`.
+    pub fn write_regions(
+        &self,
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+        regions: &[TextureRegion],
+    ) -> Result<(), TextureError> {
+        if regions.is_empty() {
+            return Ok(());
+        }
+        if self.mip_levels != 1 {
+            return Err(TextureError::HasMipmaps);
+        }
+        let fits = |offset: u32, size: u32, extent: u32| offset.checked_add(size).map_or(false, |end| end <= extent);
+        for region in regions {
+            let (offset, size) = (region.offset, region.size);
+            if !fits(offset.0, size.0, self.extent.width) || !fits(offset.1, size.1, self.extent.height) {
+                return Err(TextureError::RegionOutOfBounds);
+            }
+            check_rgba_len(size.0, size.1, region.pixels)?;
+        }
+        let images: Vec<_> = regions.iter().map(|region| (region.size, region.pixels)).collect();
+        let (mut staging, placements) = Texture::stage(context, &images)?;
+
+        let copies: Vec<_> = regions
+            .iter()
+            .zip(&placements)
+            .map(|(region, &(buffer_offset, row_pitch))| BufferImageCopy {
+                buffer_offset: buffer_offset as buffer::Offset,
+                buffer_width: row_pitch / 4,
+                buffer_height: region.size.1,
+                image_layers: colour_layers(0),
+                image_offset: Offset {
+                    x: region.offset.0 as i32,
+                    y: region.offset.1 as i32,
+                    z: 0,
+                },
+                image_extent: Extent {
+                    width: region.size.0,
+                    height: region.size.1,
+                    depth: 1,
+                },
+            })
+            .collect();
+
+        let image = self.image.as_ref().unwrap();
+        let mut cmd_buffer = command_pool.acquire_command_buffer::<OneShot>();
+        unsafe {
+            cmd_buffer.begin();
+
+            let barrier = Barrier::Image {
+                states: (Access::SHADER_READ, Layout::ShaderReadOnlyOptimal)
+                    ..(Access::TRANSFER_WRITE, Layout::TransferDstOptimal),
+                target: image,
+                families: None,
+                range: colour_range(0..1),
+            };
+            cmd_buffer.pipeline_barrier(
+                PipelineStage::FRAGMENT_SHADER..PipelineStage::TRANSFER,
+                Dependencies::empty(),
+                &[barrier],
+            );
+
+            cmd_buffer.copy_buffer_to_image(
+                staging.buffer.as_ref().unwrap(),
+                image,
+                Layout::TransferDstOptimal,
+                &copies,
+            );
+
+            let barrier = Barrier::Image {
+                states: (Access::TRANSFER_WRITE, Layout::TransferDstOptimal)
+                    ..(Access::SHADER_READ, Layout::ShaderReadOnlyOptimal),
+                target: image,
+                families: None,
+                range: colour_range(0..1),
+            };
+            cmd_buffer.pipeline_barrier(
+                PipelineStage::TRANSFER..PipelineStage::FRAGMENT_SHADER,
+                Dependencies::empty(),
+                &[barrier],
+            );
+
+            cmd_buffer.finish();
+        }
+
+        let result = unsafe { Texture::submit_and_wait(context, command_pool, cmd_buffer) };
+        staging.destroy(&context.device);
+        result
+    }
+
+    /// Copy the pixel data of one or more images of the given sizes into a
+    /// new staging buffer, padding each row to the device's copy pitch
+    /// alignment. Returns the buffer and the (offset, row pitch) of each image.
+    fn stage(
+        context: &Context<B>,
+        images: &[((u32, u32), &[u8])],
+    ) -> Result<(Buffer<B>, Vec<(usize, u32)>), TextureError> {
+        let limits = context.physical_device().limits();
+        let sizes: Vec<_> = images.iter().map(|&(size, _)| size).collect();
+        let (placements, staged_len) = staging_layout(
+            &sizes,
+            limits.min_buffer_copy_pitch_alignment as usize,
+            limits.min_buffer_copy_offset_alignment as usize,
+        )?;
+
+        let mut staged = Vec::with_capacity(staged_len);
+        for (&((width, _), pixels), &(offset, row_pitch)) in images.iter().zip(&placements) {
+            staged.resize(offset, 0);
+            let row_size = width as usize * 4;
+            for row in pixels.chunks(row_size.max(1)) {
+                staged.extend_from_slice(row);
+                staged.resize(staged.len() + (row_pitch - row_size), 0);
+            }
+        }
+
+        let memory_types = context.physical_device().memory_properties().memory_types;
+        let staging = Buffer::new(
+            &context.device,
+            &staged,
+            &memory_types,
+            Properties::CPU_VISIBLE,
+            buffer::Usage::TRANSFER_SRC,
+        )?;
+        let placements = placements
+            .into_iter()
+            .map(|(offset, row_pitch)| (offset, row_pitch as u32))
+            .collect();
+        Ok((staging, placements))
+    }
+
+    /// Submit a finished transfer command buffer and block until it
+    /// completes. The command buffer is freed whether or not this succeeds.
+    unsafe fn submit_and_wait(
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+        cmd_buffer: CommandBuffer<B, Graphics, OneShot>,
+    ) -> Result<(), TextureError> {
+        let fence = match context.device.create_fence(false) {
+            Ok(fence) => fence,
+            Err(err) => {
+                command_pool.free(Some(cmd_buffer));
+                return Err(err.into());
+            }
+        };
+        context.queue_group.queues[0].submit_nosemaphores(Some(&cmd_buffer), Some(&fence));
+        let result = context.device.wait_for_fence(&fence, !0);
+        context.device.destroy_fence(fence);
+        command_pool.free(Some(cmd_buffer));
+        result.map(|_| ()).map_err(TextureError::from)
+    }
+
+    /// Record blits from each mip level into the next, leaving every level in
+    /// `ShaderReadOnlyOptimal`.
+    unsafe fn record_mipmaps(&self, cmd_buffer: &mut CommandBuffer<B, Graphics, OneShot>) {
+        let image = self.image.as_ref().unwrap();
+        let mut src_width = self.extent.width as i32;
+        let mut src_height = self.extent.height as i32;
+
+        for level in 1..self.mip_levels {
+            let dst_width = (src_width / 2).max(1);
+            let dst_height = (src_height / 2).max(1);
+
+            let barrier = Barrier::Image {
+                states: (Access::TRANSFER_WRITE, Layout::TransferDstOptimal)
+                    ..(Access::TRANSFER_READ, Layout::TransferSrcOptimal),
+                target: image,
+                families: None,
+                range: colour_range(level - 1..level),
+            };
+            cmd_buffer.pipeline_barrier(
+                PipelineStage::TRANSFER..PipelineStage::TRANSFER,
+                Dependencies::empty(),
+                &[barrier],
+            );
+
+            cmd_buffer.blit_image(
+                image,
+                Layout::TransferSrcOptimal,
+                image,
+                Layout::TransferDstOptimal,
+                Filter::Linear,
+                &[ImageBlit {
+                    src_subresource: colour_layers(level - 1),
+                    src_bounds: Offset::ZERO..Offset { x: src_width, y: src_height, z: 1 },
+                    dst_subresource: colour_layers(level),
+                    dst_bounds: Offset::ZERO..Offset { x: dst_width, y: dst_height, z: 1 },
+                }],
+            );
+
+            let barrier = Barrier::Image {
+                states: (Access::TRANSFER_READ, Layout::TransferSrcOptimal)
+                    ..(Access::SHADER_READ, Layout::ShaderReadOnlyOptimal),
+                target: image,
+                families: None,
+                range: colour_range(level - 1..level),
+            };
+            cmd_buffer.pipeline_barrier(
+                PipelineStage::TRANSFER..PipelineStage::FRAGMENT_SHADER,
+                Dependencies::empty(),
+                &[barrier],
+            );
+
+            src_width = dst_width;
+            src_height = dst_height;
+        }
+
+        let last = self.mip_levels - 1;
+        let barrier = Barrier::Image {
+            states: (Access::TRANSFER_WRITE, Layout::TransferDstOptimal)
+                ..(Access::SHADER_READ, Layout::ShaderReadOnlyOptimal),
+            target: image,
+            families: None,
+            range: colour_range(last..last + 1),
+        };
+        cmd_buffer.pipeline_barrier(
+            PipelineStage::TRANSFER..PipelineStage::FRAGMENT_SHADER,
+            Dependencies::empty(),
+            &[barrier],
+        );
+    }
+
+    /// Get the size of the base mip level.
+    pub fn extent(&self) -> Extent {
+        self.extent
+    }
+
+    /// Get the number of mip levels in the image.
+    pub fn mip_levels(&self) -> img::Level {
+        self.mip_levels
+    }
+
+    /// Get the format the image was created with.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Layout binding for this texture as a combined image sampler.
+    pub fn layout_binding(binding: u32) -> DescriptorSetLayoutBinding {
+        DescriptorSetLayoutBinding {
+            binding,
+            ty: DescriptorType::CombinedImageSampler,
+            count: 1,
+            stage_flags: ShaderStageFlags::FRAGMENT,
+            immutable_samplers: false,
+        }
+    }
+
+    /// Descriptor write binding this texture as a combined image sampler.
+    pub fn descriptor_write<'a>(
+        &'a self,
+        set: &'a B::DescriptorSet,
+        binding: u32,
+    ) -> DescriptorSetWrite<'a, B, Option<Descriptor<'a, B>>> {
+        DescriptorSetWrite {
+            set,
+            binding,
+            array_offset: 0,
+            descriptors: Some(Descriptor::CombinedImageSampler(
+                self.view.as_ref().unwrap(),
+                Layout::ShaderReadOnlyOptimal,
+                self.sampler.as_ref().unwrap(),
+            )),
+        }
+    }
+
+    /// Destroy the texture.
+    pub fn destroy(&mut self, device: &B::Device) {
+        if let Some(sampler) = self.sampler.take() {
+            unsafe { device.destroy_sampler(sampler) };
+        }
+        if let Some(view) = self.view.take() {
+            unsafe { device.destroy_image_view(view) };
+        }
+        if let Some(image) = self.image.take() {
+            unsafe { device.destroy_image(image) };
+        }
+        if let Some(memory) = self.memory.take() {
+            unsafe { device.free_memory(memory) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rgba_len_counts_four_bytes_per_texel() {
+        assert_eq!(rgba_len(3, 2).unwrap(), 24);
+        assert_eq!(rgba_len(0, 100).unwrap(), 0);
+    }
+
+    #[test]
+    fn rgba_len_rejects_overflow() {
+        match rgba_len(std::u32::MAX, std::u32::MAX) {
+            Err(TextureError::SizeOverflow) => {}
+            other => panic!("expected overflow, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn images_without_texels_are_rejected() {
+        assert!(check_size(1, 1).is_ok());
+        for &(width, height) in &[(0, 1), (1, 0), (0, 0)] {
+            match check_size(width, height) {
+                Err(TextureError::ZeroSize) => {}
+                other => panic!("expected a zero size error, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn staging_layout_aligns_offsets_and_pitches() {
+        let (placements, len) = staging_layout(&[(3, 2), (1, 1)], 16, 32).unwrap();
+        assert_eq!(placements, vec![(0, 16), (32, 16)]);
+        assert_eq!(len, 48);
+    }
+
+    #[test]
+    fn staging_layout_keeps_pitch_in_whole_texels() {
+        let (placements, len) = staging_layout(&[(2, 3)], 1, 1).unwrap();
+        assert_eq!(placements, vec![(0, 8)]);
+        assert_eq!(len, 24);
+    }
+
+    #[test]
+    fn check_rgba_len_reports_mismatch() {
+        assert!(check_rgba_len(2, 2, &[0; 16]).is_ok());
+        match check_rgba_len(2, 2, &[0; 12]) {
+            Err(TextureError::SizeMismatch { expected: 16, actual: 12 }) => {}
+            other => panic!("expected a size mismatch, got {:?}", other),
+        }
+    }
+}
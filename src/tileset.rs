@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use gfx_hal::queue::capability::Graphics;
+
+use crate::config::TilesetConfig;
+use crate::context::Context;
+use crate::hal_prelude::*;
+use crate::texture::{Texture, TextureError, TextureOptions};
+
+use log::{info, warn};
+
+/// Get the top-left and bottom-right texture coordinates of a tile in a
+/// texture of `extent` divided into `columns` columns of `tile_size` tiles,
+/// numbered row by row.
+pub fn grid_uv_rect(extent: Extent, tile_size: (u32, u32), columns: u32, tile: u32) -> ([f32; 2], [f32; 2]) {
+    let (tile_width, tile_height) = (tile_size.0 as f32, tile_size.1 as f32);
+    let (width, height) = (extent.width as f32, extent.height as f32);
+    let x = (tile % columns) as f32 * tile_width / width;
+    let y = (tile / columns) as f32 * tile_height / height;
+    ([x, y], [x + tile_width / width, y + tile_height / height])
+}
+
+/// A texture atlas divided into equally sized tiles.
+pub struct Tileset<B: gfx_hal::Backend> {
+    pub texture: Texture<B>,
+    tile_width: u32,
+    tile_height: u32,
+    columns: u32,
+    rows: u32,
+    mapping: HashMap<char, u32>,
+}
+
+impl<B: gfx_hal::Backend> Tileset<B> {
+    /// Load the atlas image described by `config`. Tiles must be at least
+    /// one pixel wide and high.
+    pub fn load(
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+        config: &TilesetConfig,
+    ) -> Result<Self, TextureError> {
+        if config.tile_width == 0 || config.tile_height == 0 {
+            return Err(TextureError::ZeroSize);
+        }
+        let texture = Texture::from_path(context, command_pool, &config.path, TextureOptions::default())?;
+        let extent = texture.extent();
+        let width = config.columns.checked_mul(config.tile_width);
+        if width.map_or(true, |width| width > extent.width) {
+            warn!(
+                "tileset {} has {} columns of {}px but is only {}px wide",
+                config.path, config.columns, config.tile_width, extent.width
+            );
+        }
+        let rows = extent.height / config.tile_height;
+        info!("tileset {}: {}x{} tiles", config.path, config.columns, rows);
+        Ok(Tileset {
+            texture,
+            tile_width: config.tile_width,
+            tile_height: config.tile_height,
+            columns: config.columns.max(1),
+            rows,
+            mapping: config.mapping.clone(),
+        })
+    }
+
+    /// Create a single-tile atlas of one opaque white texel, so that cells
+    /// render as flat colour when no tileset is configured.
+    pub fn blank(
+        context: &mut Context<B>,
+        command_pool: &mut CommandPool<B, Graphics>,
+    ) -> Result<Self, TextureError> {
+        let texture = Texture::from_rgba(context, command_pool, 1, 1, &[255; 4], TextureOptions::default())?;
+        Ok(Tileset {
+            texture,
+            tile_width: 1,
+            tile_height: 1,
+            columns: 1,
+            rows: 1,
+            mapping: HashMap::new(),
+        })
+    }
+
+    /// Get the (width, height) of a tile in pixels.
+    pub fn tile_size(&self) -> (u32, u32) {
+        (self.tile_width, self.tile_height)
+    }
+
+    /// Get the number of tiles in the atlas.
+    pub fn tile_count(&self) -> u32 {
+        self.columns.saturating_mul(self.rows)
+    }
+
+    /// Get the codepoint-to-tile overrides.
+    pub fn mapping(&self) -> &HashMap<char, u32> {
+        &self.mapping
+    }
+
+    /// Get the tile for a codepoint, using the configured mapping and falling
+    /// back on the codepoint itself.
+    pub fn tile_for_char(&self, ch: char) -> Option<u32> {
+        let tile = self.mapping.get(&ch).cloned().unwrap_or(ch as u32);
+        if tile < self.tile_count() {
+            Some(tile)
+        } else {
+            None
+        }
+    }
+
+    /// Get the top-left and bottom-right texture coordinates of a tile.
+    pub fn uv_rect(&self, tile: u32) -> ([f32; 2], [f32; 2]) {
+        grid_uv_rect(self.texture.extent(), self.tile_size(), self.columns, tile)
+    }
+
+    /// Destroy the tileset.
+    pub fn destroy(&mut self, device: &B::Device) {
+        self.texture.destroy(device);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid_uv_rect_numbers_tiles_row_by_row() {
+        let extent = Extent { width: 64, height: 32, depth: 1 };
+        assert_eq!(grid_uv_rect(extent, (16, 16), 4, 0), ([0.0, 0.0], [0.25, 0.5]));
+        assert_eq!(grid_uv_rect(extent, (16, 16), 4, 5), ([0.25, 0.5], [0.5, 1.0]));
+    }
+
+    #[test]
+    fn grid_uv_rect_does_not_overflow() {
+        let extent = Extent { width: 1, height: 1, depth: 1 };
+        let ([x, y], _) = grid_uv_rect(extent, (std::u32::MAX, 1), 2, 1);
+        assert_eq!((x, y), (std::u32::MAX as f32, 0.0));
+    }
+}
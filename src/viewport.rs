@@ -0,0 +1,198 @@
+use crate::config::ScalingPolicy;
+use crate::hal_prelude::*;
+
+/// Placement of the console content within the window, as decided by a
+/// `ScalingPolicy`. Content is measured in content pixels (cells times cell
+/// size); the target is the swapchain extent in physical pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ViewportTransform {
+    /// Top-left corner of the scaled content within the target. Negative
+    /// when the content is cropped.
+    pub offset: (i32, i32),
+    /// Size of the scaled content within the target.
+    pub size: (i32, i32),
+    /// Size of the target.
+    pub target: (u32, u32),
+}
+
+impl ViewportTransform {
+    /// Fit content of the given size into a target according to `policy`.
+    pub fn compute(policy: ScalingPolicy, content: (f32, f32), target: (u32, u32)) -> Self {
+        ViewportTransform::compute_scaled(policy, content, target, 1.0)
+    }
+
+    /// Fit content into a target as `compute` does, drawing content pixels
+    /// `cell_scale` physical pixels wide under `ScalingPolicy::Resize`, as
+    /// wanted for cells scaled by the HiDPI factor.
+    pub fn compute_scaled(policy: ScalingPolicy, content: (f32, f32), target: (u32, u32), cell_scale: f32) -> Self {
+        let (content_width, content_height) = (content.0.max(1.0), content.1.max(1.0));
+        let (target_width, target_height) = (target.0 as f32, target.1 as f32);
+        let scale_x = target_width / content_width;
+        let scale_y = target_height / content_height;
+
+        let (width, height) = match policy {
+            ScalingPolicy::Stretch => (target_width, target_height),
+            ScalingPolicy::Integer => {
+                let scale = scale_x.min(scale_y).floor().max(1.0);
+                (content_width * scale, content_height * scale)
+            }
+            ScalingPolicy::Fit => {
+                let scale = scale_x.min(scale_y);
+                (content_width * scale, content_height * scale)
+            }
+            ScalingPolicy::Fill => {
+                let scale = scale_x.max(scale_y);
+                (content_width * scale, content_height * scale)
+            }
+            ScalingPolicy::Resize => (content_width * cell_scale, content_height * cell_scale),
+        };
+        let (width, height) = (width.round() as i32, height.round() as i32);
+        ViewportTransform {
+            offset: ((target.0 as i32 - width) / 2, (target.1 as i32 - height) / 2),
+            size: (width, height),
+            target,
+        }
+    }
+
+    /// Get the viewport to render the content into.
+    pub fn viewport(&self) -> Viewport {
+        Viewport {
+            rect: Rect {
+                x: self.offset.0 as i16,
+                y: self.offset.1 as i16,
+                w: self.size.0 as i16,
+                h: self.size.1 as i16,
+            },
+            depth: 0.0..1.0,
+        }
+    }
+
+    /// Get the part of the viewport that lies within the target, for use as
+    /// a scissor rectangle.
+    pub fn scissor(&self) -> Rect {
+        let x = self.offset.0.max(0);
+        let y = self.offset.1.max(0);
+        let right = (self.offset.0 + self.size.0).min(self.target.0 as i32);
+        let bottom = (self.offset.1 + self.size.1).min(self.target.1 as i32);
+        Rect {
+            x: x as i16,
+            y: y as i16,
+            w: (right - x).max(0) as i16,
+            h: (bottom - y).max(0) as i16,
+        }
+    }
+
+    /// Get the part of a rectangle of content pixels, given by its top-left
+    /// corner and size, that lies within the viewport and target, for use as
+    /// a scissor rectangle.
+    pub fn content_scissor(&self, origin: (f32, f32), size: (f32, f32), content: (f32, f32)) -> Rect {
+        let scale_x = self.size.0 as f32 / content.0.max(1.0);
+        let scale_y = self.size.1 as f32 / content.1.max(1.0);
+        let bounds = self.scissor();
+        let (min_x, min_y) = (i32::from(bounds.x), i32::from(bounds.y));
+        let (max_x, max_y) = (min_x + i32::from(bounds.w), min_y + i32::from(bounds.h));
+
+        let left = ((self.offset.0 as f32 + origin.0 * scale_x).floor() as i32).max(min_x).min(max_x);
+        let top = ((self.offset.1 as f32 + origin.1 * scale_y).floor() as i32).max(min_y).min(max_y);
+        let right = ((self.offset.0 as f32 + (origin.0 + size.0) * scale_x).ceil() as i32).max(left).min(max_x);
+        let bottom = ((self.offset.1 as f32 + (origin.1 + size.1) * scale_y).ceil() as i32).max(top).min(max_y);
+        Rect {
+            x: left as i16,
+            y: top as i16,
+            w: (right - left) as i16,
+            h: (bottom - top) as i16,
+        }
+    }
+
+    /// Get the whole target as a rectangle, for use as the render area so
+    /// that letterbox bars are cleared.
+    pub fn target_rect(&self) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            w: self.target.0 as i16,
+            h: self.target.1 as i16,
+        }
+    }
+
+    /// Map a point in target pixels to content pixels. Points in the
+    /// letterbox bars map outside the content.
+    pub fn to_content(&self, point: (f32, f32), content: (f32, f32)) -> (f32, f32) {
+        let scale_x = self.size.0.max(1) as f32 / content.0.max(1.0);
+        let scale_y = self.size.1.max(1) as f32 / content.1.max(1.0);
+        (
+            (point.0 - self.offset.0 as f32) / scale_x,
+            (point.1 - self.offset.1 as f32) / scale_y,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT: (f32, f32) = (320.0, 200.0);
+    const TARGET: (u32, u32) = (800, 600);
+
+    fn placement(policy: ScalingPolicy) -> ((i32, i32), (i32, i32)) {
+        let transform = ViewportTransform::compute(policy, CONTENT, TARGET);
+        (transform.offset, transform.size)
+    }
+
+    #[test]
+    fn policies_place_the_content() {
+        assert_eq!(placement(ScalingPolicy::Stretch), ((0, 0), (800, 600)));
+        assert_eq!(placement(ScalingPolicy::Integer), ((80, 100), (640, 400)));
+        assert_eq!(placement(ScalingPolicy::Fit), ((0, 50), (800, 500)));
+        assert_eq!(placement(ScalingPolicy::Fill), ((-80, 0), (960, 600)));
+        assert_eq!(placement(ScalingPolicy::Resize), ((240, 200), (320, 200)));
+
+        let scaled = ViewportTransform::compute_scaled(ScalingPolicy::Resize, CONTENT, TARGET, 2.0);
+        assert_eq!((scaled.offset, scaled.size), ((80, 100), (640, 400)));
+    }
+
+    #[test]
+    fn integer_scaling_never_shrinks_the_content() {
+        let transform = ViewportTransform::compute(ScalingPolicy::Integer, (100.0, 100.0), (50, 50));
+        assert_eq!((transform.offset, transform.size), ((-25, -25), (100, 100)));
+        let scissor = transform.scissor();
+        assert_eq!((scissor.x, scissor.y, scissor.w, scissor.h), (0, 0, 50, 50));
+    }
+
+    #[test]
+    fn cropped_content_is_scissored_to_the_target() {
+        let transform = ViewportTransform::compute(ScalingPolicy::Fill, CONTENT, TARGET);
+        let viewport = transform.viewport();
+        assert_eq!((viewport.rect.x, viewport.rect.y, viewport.rect.w, viewport.rect.h), (-80, 0, 960, 600));
+        let scissor = transform.scissor();
+        assert_eq!((scissor.x, scissor.y, scissor.w, scissor.h), (0, 0, 800, 600));
+        let target = transform.target_rect();
+        assert_eq!((target.x, target.y, target.w, target.h), (0, 0, 800, 600));
+    }
+
+    #[test]
+    fn target_points_map_to_content() {
+        let transform = ViewportTransform::compute(ScalingPolicy::Fit, CONTENT, TARGET);
+        assert_eq!(transform.to_content((400.0, 300.0), CONTENT), (160.0, 100.0));
+        // the letterbox bar above the content maps above it
+        assert_eq!(transform.to_content((0.0, 10.0), CONTENT), (0.0, -16.0));
+    }
+
+    #[test]
+    fn content_scissor_scales_into_the_viewport() {
+        // 100x50 content fitted into 400x400: scaled by 4, letterboxed by 100
+        let transform = ViewportTransform::compute(ScalingPolicy::Fit, (100.0, 50.0), (400, 400));
+        let rect = transform.content_scissor((10.0, 5.0), (20.0, 10.0), (100.0, 50.0));
+        assert_eq!((rect.x, rect.y, rect.w, rect.h), (40, 120, 80, 40));
+    }
+
+    #[test]
+    fn content_scissor_is_clipped_to_the_target() {
+        let transform = ViewportTransform::compute(ScalingPolicy::Fit, (100.0, 100.0), (100, 100));
+        let rect = transform.content_scissor((-10.0, 90.0), (30.0, 30.0), (100.0, 100.0));
+        assert_eq!((rect.x, rect.y, rect.w, rect.h), (0, 90, 20, 10));
+
+        let outside = transform.content_scissor((200.0, 200.0), (10.0, 10.0), (100.0, 100.0));
+        assert_eq!((outside.w, outside.h), (0, 0));
+    }
+}
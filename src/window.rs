@@ -1,54 +1,251 @@
-use crate::config::Config;
-#[cfg(not(feature = "gl"))]
-use gfx_backend::winit;
+use crate::backend::{BackendKind, WindowBackend};
+use crate::config::{Config, FullscreenMode, WindowConfig};
+use crate::context::Context;
+use crate::winit::dpi::{LogicalPosition, LogicalSize};
+use crate::winit::{EventsLoop, Icon, MonitorId};
 #[cfg(feature = "gl")]
-use gfx_backend::glutin;
+use gfx_backend_gl::glutin::GlWindow;
 
-/// Window abstraction.
+use log::warn;
+
+/// The platform window, the same winit type under every backend.
+pub type PlatformWindow = crate::winit::Window;
+
+/// Error building a platform window.
+pub use crate::winit::CreationError as WindowCreationError;
+
+/// Window abstraction, with the same API under every backend.
 pub struct Window {
-    #[cfg(not(feature = "gl"))]
-    pub events_loop: winit::EventsLoop,
+    pub events_loop: EventsLoop,
+    /// The window, when opened for a backend that draws to plain winit
+    /// windows.
+    pub(crate) window: Option<PlatformWindow>,
+    /// The window, when opened for the GL backend, until a context moves it
+    /// into its surface.
     #[cfg(feature = "gl")]
-    pub events_loop: glutin::EventsLoop,
-    #[cfg(not(feature = "gl"))]
-    pub window: winit::Window,
-    #[cfg(feature = "gl")]
-    pub window: Option<glutin::GlWindow>,
+    pub(crate) gl_window: Option<GlWindow>,
 }
 
 impl Window {
-    /// Create a new window from the passed Config.
-    #[cfg(not(feature = "gl"))]
-    pub fn new(config: &Config) -> Self {
+    /// Create a new window from the passed Config, to be drawn to with
+    /// `backend`.
+    pub fn new(config: &Config, backend: BackendKind) -> Self {
         let events_loop = EventsLoop::new();
-        let window = config
-            .window
-            .build(&events_loop)
-            .expect("Failed to build window!");
-        Window {
+        let mut window = Window {
+            window: None,
+            #[cfg(feature = "gl")]
+            gl_window: None,
             events_loop,
-            window,
+        };
+        match backend {
+            #[cfg(feature = "gl")]
+            BackendKind::Gl => window.gl_window = Some(Window::build_gl(config, &window.events_loop)),
+            _ => {
+                let built = config.window.build(&window.events_loop).expect("Failed to build window!");
+                window.window = Some(built);
+            }
         }
+        window
     }
 
     #[cfg(feature = "gl")]
-    pub fn new(config: &Config) -> Self {
-        use gfx_hal::format::{AsFormat, Rgba8Srgb as ColorFormat};
-        let events_loop = glutin::EventsLoop::new();
-
-        let window = {
-            let builder = gfx_backend::config_context(
-                glutin::ContextBuilder::new(),
-                ColorFormat::SELF,
-                None,
-            )
-            .with_vsync(true);
-            glutin::GlWindow::new(config.window.get_builder(), builder, &events_loop)
-                .unwrap()
+    fn build_gl(config: &Config, events_loop: &EventsLoop) -> GlWindow {
+        use gfx_hal::format::{AsFormat, Format, Rgba8Srgb as ColorFormat};
+        // the default framebuffer's depth buffer and multisampling come with
+        // the GL context
+        let depth_format = if config.graphics.depth {
+            Some(Format::D24UnormS8Uint)
+        } else {
+            None
         };
-        Window {
-            events_loop,
-            window: Some(window),
+        let builder = gfx_backend_gl::config_context(
+            gfx_backend_gl::glutin::ContextBuilder::new(),
+            ColorFormat::SELF,
+            depth_format,
+        )
+        .with_vsync(true);
+        let samples = config.graphics.samples;
+        let builder = if samples > 1 && samples.is_power_of_two() {
+            builder.with_multisampling(u16::from(samples))
+        } else {
+            builder
+        };
+        GlWindow::new(config.window.get_builder(), builder, events_loop).expect("Failed to build window!")
+    }
+
+    /// Get the platform window, given the context created for it with
+    /// `InstanceWrapper::create_context`.
+    pub fn platform_window<'a, B: WindowBackend>(&'a self, context: &'a Context<B>) -> &'a PlatformWindow {
+        B::platform_window(self, context)
+    }
+}
+
+/// Get the inner size of a window in physical pixels.
+pub fn physical_size(window: &PlatformWindow) -> (u32, u32) {
+    let hidpi_factor = window.get_hidpi_factor();
+    window
+        .get_inner_size()
+        .map(|size| size.to_physical(hidpi_factor).into())
+        .unwrap_or((0, 0))
+}
+
+/// Load an image file as a window icon.
+pub fn load_icon(path: &str) -> Option<Icon> {
+    let pixels = match ::image::open(path) {
+        Ok(image) => image.to_rgba(),
+        Err(err) => {
+            warn!("unable to load window icon {}: {}", path, err);
+            return None;
+        }
+    };
+    let (width, height) = pixels.dimensions();
+    match Icon::from_rgba(pixels.into_raw(), width, height) {
+        Ok(icon) => Some(icon),
+        Err(err) => {
+            warn!("unable to use {} as window icon: {:?}", path, err);
+            None
+        }
+    }
+}
+
+/// Change to the window requested at runtime, applied between frames.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WindowCommand {
+    SetTitle(String),
+    SetFullscreen(FullscreenMode),
+    /// Switch between windowed and the last fullscreen mode used.
+    ToggleFullscreen,
+    SetMaximized(bool),
+    SetResizable(bool),
+    SetMinSize(Option<(u32, u32)>),
+    SetMaxSize(Option<(u32, u32)>),
+    /// Select the monitor to go fullscreen on by index, or the primary one.
+    SetMonitor(Option<usize>),
+    SetIcon(Option<String>),
+}
+
+/// Tracks the fullscreen mode of a window, so that it can be toggled and
+/// restored to its windowed size and position.
+#[derive(Clone, Debug)]
+pub struct WindowState {
+    fullscreen: FullscreenMode,
+    /// Mode switched to by `ToggleFullscreen` from windowed.
+    toggle_mode: FullscreenMode,
+    monitor: Option<usize>,
+    decorations: bool,
+    /// Position and size to restore when leaving borderless fullscreen.
+    windowed: Option<(LogicalPosition, LogicalSize)>,
+}
+
+impl WindowState {
+    /// Start in windowed mode. Apply `SetFullscreen` with the configured
+    /// mode once the window exists to enter it.
+    pub fn new(config: &WindowConfig) -> Self {
+        WindowState {
+            fullscreen: FullscreenMode::Windowed,
+            toggle_mode: match config.fullscreen {
+                FullscreenMode::Windowed => FullscreenMode::Borderless,
+                mode => mode,
+            },
+            monitor: config.monitor,
+            decorations: config.decorations,
+            windowed: None,
+        }
+    }
+
+    /// Get the current fullscreen mode.
+    pub fn fullscreen(&self) -> FullscreenMode {
+        self.fullscreen
+    }
+
+    /// Apply a command to the window. Returns true if the window's size may
+    /// have changed, in which case the swapchain should be rebuilt.
+    pub fn apply(&mut self, window: &PlatformWindow, command: WindowCommand) -> bool {
+        match command {
+            WindowCommand::SetTitle(title) => {
+                window.set_title(&title);
+                false
+            }
+            WindowCommand::SetFullscreen(mode) => self.set_fullscreen(window, mode),
+            WindowCommand::ToggleFullscreen => {
+                let mode = match self.fullscreen {
+                    FullscreenMode::Windowed => self.toggle_mode,
+                    _ => FullscreenMode::Windowed,
+                };
+                self.set_fullscreen(window, mode)
+            }
+            WindowCommand::SetMaximized(maximized) => {
+                window.set_maximized(maximized);
+                true
+            }
+            WindowCommand::SetResizable(resizable) => {
+                window.set_resizable(resizable);
+                false
+            }
+            WindowCommand::SetMinSize(size) => {
+                window.set_min_dimensions(size.map(Into::into));
+                true
+            }
+            WindowCommand::SetMaxSize(size) => {
+                window.set_max_dimensions(size.map(Into::into));
+                true
+            }
+            WindowCommand::SetMonitor(monitor) => {
+                self.monitor = monitor;
+                let mode = self.fullscreen;
+                // move a fullscreen window over by leaving and re-entering
+                mode != FullscreenMode::Windowed
+                    && (self.set_fullscreen(window, FullscreenMode::Windowed) | self.set_fullscreen(window, mode))
+            }
+            WindowCommand::SetIcon(path) => {
+                window.set_window_icon(path.and_then(|path| load_icon(&path)));
+                false
+            }
+        }
+    }
+
+    fn set_fullscreen(&mut self, window: &PlatformWindow, mode: FullscreenMode) -> bool {
+        if mode == self.fullscreen {
+            return false;
+        }
+        if self.fullscreen == FullscreenMode::Windowed {
+            self.windowed = match (window.get_position(), window.get_inner_size()) {
+                (Some(position), Some(size)) => Some((position, size)),
+                _ => None,
+            };
         }
+        match mode {
+            FullscreenMode::Windowed => {
+                window.set_fullscreen(None);
+                window.set_decorations(self.decorations);
+                if let Some((position, size)) = self.windowed.take() {
+                    window.set_inner_size(size);
+                    window.set_position(position);
+                }
+            }
+            FullscreenMode::Borderless => {
+                let monitor = self.monitor(window);
+                let hidpi_factor = monitor.get_hidpi_factor();
+                window.set_fullscreen(None);
+                window.set_decorations(false);
+                window.set_position(monitor.get_position().to_logical(hidpi_factor));
+                window.set_inner_size(monitor.get_dimensions().to_logical(hidpi_factor));
+            }
+            FullscreenMode::Exclusive => {
+                window.set_fullscreen(Some(self.monitor(window)));
+            }
+        }
+        self.fullscreen = mode;
+        if mode != FullscreenMode::Windowed {
+            self.toggle_mode = mode;
+        }
+        true
+    }
+
+    /// Get the selected monitor, falling back on the primary one.
+    fn monitor(&self, window: &PlatformWindow) -> MonitorId {
+        self.monitor
+            .and_then(|index| window.get_available_monitors().nth(index))
+            .unwrap_or_else(|| window.get_primary_monitor())
     }
 }